@@ -1,4 +1,4 @@
-use syscall::arch::{syscall0, syscall1, syscall2, syscall3};
+use syscall::arch::{syscall0, syscall1, syscall2, syscall3, syscall4};
 use error::Result;
 
 pub const SYS_BRK: usize = 45;
@@ -13,13 +13,22 @@ pub const SYS_CLOCK_GETTIME: usize = 265;
     pub const CLOCK_REALTIME: usize = 1;
     pub const CLOCK_MONOTONIC: usize = 4;
 pub const SYS_DUP: usize = 41;
+pub const SYS_DUP2: usize = 63;
 pub const SYS_EXECVE: usize = 11;
 pub const SYS_EXIT: usize = 1;
+pub const SYS_FALLOCATE: usize = 324;
+    pub const FALLOC_FL_KEEP_SIZE: usize = 1;
+pub const SYS_FCNTL: usize = 55;
+    pub const F_GETFL: usize = 3;
+    pub const F_SETFL: usize = 4;
+pub const SYS_FDATASYNC: usize = 148;
 pub const SYS_FPATH: usize = 928;
 pub const SYS_FSTAT: usize = 28;
 pub const SYS_FSYNC: usize = 118;
 pub const SYS_FTRUNCATE: usize = 93;
+pub const SYS_GETCWD: usize = 183;
 pub const SYS_GETPID: usize = 20;
+pub const SYS_GETXATTR: usize = 229;
 pub const SYS_IOPL: usize = 110;
 pub const SYS_LINK: usize = 9;
 pub const SYS_LSEEK: usize = 19;
@@ -41,12 +50,23 @@ pub const SYS_OPEN: usize = 5;
     pub const O_CREAT: usize = 0x200;
     pub const O_TRUNC: usize = 0x400;
     pub const O_EXCL: usize = 0x800;
+    pub const O_TMPFILE: usize = 0x1000;
+pub const SYS_OPENAT: usize = 295;
+pub const SYS_OPEN_NODE: usize = 929;
 pub const SYS_PIPE2: usize = 331;
+pub const SYS_PREAD: usize = 180;
 pub const SYS_READ: usize = 3;
+pub const SYS_READAHEAD: usize = 225;
+pub const SYS_RENAME: usize = 38;
 pub const SYS_RMDIR: usize = 84;
+pub const SYS_SETXATTR: usize = 226;
 pub const SYS_STAT: usize = 18;
     pub const MODE_DIR: u16 = 0x4000;
     pub const MODE_FILE: u16 = 0x8000;
+// The type byte that starts each entry of a directory listing
+pub const DIRENT_FILE: u8 = 1;
+pub const DIRENT_DIR: u8 = 2;
+pub const DIRENT_SYMLINK: u8 = 3;
 pub const SYS_UNLINK: usize = 10;
 pub const SYS_WAITPID: usize = 7;
 pub const SYS_WRITE: usize = 4;
@@ -56,7 +76,9 @@ pub const SYS_YIELD: usize = 158;
 #[repr(packed)]
 pub struct Stat {
     pub st_mode: u16,
-    pub st_size: u64
+    pub st_size: u64,
+    /// Number of 512 byte blocks allocated
+    pub st_blocks: u64
 }
 
 #[derive(Copy, Clone, Debug, Default)]
@@ -90,6 +112,10 @@ pub fn sys_dup(fd: usize) -> Result<usize> {
     unsafe { syscall1(SYS_DUP, fd) }
 }
 
+pub fn sys_dup2(fd: usize, new_fd: usize) -> Result<usize> {
+    unsafe { syscall2(SYS_DUP2, fd, new_fd) }
+}
+
 pub unsafe fn sys_execve(path: *const u8, args: *const *const u8) -> Result<usize> {
     syscall2(SYS_EXECVE, path as usize, args as usize)
 }
@@ -98,10 +124,26 @@ pub fn sys_exit(status: usize) -> Result<usize> {
     unsafe { syscall1(SYS_EXIT, status) }
 }
 
+pub fn sys_fallocate(fd: usize, mode: usize, len: usize) -> Result<usize> {
+    unsafe { syscall3(SYS_FALLOCATE, fd, mode, len) }
+}
+
+pub fn sys_fcntl(fd: usize, cmd: usize, arg: usize) -> Result<usize> {
+    unsafe { syscall3(SYS_FCNTL, fd, cmd, arg) }
+}
+
+pub fn sys_fdatasync(fd: usize) -> Result<usize> {
+    unsafe { syscall1(SYS_FDATASYNC, fd) }
+}
+
 pub fn sys_fpath(fd: usize, buf: &mut [u8]) -> Result<usize> {
     unsafe { syscall3(SYS_FPATH, fd, buf.as_mut_ptr() as usize, buf.len()) }
 }
 
+pub fn sys_getcwd(buf: &mut [u8]) -> Result<usize> {
+    unsafe { syscall2(SYS_GETCWD, buf.as_mut_ptr() as usize, buf.len()) }
+}
+
 pub fn sys_fstat(fd: usize, stat: &mut Stat) -> Result<usize> {
     unsafe { syscall2(SYS_FSTAT, fd, stat as *mut Stat as usize) }
 }
@@ -118,6 +160,10 @@ pub fn sys_getpid() -> Result<usize> {
     unsafe { syscall0(SYS_GETPID) }
 }
 
+pub unsafe fn sys_getxattr(path: *const u8, key: *const u8, buf: *mut u8, len: usize) -> Result<usize> {
+    syscall4(SYS_GETXATTR, path as usize, key as usize, buf as usize, len)
+}
+
 pub unsafe fn sys_iopl(level: usize) -> Result<usize> {
     syscall1(SYS_IOPL, level)
 }
@@ -142,18 +188,42 @@ pub unsafe fn sys_open(path: *const u8, flags: usize, mode: usize) -> Result<usi
     syscall3(SYS_OPEN, path as usize, flags, mode)
 }
 
+pub unsafe fn sys_openat(fd: usize, path: *const u8, flags: usize) -> Result<usize> {
+    syscall3(SYS_OPENAT, fd, path as usize, flags)
+}
+
+pub unsafe fn sys_open_node(path: *const u8, node: usize, flags: usize) -> Result<usize> {
+    syscall3(SYS_OPEN_NODE, path as usize, node, flags)
+}
+
 pub unsafe fn sys_pipe2(fds: *mut usize, flags: usize) -> Result<usize> {
     syscall2(SYS_PIPE2, fds as usize, flags)
 }
 
+pub fn sys_pread(fd: usize, buf: &mut [u8], offset: usize) -> Result<usize> {
+    unsafe { syscall4(SYS_PREAD, fd, buf.as_mut_ptr() as usize, buf.len(), offset) }
+}
+
 pub fn sys_read(fd: usize, buf: &mut [u8]) -> Result<usize> {
     unsafe { syscall3(SYS_READ, fd, buf.as_mut_ptr() as usize, buf.len()) }
 }
 
+pub fn sys_readahead(fd: usize, offset: usize, len: usize) -> Result<usize> {
+    unsafe { syscall3(SYS_READAHEAD, fd, offset, len) }
+}
+
+pub unsafe fn sys_rename(old: *const u8, new: *const u8) -> Result<usize> {
+    syscall2(SYS_RENAME, old as usize, new as usize)
+}
+
 pub unsafe fn sys_rmdir(path: *const u8) -> Result<usize> {
     syscall1(SYS_RMDIR, path as usize)
 }
 
+pub unsafe fn sys_setxattr(path: *const u8, key: *const u8, value: *const u8, len: usize) -> Result<usize> {
+    syscall4(SYS_SETXATTR, path as usize, key as usize, value as usize, len)
+}
+
 pub unsafe fn sys_stat(path: *const u8, stat: &mut Stat) -> Result<usize> {
     syscall2(SYS_STAT, path as usize, stat as *mut Stat as usize)
 }
@@ -0,0 +1,376 @@
+use alloc::boxed::Box;
+
+use collections::string::String;
+use collections::vec::Vec;
+
+use common::time::Duration;
+
+use disk::{Disk, DiskStatus};
+
+use sync::{Intex, WaitCondition};
+
+use system::error::Result;
+
+/// The default time a sector may stay dirty before it is written back
+pub const CACHE_FLUSH_INTERVAL: Duration = Duration {
+    secs: 5,
+    nanos: 0,
+};
+
+/// A dirty sector
+struct CacheEntry {
+    block: u64,
+    data: Vec<u8>,
+    /// The time the sector was first written since it was last clean
+    dirtied: Duration,
+}
+
+/// A disk wrapper that caches written sectors and writes them back on flush, when the cache is
+/// full, or once they have been dirty for longer than the flush interval
+///
+/// The flush daemon calls `on_tick` from its own context while the file system may be using the
+/// disk, so every access to the inner disk, and every change to the cache, is done holding the
+/// disk lock. A dirty sector stays in the cache until it has been written back, so a read never
+/// sees older data from the disk.
+pub struct CachedDisk {
+    disk: Box<Disk>,
+    /// Dirty sectors, oldest first
+    entries: Intex<Vec<CacheEntry>>,
+    capacity: usize,
+    flush_interval: Duration,
+    /// Whether the inner disk is in use
+    busy: Intex<bool>,
+    condition: WaitCondition,
+}
+
+impl CachedDisk {
+    /// Wrap a disk, caching up to `capacity` dirty sectors
+    pub fn new(disk: Box<Disk>, capacity: usize) -> Self {
+        CachedDisk {
+            disk: disk,
+            entries: Intex::new(Vec::new()),
+            capacity: capacity,
+            flush_interval: CACHE_FLUSH_INTERVAL,
+            busy: Intex::new(false),
+            condition: WaitCondition::new(),
+        }
+    }
+
+    /// Lock the inner disk, waiting until the context using it is done
+    fn lock_disk(&self) {
+        loop {
+            {
+                let mut busy = self.busy.lock();
+                if ! *busy {
+                    *busy = true;
+                    return;
+                }
+            }
+            unsafe { self.condition.wait(); }
+        }
+    }
+
+    /// Unlock the inner disk, waking up any waiters
+    fn unlock_disk(&self) {
+        *self.busy.lock() = false;
+        unsafe { self.condition.notify(); }
+    }
+
+    /// Get the time a sector may stay dirty before it is written back
+    pub fn flush_interval(&self) -> Duration {
+        self.flush_interval
+    }
+
+    /// Set the time a sector may stay dirty before it is written back
+    pub fn set_flush_interval(&mut self, interval: Duration) {
+        self.flush_interval = interval;
+    }
+
+    /// Get the number of dirty sectors
+    pub fn dirty(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    /// Write back the oldest dirty sector if it was dirtied before `before`, or unconditionally if
+    /// `before` is None. The sector is only removed from the cache once it is written, and is kept
+    /// for a retry if the write fails. Returns false if there was nothing to write back. The
+    /// caller must hold the disk lock
+    fn write_back(&mut self, before: Option<Duration>) -> Result<bool> {
+        let (block, data) = {
+            let entries = self.entries.lock();
+            match entries.first() {
+                Some(entry) => {
+                    if let Some(before) = before {
+                        if entry.dirtied >= before {
+                            return Ok(false);
+                        }
+                    }
+                    (entry.block, entry.data.clone())
+                },
+                None => return Ok(false),
+            }
+        };
+
+        try!(self.disk.write(block, &data));
+
+        self.entries.lock().retain(|entry| entry.block != block);
+        Ok(true)
+    }
+
+    /// Read from the disk, with dirty sectors from the cache on top. The caller must hold the
+    /// disk lock
+    fn read_locked(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let count = try!(self.disk.read(block, buffer));
+
+        // Dirty sectors are newer than the disk
+        let entries = self.entries.lock();
+        for entry in entries.iter() {
+            if entry.block >= block {
+                let start = (entry.block - block) as usize * 512;
+                if start < count {
+                    for (b, d) in buffer[start..count].iter_mut().zip(entry.data.iter()) {
+                        *b = *d;
+                    }
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Cache a write made at `now`, writing back older sectors to make room. The caller must hold
+    /// the disk lock
+    fn write_locked(&mut self, block: u64, buffer: &[u8], now: Duration) -> Result<usize> {
+        let sectors = buffer.len() / 512;
+
+        // Writes that would not fit in the cache go straight to the disk
+        if sectors == 0 || sectors > self.capacity {
+            let end = block + sectors as u64;
+            self.entries.lock().retain(|entry| entry.block < block || entry.block >= end);
+            return self.disk.write(block, buffer);
+        }
+
+        for i in 0..sectors {
+            while ! self.cache_sector(block + i as u64, &buffer[i * 512..(i + 1) * 512], now) {
+                try!(self.write_back(None));
+            }
+        }
+
+        Ok(sectors * 512)
+    }
+
+    /// Write back every dirty sector and flush the disk. The caller must hold the disk lock
+    fn flush_locked(&mut self) -> Result<()> {
+        while try!(self.write_back(None)) {}
+        self.disk.flush()
+    }
+
+    /// Write back the sectors dirty for longer than the flush interval at `now`. The disk lock is
+    /// taken for each sector, so that the file system is not kept waiting for the whole write back
+    fn on_tick_at(&mut self, now: Duration) -> Result<()> {
+        let before = now - self.flush_interval;
+        loop {
+            self.lock_disk();
+            let result = self.write_back(Some(before));
+            self.unlock_disk();
+            if ! try!(result) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Store the data written to a sector at `now`, returns false if the cache is full
+    fn cache_sector(&self, block: u64, data: &[u8], now: Duration) -> bool {
+        let mut entries = self.entries.lock();
+        for entry in entries.iter_mut() {
+            if entry.block == block {
+                entry.data.clone_from_slice(data);
+                return true;
+            }
+        }
+
+        if entries.len() < self.capacity {
+            entries.push(CacheEntry {
+                block: block,
+                data: data.to_vec(),
+                dirtied: now,
+            });
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Disk for CachedDisk {
+    fn name(&self) -> String {
+        self.disk.name()
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        self.lock_disk();
+        let result = self.read_locked(block, buffer);
+        self.unlock_disk();
+        result
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        self.lock_disk();
+        let result = self.write_locked(block, buffer, Duration::monotonic());
+        self.unlock_disk();
+        result
+    }
+
+    fn size(&self) -> u64 {
+        self.disk.size()
+    }
+
+    fn sector_size(&self) -> u64 {
+        self.disk.sector_size()
+    }
+
+    fn max_transfer_sectors(&self) -> usize {
+        self.disk.max_transfer_sectors()
+    }
+
+    fn is_rotational(&self) -> bool {
+        self.disk.is_rotational()
+    }
+
+    fn status(&mut self) -> DiskStatus {
+        self.lock_disk();
+        let status = self.disk.status();
+        self.unlock_disk();
+        status
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.lock_disk();
+        let result = self.flush_locked();
+        self.unlock_disk();
+        result
+    }
+
+    fn on_tick(&mut self) -> Result<()> {
+        self.on_tick_at(Duration::monotonic())
+    }
+}
+
+impl Drop for CachedDisk {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use collections::string::String;
+    use collections::vec::Vec;
+
+    use common::time::Duration;
+
+    use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    use disk::Disk;
+
+    use fs::redoxfs::tests::MemDisk;
+
+    use system::error::Result;
+
+    use super::CachedDisk;
+
+    /// The number of sectors written to a `CountDisk`
+    static WRITTEN: AtomicUsize = ATOMIC_USIZE_INIT;
+    /// The number of times a `CountDisk` was flushed
+    static FLUSHES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// A disk in memory that counts writes and flushes
+    struct CountDisk {
+        inner: MemDisk,
+    }
+
+    impl Disk for CountDisk {
+        fn name(&self) -> String {
+            self.inner.name()
+        }
+
+        fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+            self.inner.read(block, buffer)
+        }
+
+        fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+            WRITTEN.fetch_add(buffer.len() / 512, Ordering::SeqCst);
+            self.inner.write(block, buffer)
+        }
+
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            FLUSHES.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn cached(capacity: usize) -> CachedDisk {
+        CachedDisk::new(box MemDisk { data: vec![0; 16 * 512] }, capacity)
+    }
+
+    fn secs(secs: i64) -> Duration {
+        Duration::new(secs, 0)
+    }
+
+    /// Read a sector from the disk under the cache
+    fn inner(disk: &mut CachedDisk, block: u64) -> Vec<u8> {
+        let mut data = vec![0; 512];
+        disk.disk.read(block, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn write_back_after_interval() {
+        let mut disk = cached(4);
+        disk.write_locked(1, &[1; 512], secs(10)).unwrap();
+        disk.write_locked(2, &[2; 512], secs(13)).unwrap();
+
+        disk.on_tick_at(secs(14)).unwrap();
+        assert_eq!(disk.dirty(), 2);
+        assert_eq!(inner(&mut disk, 1), vec![0; 512]);
+
+        // Only the sector dirty for longer than the interval is written back
+        disk.on_tick_at(secs(16)).unwrap();
+        assert_eq!(disk.dirty(), 1);
+        assert_eq!(inner(&mut disk, 1), vec![1; 512]);
+        assert_eq!(inner(&mut disk, 2), vec![0; 512]);
+
+        disk.on_tick_at(secs(20)).unwrap();
+        assert_eq!(disk.dirty(), 0);
+        assert_eq!(inner(&mut disk, 2), vec![2; 512]);
+    }
+
+    #[test]
+    fn read_dirty() {
+        let mut disk = cached(4);
+        disk.write_locked(2, &[2; 512], secs(10)).unwrap();
+        assert_eq!(inner(&mut disk, 2), vec![0; 512]);
+
+        let mut data = vec![0xFF; 3 * 512];
+        assert_eq!(disk.read(1, &mut data).unwrap(), 3 * 512);
+        assert!(data[..512].iter().all(|b| *b == 0));
+        assert!(data[512..1024].iter().all(|b| *b == 2));
+        assert!(data[1024..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn flush_on_drop() {
+        let mut disk = CachedDisk::new(box CountDisk { inner: MemDisk { data: vec![0; 16 * 512] } }, 4);
+        disk.write_locked(3, &[3; 512], secs(10)).unwrap();
+        disk.write_locked(4, &[4; 512], secs(10)).unwrap();
+        assert_eq!(WRITTEN.load(Ordering::SeqCst), 0);
+
+        drop(disk);
+        assert_eq!(WRITTEN.load(Ordering::SeqCst), 2);
+        assert_eq!(FLUSHES.load(Ordering::SeqCst), 1);
+    }
+}
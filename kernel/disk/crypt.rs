@@ -0,0 +1,202 @@
+use alloc::boxed::Box;
+
+use collections::string::String;
+
+use core::slice;
+
+use arch::memory::Memory;
+
+use disk::{Disk, DiskStatus};
+
+use system::error::{Error, Result, ENOMEM};
+
+/// A cipher applied to each sector of a `CryptDisk`
+pub trait SectorCipher {
+    /// The name of the cipher
+    fn name(&self) -> &str;
+
+    /// Encrypt the data of the given sector in place
+    fn encrypt(&self, sector: u64, data: &mut [u8]);
+
+    /// Decrypt the data of the given sector in place
+    fn decrypt(&self, sector: u64, data: &mut [u8]);
+}
+
+/// A placeholder cipher that XORs each sector with a keystream derived from the key and the
+/// sector number. It hides data from casual inspection only and is not secure
+pub struct XorCipher {
+    seed: u64,
+}
+
+impl XorCipher {
+    pub fn new(key: &[u8]) -> Self {
+        // FNV-1a, so every byte of the key affects the keystream
+        let mut seed = 0xCBF29CE484222325;
+        for b in key.iter() {
+            seed ^= *b as u64;
+            seed = seed.wrapping_mul(0x100000001B3);
+        }
+
+        XorCipher {
+            seed: seed,
+        }
+    }
+
+    fn apply(&self, sector: u64, data: &mut [u8]) {
+        // Xorshift, seeded per sector so that equal sectors encrypt differently
+        let mut state = (self.seed ^ sector.wrapping_mul(0x9E3779B97F4A7C15)) | 1;
+        for chunk in data.chunks_mut(8) {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            for (i, b) in chunk.iter_mut().enumerate() {
+                *b ^= (state >> (i * 8)) as u8;
+            }
+        }
+    }
+}
+
+impl SectorCipher for XorCipher {
+    fn name(&self) -> &str {
+        "XOR"
+    }
+
+    fn encrypt(&self, sector: u64, data: &mut [u8]) {
+        self.apply(sector, data);
+    }
+
+    fn decrypt(&self, sector: u64, data: &mut [u8]) {
+        self.apply(sector, data);
+    }
+}
+
+/// A disk wrapper that encrypts sectors on write and decrypts them on read
+pub struct CryptDisk {
+    disk: Box<Disk>,
+    cipher: Box<SectorCipher>,
+}
+
+impl CryptDisk {
+    /// Wrap a disk, encrypting it with the given cipher
+    pub fn new(disk: Box<Disk>, cipher: Box<SectorCipher>) -> Self {
+        CryptDisk {
+            disk: disk,
+            cipher: cipher,
+        }
+    }
+}
+
+impl Disk for CryptDisk {
+    fn name(&self) -> String {
+        format!("{} ({} encrypted)", self.disk.name(), self.cipher.name())
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let count = try!(self.disk.read(block, buffer));
+
+        for (i, sector) in buffer[..count].chunks_mut(512).enumerate() {
+            self.cipher.decrypt(block + i as u64, sector);
+        }
+
+        Ok(count)
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        // Encrypt a copy, the caller still owns the plaintext
+        if let Some(data) = Memory::<u8>::new(buffer.len()) {
+            let encrypted = unsafe { slice::from_raw_parts_mut(data.ptr, buffer.len()) };
+            for (e, b) in encrypted.iter_mut().zip(buffer.iter()) {
+                *e = *b;
+            }
+
+            for (i, sector) in encrypted.chunks_mut(512).enumerate() {
+                self.cipher.encrypt(block + i as u64, sector);
+            }
+
+            self.disk.write(block, encrypted)
+        } else {
+            Err(Error::new(ENOMEM))
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.disk.size()
+    }
+
+    fn sector_size(&self) -> u64 {
+        self.disk.sector_size()
+    }
+
+    fn max_transfer_sectors(&self) -> usize {
+        self.disk.max_transfer_sectors()
+    }
+
+    fn is_rotational(&self) -> bool {
+        self.disk.is_rotational()
+    }
+
+    fn status(&mut self) -> DiskStatus {
+        self.disk.status()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.disk.flush()
+    }
+
+    fn on_tick(&mut self) -> Result<()> {
+        self.disk.on_tick()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use collections::vec::Vec;
+
+    use disk::Disk;
+
+    use fs::redoxfs::tests::MemDisk;
+
+    use super::{CryptDisk, XorCipher};
+
+    fn crypt(key: &[u8]) -> CryptDisk {
+        CryptDisk::new(box MemDisk { data: vec![0; 16 * 512] }, box XorCipher::new(key))
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut disk = crypt(b"key");
+        let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        assert_eq!(disk.write(2, &data).unwrap(), 1024);
+
+        // The disk below only sees the encrypted sectors
+        let mut raw = vec![0; 1024];
+        disk.disk.read(2, &mut raw).unwrap();
+        assert!(raw != data);
+
+        let mut read = vec![0; 1024];
+        assert_eq!(disk.read(2, &mut read).unwrap(), 1024);
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn sectors_and_keys_differ() {
+        let mut disk = crypt(b"key");
+        disk.write(0, &[0; 1024]).unwrap();
+        let mut raw = vec![0; 1024];
+        disk.disk.read(0, &mut raw).unwrap();
+        assert!(raw[..512] != raw[512..]);
+
+        let mut other = crypt(b"other key");
+        other.write(0, &[0; 512]).unwrap();
+        let mut other_raw = vec![0; 512];
+        other.disk.read(0, &mut other_raw).unwrap();
+        assert!(other_raw[..] != raw[..512]);
+
+        // Reading with the wrong key does not give back the data
+        let mut wrong = CryptDisk::new(box MemDisk { data: raw.clone() }, box XorCipher::new(b"other key"));
+        let mut read = vec![0; 512];
+        wrong.read(0, &mut read).unwrap();
+        assert!(read.iter().any(|b| *b != 0));
+    }
+}
@@ -1,18 +1,18 @@
 use alloc::boxed::Box;
 
-use collections::string::String;
+use collections::string::{String, ToString};
 use collections::vec::Vec;
 
-use core::ptr;
+use core::{cmp, fmt, ptr};
 
 use arch::memory::Memory;
 
-use disk::Disk;
+use disk::{Disk, DiskStatus};
 
 use drivers::pci::config::PciConfig;
 use drivers::io::{Io, Pio, ReadOnly, WriteOnly};
 
-use system::error::{Error, Result, EIO};
+use system::error::{Error, Result, EAGAIN, EINVAL, EIO, ENOMEDIUM, ENOMEM};
 
 /// An disk extent
 #[derive(Copy, Clone)]
@@ -26,6 +26,239 @@ impl Extent {
     pub fn empty(&self) -> bool {
         return self.block == 0 || self.length == 0;
     }
+
+    /// Number of sectors covered by the extent
+    pub fn sectors(&self) -> u64 {
+        (self.length + 511) / 512
+    }
+
+    /// Check if the extent covers the given block
+    pub fn contains(&self, block: u64) -> bool {
+        ! self.empty() && block >= self.block && block < self.block + self.sectors()
+    }
+
+    /// Check if the extent covers any block covered by another extent
+    pub fn overlaps(&self, other: &Extent) -> bool {
+        ! self.empty() && ! other.empty() && self.block < other.block + other.sectors()
+            && other.block < self.block + self.sectors()
+    }
+}
+
+/// PIO transfer mode
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PioMode {
+    Pio0,
+    Pio1,
+    Pio2,
+    Pio3,
+    Pio4,
+}
+
+/// Ultra DMA transfer mode
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum UdmaMode {
+    Udma0,
+    Udma1,
+    Udma2,
+    Udma3,
+    Udma4,
+    Udma5,
+    Udma6,
+}
+
+impl UdmaMode {
+    /// Get the mode with the given number
+    pub fn from_number(number: u8) -> Option<UdmaMode> {
+        match number {
+            0 => Some(UdmaMode::Udma0),
+            1 => Some(UdmaMode::Udma1),
+            2 => Some(UdmaMode::Udma2),
+            3 => Some(UdmaMode::Udma3),
+            4 => Some(UdmaMode::Udma4),
+            5 => Some(UdmaMode::Udma5),
+            6 => Some(UdmaMode::Udma6),
+            _ => None,
+        }
+    }
+
+    /// Get the number of this mode
+    pub fn number(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// Transfer modes supported by a drive
+#[derive(Copy, Clone, Debug)]
+pub struct TransferModes {
+    /// Highest supported PIO mode
+    pub pio: PioMode,
+    /// Highest supported UDMA mode, if any
+    pub udma: Option<UdmaMode>,
+    /// Currently selected UDMA mode, if any
+    pub udma_selected: Option<UdmaMode>,
+    udma_supported: u8,
+}
+
+impl TransferModes {
+    /// The conservative modes that every drive supports
+    pub fn conservative() -> TransferModes {
+        TransferModes {
+            pio: PioMode::Pio0,
+            udma: None,
+            udma_selected: None,
+            udma_supported: 0,
+        }
+    }
+
+    /// Parse the transfer modes from IDENTIFY words 53 (field validity), 64 (advanced PIO modes)
+    /// and 88 (UDMA modes). Modes in words that are marked invalid are not reported
+    pub fn from_identify(validity: u16, pio: u16, udma: u16) -> TransferModes {
+        let mut modes = TransferModes::conservative();
+
+        if validity & 1 << 1 == 1 << 1 {
+            if pio & 1 << 1 == 1 << 1 {
+                modes.pio = PioMode::Pio4;
+            } else if pio & 1 == 1 {
+                modes.pio = PioMode::Pio3;
+            }
+        }
+
+        if validity & 1 << 2 == 1 << 2 {
+            modes.udma_supported = (udma & 0x7F) as u8;
+            for number in 0..7 {
+                if modes.udma_supported & 1 << number == 1 << number {
+                    modes.udma = UdmaMode::from_number(number);
+                }
+                if (udma >> 8) & 1 << number == 1 << number {
+                    modes.udma_selected = UdmaMode::from_number(number);
+                }
+            }
+        }
+
+        modes
+    }
+
+    /// Check if the given UDMA mode is supported
+    pub fn supports_udma(&self, mode: UdmaMode) -> bool {
+        self.udma_supported & 1 << mode.number() == 1 << mode.number()
+    }
+}
+
+/// The fields of an IDENTIFY DEVICE response used by the driver
+#[derive(Clone, Debug)]
+pub struct IdentifyData {
+    /// Model number
+    pub model: String,
+    /// Serial number
+    pub serial: String,
+    /// Firmware revision
+    pub firmware: String,
+    /// Number of addressable logical sectors
+    pub sectors: u64,
+    /// Whether the 48-bit address feature set is supported
+    pub lba48: bool,
+    /// Supported and selected transfer modes
+    pub modes: TransferModes,
+    /// Size of a logical sector in bytes
+    pub sector_size: u32,
+    /// Whether the medium is removable
+    pub removable: bool,
+    /// Whether the medium spins, which is assumed unless the drive reports otherwise
+    pub rotational: bool,
+    /// Whether the drive is an ATAPI device, such as an optical drive
+    pub atapi: bool,
+}
+
+impl IdentifyData {
+    /// The data of a drive that has not been identified
+    pub fn unknown() -> IdentifyData {
+        IdentifyData {
+            model: String::new(),
+            serial: String::new(),
+            firmware: String::new(),
+            sectors: 0,
+            lba48: false,
+            modes: TransferModes::conservative(),
+            sector_size: 512,
+            removable: false,
+            rotational: true,
+            atapi: false,
+        }
+    }
+
+    /// Parse the 256 words returned by IDENTIFY DEVICE
+    pub fn parse(words: &[u16; 256]) -> IdentifyData {
+        let lba48 = words[83] & 1 << 10 == 1 << 10;
+
+        let mut sectors = 0;
+        if lba48 {
+            sectors = (words[100] as u64) | ((words[101] as u64) << 16) |
+                      ((words[102] as u64) << 32) | ((words[103] as u64) << 48);
+        }
+        if sectors == 0 {
+            sectors = (words[60] as u64) | ((words[61] as u64) << 16);
+        }
+
+        // Word 106 is valid if bit 14 is set and bit 15 is clear, bit 12 means words 117 and
+        // 118 hold the logical sector size in words
+        let mut sector_size = 512;
+        if words[106] & 0xD000 == 0x5000 {
+            sector_size = ((words[117] as u32) | ((words[118] as u32) << 16)) * 2;
+        }
+
+        IdentifyData {
+            model: ata_string(&words[27..47]),
+            serial: ata_string(&words[10..20]),
+            firmware: ata_string(&words[23..27]),
+            sectors: sectors,
+            lba48: lba48,
+            modes: TransferModes::from_identify(words[53], words[64], words[88]),
+            sector_size: sector_size,
+            removable: words[0] & 1 << 7 == 1 << 7,
+            // Word 217 is the nominal media rotation rate, 1 for non-rotating media and 0 if
+            // it is not reported
+            rotational: words[217] != 1,
+            atapi: false,
+        }
+    }
+
+    /// Parse the 256 words returned by IDENTIFY PACKET DEVICE. These do not hold the capacity,
+    /// which has to be read from the medium, so there are no sectors until it is
+    pub fn parse_packet(words: &[u16; 256]) -> IdentifyData {
+        let mut identity = IdentifyData::parse(words);
+        identity.sectors = 0;
+        identity.lba48 = false;
+        identity.sector_size = ATAPI_SECTOR_SIZE;
+        identity.atapi = true;
+        identity
+    }
+}
+
+/// Parse the response to READ CAPACITY, the big endian address of the last sector followed by
+/// the big endian sector size. Returns the number of sectors and the sector size
+fn read_capacity(data: &[u8; 8]) -> (u64, u32) {
+    let last = (data[0] as u64) << 24 | (data[1] as u64) << 16 | (data[2] as u64) << 8 | data[3] as u64;
+    let size = (data[4] as u32) << 24 | (data[5] as u32) << 16 | (data[6] as u32) << 8 | data[7] as u32;
+    (last + 1, if size == 0 {
+        ATAPI_SECTOR_SIZE
+    } else {
+        size
+    })
+}
+
+/// Read an ATA string, which stores two characters per word with the first in the high byte, and
+/// trim its padding
+fn ata_string(words: &[u16]) -> String {
+    let mut string = String::new();
+    for word in words.iter() {
+        for b in [(*word >> 8) as u8, *word as u8].iter() {
+            if *b > 0 {
+                string.push(*b as char);
+            }
+        }
+    }
+
+    string.trim().to_string()
 }
 
 /// Direction of DMA, set if moving from disk to memory, not set if moving from memory to disk
@@ -42,6 +275,13 @@ const STS_ACT: u8 = 1;
 /// PRDT End of Table
 const PRD_EOT: u8 = 1 << 7;
 
+/// The first sector that LBA48 cannot address
+const LBA48_LIMIT: u64 = 1 << 48;
+/// The first physical address that a PRD cannot point to, as its address is 32 bits
+const PRD_ADDR_LIMIT: u64 = 1 << 32;
+/// The size of the bounce buffer used for DMA to buffers above `PRD_ADDR_LIMIT`, in sectors
+const BOUNCE_SECTORS: usize = 128;
+
 /// Physical Region Descriptor
 #[repr(packed)]
 struct Prd {
@@ -93,6 +333,72 @@ const ATA_ER_MCR: u8 = 0x08;
 const ATA_ER_ABRT: u8 = 0x04;
 const ATA_ER_TK0NF: u8 = 0x02;
 const ATA_ER_AMNF: u8 = 0x01;
+// Removable media status, reported in place of the bits above
+const ATA_ER_NM: u8 = 0x02;
+
+/// The cause of a failed command, decoded from the error register
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AtaError {
+    /// No command has failed
+    None,
+    /// The command failed without setting the error bit, such as on a timeout or device fault
+    Unknown,
+    BadBlock,
+    Uncorrectable,
+    IdNotFound,
+    Aborted,
+    MediaChanged,
+    MediaChangeRequested,
+    NoMedia,
+    Track0NotFound,
+    AddressMarkNotFound,
+}
+
+impl AtaError {
+    /// Decode the error register. The most specific bit wins when several are set. Bit 1 means
+    /// no media on removable drives
+    pub fn decode(error: u8, removable: bool) -> AtaError {
+        if error & ATA_ER_BBK > 0 {
+            AtaError::BadBlock
+        } else if error & ATA_ER_UNC > 0 {
+            AtaError::Uncorrectable
+        } else if error & ATA_ER_IDNF > 0 {
+            AtaError::IdNotFound
+        } else if removable && error & ATA_ER_NM > 0 {
+            AtaError::NoMedia
+        } else if error & ATA_ER_MC > 0 {
+            AtaError::MediaChanged
+        } else if error & ATA_ER_MCR > 0 {
+            AtaError::MediaChangeRequested
+        } else if error & ATA_ER_ABRT > 0 {
+            AtaError::Aborted
+        } else if error & ATA_ER_TK0NF > 0 {
+            AtaError::Track0NotFound
+        } else if error & ATA_ER_AMNF > 0 {
+            AtaError::AddressMarkNotFound
+        } else {
+            AtaError::Unknown
+        }
+    }
+}
+
+impl fmt::Display for AtaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            AtaError::None => "no error",
+            AtaError::Unknown => "unknown error",
+            AtaError::BadBlock => "bad block",
+            AtaError::Uncorrectable => "uncorrectable data error",
+            AtaError::IdNotFound => "sector not found",
+            AtaError::Aborted => "command aborted",
+            AtaError::MediaChanged => "media changed",
+            AtaError::MediaChangeRequested => "media change requested",
+            AtaError::NoMedia => "no media",
+            AtaError::Track0NotFound => "track 0 not found",
+            AtaError::AddressMarkNotFound => "address mark not found",
+        })
+    }
+}
 
 // Commands
 const ATA_CMD_READ_PIO: u8 = 0x20;
@@ -108,6 +414,30 @@ const ATA_CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
 const ATA_CMD_PACKET: u8 = 0xA0;
 const ATA_CMD_IDENTIFY_PACKET: u8 = 0xA1;
 const ATA_CMD_IDENTIFY: u8 = 0xEC;
+const ATA_CMD_SET_FEATURES: u8 = 0xEF;
+
+// Packet commands, sent in the command descriptor block of ATA_CMD_PACKET
+const ATAPI_CMD_READ_CAPACITY: u8 = 0x25;
+
+/// The values left in the LBA mid and high registers by an ATAPI drive that aborts IDENTIFY
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xEB);
+/// The sector size of optical media, used until the capacity is read
+const ATAPI_SECTOR_SIZE: u32 = 2048;
+
+// Set features subcommands
+const ATA_FEAT_XFER_MODE: u8 = 0x03;
+
+// Transfer mode values for ATA_FEAT_XFER_MODE
+const ATA_XFER_UDMA: u8 = 0x40;
+
+// Programming interface bits
+/// The primary channel is in native PCI mode
+const PROG_IF_PRIMARY_NATIVE: u32 = 1;
+/// The secondary channel is in native PCI mode
+const PROG_IF_SECONDARY_NATIVE: u32 = 1 << 2;
+
+/// Maximum number of status reads while waiting for the drive
+const IDE_POLL_TIMEOUT: usize = 1000000;
 
 // Identification
 const ATA_IDENT_DEVICETYPE: u8 = 0;
@@ -133,6 +463,20 @@ const IDE_ATAPI: u8 = 0x01;
 pub struct Ide;
 
 impl Ide {
+    /// Get the ports and IRQ of a channel. In native PCI mode they are read from the BARs and the
+    /// interrupt line, in compatibility mode the legacy values are used
+    fn channel(pci: &mut PciConfig, native: bool, bar: u8, legacy: (u16, u16, u8)) -> (u16, u16, u8) {
+        if native {
+            unsafe {
+                (pci.read(bar) as u16 & 0xFFFC,
+                 pci.read(bar + 4) as u16 & 0xFFFC,
+                 pci.read(0x3C) as u8)
+            }
+        } else {
+            legacy
+        }
+    }
+
     pub fn disks(mut pci: PciConfig) -> Vec<Box<Disk>> {
         let mut ret: Vec<Box<Disk>> = Vec::new();
 
@@ -140,26 +484,40 @@ impl Ide {
 
         let busmaster = unsafe { pci.read(0x20) } as u16 & 0xFFF0;
 
+        let prog_if = (unsafe { pci.read(0x08) } >> 8) & 0xFF;
+        let primary_native = prog_if & PROG_IF_PRIMARY_NATIVE == PROG_IF_PRIMARY_NATIVE;
+        let secondary_native = prog_if & PROG_IF_SECONDARY_NATIVE == PROG_IF_SECONDARY_NATIVE;
+
+        let (base, ctrl, irq) = Ide::channel(&mut pci, primary_native, 0x10, (0x1F0, 0x3F4, 0xE));
+
         debug!("Primary Master:");
-        if let Some(disk) = IdeDisk::new(busmaster, 0x1F0, 0x3F4, 0xE, true) {
+        if primary_native {
+            debug!(" Native");
+        }
+        if let Some(disk) = IdeDisk::new(busmaster, base, ctrl, irq, true, true) {
             ret.push(box disk);
         }
         debugln!("");
 
         debug!("Primary Slave:");
-        if let Some(disk) = IdeDisk::new(busmaster, 0x1F0, 0x3F4, 0xE, false) {
+        if let Some(disk) = IdeDisk::new(busmaster, base, ctrl, irq, true, false) {
             ret.push(box disk);
         }
         debugln!("");
 
+        let (base, ctrl, irq) = Ide::channel(&mut pci, secondary_native, 0x18, (0x170, 0x374, 0xF));
+
         debug!("Secondary Master:");
-        if let Some(disk) = IdeDisk::new(busmaster + 8, 0x170, 0x374, 0xF, true) {
+        if secondary_native {
+            debug!(" Native");
+        }
+        if let Some(disk) = IdeDisk::new(busmaster + 8, base, ctrl, irq, false, true) {
             ret.push(box disk);
         }
         debugln!("");
 
         debug!("Secondary Slave:");
-        if let Some(disk) = IdeDisk::new(busmaster + 8, 0x170, 0x374, 0xF, false) {
+        if let Some(disk) = IdeDisk::new(busmaster + 8, base, ctrl, irq, false, false) {
             ret.push(box disk);
         }
         debugln!("");
@@ -175,6 +533,7 @@ pub struct IdeDisk {
     prdt: Prdt,
     data: Pio<u16>,
     error: ReadOnly<u8, Pio<u8>>,
+    features: WriteOnly<u8, Pio<u8>>,
     seccount: Pio<u8>,
     sector0: Pio<u8>,
     sector1: Pio<u8>,
@@ -184,17 +543,25 @@ pub struct IdeDisk {
     cmd: WriteOnly<u8, Pio<u8>>,
     alt_sts: ReadOnly<u8, Pio<u8>>,
     irq: u8,
+    primary: bool,
     master: bool,
+    identity: IdentifyData,
+    last_error: AtaError,
+    error_count: u64,
+    pio_only: bool,
+    /// Zeroed sectors for `write_zeros`, allocated on first use
+    zeros: Option<Memory<u8>>,
 }
 
 impl IdeDisk {
-    pub fn new(busmaster: u16, base: u16, ctrl: u16, irq: u8, master: bool) -> Option<Self> {
+    pub fn new(busmaster: u16, base: u16, ctrl: u16, irq: u8, primary: bool, master: bool) -> Option<Self> {
         let mut ret = IdeDisk {
             buscmd: Pio::new(busmaster),
             bussts: Pio::new(busmaster + 2),
             prdt: Prdt::new(busmaster + 4),
             data: Pio::new(base),
             error: ReadOnly::new(Pio::new(base + 1)),
+            features: WriteOnly::new(Pio::new(base + 1)),
             seccount: Pio::new(base + 2),
             sector0: Pio::new(base + 3),
             sector1: Pio::new(base + 4),
@@ -204,7 +571,13 @@ impl IdeDisk {
             cmd: WriteOnly::new(Pio::new(base + 7)),
             alt_sts: ReadOnly::new(Pio::new(ctrl + 2)),
             irq: irq,
+            primary: primary,
             master: master,
+            identity: IdentifyData::unknown(),
+            last_error: AtaError::None,
+            error_count: 0,
+            pio_only: false,
+            zeros: None,
         };
 
         if unsafe { ret.identify() } {
@@ -215,7 +588,13 @@ impl IdeDisk {
     }
 
     unsafe fn ide_poll(&self, check_error: bool) -> u8 {
-        while self.alt_sts.readf(ATA_SR_BSY) {}
+        let mut timeout = IDE_POLL_TIMEOUT;
+        while self.alt_sts.readf(ATA_SR_BSY) {
+            if timeout == 0 {
+                return 4;
+            }
+            timeout -= 1;
+        }
 
         if check_error {
             let state = self.alt_sts.read();
@@ -262,12 +641,13 @@ impl IdeDisk {
         self.cmd.write(cmd);
     }
 
-    /// Identify
-    pub unsafe fn identify(&mut self) -> bool {
+    /// Send IDENTIFY DEVICE and parse the response, returns None if there is no drive or it
+    /// does not respond
+    pub unsafe fn ata_identify(&mut self) -> Option<IdentifyData> {
         if self.alt_sts.read() == 0xFF {
             debug!(" Floating Bus");
 
-            return false;
+            return None;
         }
 
         self.ata(ATA_CMD_IDENTIFY, 0, 0);
@@ -276,71 +656,243 @@ impl IdeDisk {
         debug!(" Status: {:X}", status);
 
         if status == 0 {
-            return false;
+            return None;
         }
 
         let err = self.ide_poll(true);
         if err > 0 {
+            if (self.sector1.read(), self.sector2.read()) == ATAPI_SIGNATURE {
+                return self.atapi_identify();
+            }
+
             debug!(" Error: {:X}", err);
 
-            return false;
+            return None;
         }
 
-        let mut destination = Memory::<u16>::new(256).unwrap();
-        for word in 0..256 {
-            destination.write(word, self.data.read());
+        let mut words = [0; 256];
+        for word in words.iter_mut() {
+            *word = self.data.read();
         }
 
-        debug!(" Serial: ");
-        for word in 10..20 {
-            let d = destination.read(word);
-            let a = ((d >> 8) as u8) as char;
-            if a != ' ' && a != '\0' {
-                debug!("{}", a);
-            }
-            let b = (d as u8) as char;
-            if b != ' ' && b != '\0' {
-                debug!("{}", b);
-            }
+        Some(IdentifyData::parse(&words))
+    }
+
+    /// Send IDENTIFY PACKET DEVICE and parse the response, then read the capacity of the medium.
+    /// A drive without a medium is identified with no sectors rather than failing
+    unsafe fn atapi_identify(&mut self) -> Option<IdentifyData> {
+        debug!(" ATAPI");
+
+        self.ata(ATA_CMD_IDENTIFY_PACKET, 0, 0);
+
+        let err = self.ide_poll(true);
+        if err > 0 {
+            debug!(" Error: {:X}", err);
+
+            return None;
         }
 
-        debug!(" Firmware: ");
-        for word in 23..27 {
-            let d = destination.read(word);
-            let a = ((d >> 8) as u8) as char;
-            if a != ' ' && a != '\0' {
-                debug!("{}", a);
-            }
-            let b = (d as u8) as char;
-            if b != ' ' && b != '\0' {
-                debug!("{}", b);
-            }
+        let mut words = [0; 256];
+        for word in words.iter_mut() {
+            *word = self.data.read();
         }
 
-        debug!(" Model: ");
-        for word in 27..47 {
-            let d = destination.read(word);
-            let a = ((d >> 8) as u8) as char;
-            if a != ' ' && a != '\0' {
-                debug!("{}", a);
-            }
-            let b = (d as u8) as char;
-            if b != ' ' && b != '\0' {
-                debug!("{}", b);
+        let mut identity = IdentifyData::parse_packet(&words);
+        match self.atapi_capacity() {
+            Ok((sectors, sector_size)) => {
+                identity.sectors = sectors;
+                identity.sector_size = sector_size;
+            },
+            Err(_) => debug!(" No Medium"),
+        }
+
+        Some(identity)
+    }
+
+    /// Read the number of sectors and the sector size of the medium with READ CAPACITY
+    unsafe fn atapi_capacity(&mut self) -> Result<(u64, u32)> {
+        let mut cdb = [0; 12];
+        cdb[0] = ATAPI_CMD_READ_CAPACITY;
+
+        let mut data = [0; 8];
+        if try!(self.atapi_packet(&cdb, &mut data)) < data.len() {
+            return Err(Error::new(EIO));
+        }
+
+        Ok(read_capacity(&data))
+    }
+
+    /// Send a PACKET command with a command descriptor block and read the data it returns into
+    /// `buf` with PIO. Returns the number of bytes read, or EIO if the drive fails the command,
+    /// as a drive without a medium does
+    unsafe fn atapi_packet(&mut self, cdb: &[u8; 12], buf: &mut [u8]) -> Result<usize> {
+        while self.alt_sts.readf(ATA_SR_BSY) {}
+
+        self.devsel.write(if self.master {
+            0b10100000
+        } else {
+            0b10110000
+        });
+
+        self.alt_sts.read();
+        self.alt_sts.read();
+        self.alt_sts.read();
+        self.alt_sts.read();
+
+        while self.alt_sts.readf(ATA_SR_BSY) {}
+
+        // PIO, with the largest transfer per data request in the LBA mid and high registers
+        self.features.write(0);
+        self.sector1.write(buf.len() as u8);
+        self.sector2.write((buf.len() >> 8) as u8);
+        self.cmd.write(ATA_CMD_PACKET);
+
+        if self.ide_poll(true) > 0 {
+            return Err(Error::new(EIO));
+        }
+
+        for pair in cdb.chunks(2) {
+            self.data.write(pair[0] as u16 | (pair[1] as u16) << 8);
+        }
+
+        if self.ide_poll(true) > 0 {
+            return Err(Error::new(EIO));
+        }
+
+        let count = cmp::min(self.sector1.read() as usize | (self.sector2.read() as usize) << 8, buf.len());
+        for i in 0..(count + 1) / 2 {
+            let word = self.data.read();
+            buf[i * 2] = word as u8;
+            if i * 2 + 1 < count {
+                buf[i * 2 + 1] = (word >> 8) as u8;
             }
         }
 
-        let mut sectors = (destination.read(100) as u64) | ((destination.read(101) as u64) << 16) |
-                          ((destination.read(102) as u64) << 32) |
-                          ((destination.read(103) as u64) << 48);
+        Ok(count)
+    }
 
-        if sectors == 0 {
-            sectors = (destination.read(60) as u64) | ((destination.read(61) as u64) << 16);
+    /// Identify the drive and remember its data, returns false if there is no usable drive
+    pub unsafe fn identify(&mut self) -> bool {
+        match self.ata_identify() {
+            Some(identity) => {
+                debug!(" Serial: {}", identity.serial);
+                debug!(" Firmware: {}", identity.firmware);
+                debug!(" Model: {}", identity.model);
+                debug!(" Size: {} MB", (identity.sectors * identity.sector_size as u64 / 1048576) as usize);
+                debug!(" PIO: {:?}", identity.modes.pio);
+                if let Some(udma) = identity.modes.udma {
+                    debug!(" UDMA: {:?}", udma);
+                }
+                if ! identity.rotational {
+                    debug!(" Non-rotational");
+                }
+
+                self.identity = identity;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Get the data of the last successful identify
+    pub fn identity(&self) -> &IdentifyData {
+        &self.identity
+    }
+
+    /// Get the model number of the drive
+    pub fn model(&self) -> &str {
+        &self.identity.model
+    }
+
+    /// Get the transfer modes supported by the drive
+    pub fn transfer_modes(&self) -> TransferModes {
+        self.identity.modes
+    }
+
+    /// Select the UDMA mode used by the drive with SET FEATURES, then identify again to verify it
+    pub fn set_dma_mode(&mut self, mode: UdmaMode) -> Result<()> {
+        if ! self.identity.modes.supports_udma(mode) {
+            return Err(Error::new(EINVAL));
         }
 
-        debug!(" Size: {} MB", (sectors / 2048) as usize);
+        self.features.write(ATA_FEAT_XFER_MODE);
+        self.ata(ATA_CMD_SET_FEATURES, 0, (ATA_XFER_UDMA | mode.number()) as u16);
+
+        let err = unsafe { self.ide_poll(false) };
+        if err > 0 || self.alt_sts.readf(ATA_SR_ERR) {
+            debugln!("IDE Set Features Error: {:X}", err);
+            return Err(Error::new(EIO));
+        }
+
+        if unsafe { self.identify() } && self.identity.modes.udma_selected == Some(mode) {
+            Ok(())
+        } else {
+            debugln!("IDE Failed to select {:?}", mode);
+            Err(Error::new(EIO))
+        }
+    }
+
+    /// Flush the write cache of the drive
+    pub fn ata_flush(&mut self) -> Result<()> {
+        self.ata(ATA_CMD_CACHE_FLUSH_EXT, 0, 0);
+
+        unsafe { self.ide_poll(false) };
+
+        if self.alt_sts.readf(ATA_SR_ERR) {
+            debugln!("IDE Flush Error");
+            Err(Error::new(EIO))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Get the cause of the last failed transfer
+    pub fn last_error(&self) -> AtaError {
+        self.last_error
+    }
+
+    /// Make reads and writes use PIO even if the drive can do DMA. This is for isolating
+    /// corruption caused by DMA, PIO is much slower
+    pub fn set_pio_only(&mut self, pio_only: bool) {
+        self.pio_only = pio_only;
+    }
+
+    /// Check if reads and writes are forced to use PIO
+    pub fn is_pio_only(&self) -> bool {
+        self.pio_only
+    }
+
+    /// Get the error of a failed transfer. Removable drives report a missing medium, which gives
+    /// ENOMEDIUM, or a changed medium, which gives EAGAIN once the capacity of the new medium is
+    /// read. Anything else is EIO
+    unsafe fn transfer_error(&mut self) -> Error {
+        self.error_count += 1;
+
+        if ! self.alt_sts.readf(ATA_SR_ERR) {
+            self.last_error = AtaError::Unknown;
+            return Error::new(EIO);
+        }
+
+        let error = self.error.read();
+        self.last_error = AtaError::decode(error, self.identity.removable);
+        debugln!("{}: {}", self.name(), self.last_error);
+
+        if self.identity.removable {
+            if error & (ATA_ER_NM | ATA_ER_MCR) > 0 {
+                return Error::new(ENOMEDIUM);
+            }
+
+            if error & ATA_ER_MC > 0 {
+                debugln!("{}: Medium changed", self.name());
+                return if self.identify() {
+                    Error::new(EAGAIN)
+                } else {
+                    Error::new(ENOMEDIUM)
+                };
+            }
+        }
 
-        true
+        Error::new(EIO)
     }
 
     unsafe fn ata_pio_small(&mut self,
@@ -360,7 +912,7 @@ impl IdeDisk {
                 let err = self.ide_poll(true);
                 if err > 0 {
                     debugln!("IDE Error: {:X}", err);
-                    return Err(Error::new(EIO));
+                    return Err(self.transfer_error());
                 }
 
                 if write {
@@ -374,6 +926,15 @@ impl IdeDisk {
                     for word in 0..256 {
                         ptr::write((buf + sector * 512 + word * 2) as *mut u16, self.data.read());
                     }
+
+                    // A drive that disconnected mid-transfer reads back as all ones, so check that
+                    // the status is still sane before trusting the data. DRQ is checked by the
+                    // poll before the next sector
+                    let status = self.alt_sts.read();
+                    if status == 0xFF || status & (ATA_SR_ERR | ATA_SR_DF) > 0 {
+                        debugln!("IDE Read Error: status {:X} after sector {}", status, sector);
+                        return Err(self.transfer_error());
+                    }
                 }
             }
 
@@ -384,9 +945,22 @@ impl IdeDisk {
         }
     }
 
+    /// Check that a transfer only covers sectors that LBA48 can address, instead of letting the
+    /// high bits of the block be dropped and a different sector be transferred
+    fn check_lba(block: u64, sectors: usize) -> Result<()> {
+        if block >= LBA48_LIMIT || sectors as u64 > LBA48_LIMIT - block {
+            debugln!("IDE block {} + {} sectors out of LBA48 range", block, sectors);
+            Err(Error::new(EINVAL))
+        } else {
+            Ok(())
+        }
+    }
+
     fn ata_pio(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
         // debugln!("IDE PIO BLOCK: {} SECTORS: {} BUF: {:X} WRITE: {}", block, sectors, buf, write);
 
+        try!(IdeDisk::check_lba(block, sectors));
+
         if buf > 0 && sectors > 0 {
             let mut sector: usize = 0;
             while sectors - sector >= 65536 {
@@ -442,7 +1016,7 @@ impl IdeDisk {
             for i in 0..entries {
                 self.prdt.mem.write(i,
                                     Prd {
-                                        addr: buf as u32 + offset,
+                                        addr: (buf as u64 + offset) as u32,
                                         size: 0,
                                         rsv: 0,
                                         eot: if i == entries - 1 && remainder == 0 {
@@ -457,7 +1031,7 @@ impl IdeDisk {
             if remainder > 0 {
                 self.prdt.mem.write(entries,
                                     Prd {
-                                        addr: buf as u32 + offset,
+                                        addr: (buf as u64 + offset) as u32,
                                         size: remainder,
                                         rsv: 0,
                                         eot: PRD_EOT,
@@ -488,7 +1062,7 @@ impl IdeDisk {
 
             if status & STS_ERR == STS_ERR {
                 debugln!("IDE DMA Read Error");
-                return Err(Error::new(EIO));
+                return Err(self.transfer_error());
             }
 
             Ok(sectors as usize * 512)
@@ -498,9 +1072,54 @@ impl IdeDisk {
         }
     }
 
+    /// Check if a buffer reaches above what a PRD can address, so DMA must go through a bounce
+    /// buffer
+    fn needs_bounce(buf: usize, sectors: usize) -> bool {
+        buf as u64 + sectors as u64 * 512 > PRD_ADDR_LIMIT
+    }
+
+    /// Transfer with DMA through a bounce buffer from the kernel heap, which lies below 4 GiB,
+    /// copying to or from the caller's buffer around each transfer
+    fn ata_dma_bounce(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
+        let chunk = cmp::min(sectors, BOUNCE_SECTORS);
+        if let Some(bounce) = Memory::<u8>::new(chunk * 512) {
+            if IdeDisk::needs_bounce(bounce.ptr as usize, chunk) {
+                return Err(Error::new(EINVAL));
+            }
+
+            let mut sector = 0;
+            while sector < sectors {
+                let count = cmp::min(sectors - sector, chunk);
+                let data = (buf + sector * 512) as *mut u8;
+                unsafe {
+                    if write {
+                        ptr::copy(data, bounce.ptr, count * 512);
+                    }
+                    try!(self.ata_dma_small(block + sector as u64, count as u16, bounce.ptr as usize, write));
+                    if ! write {
+                        ptr::copy(bounce.ptr, data, count * 512);
+                    }
+                }
+                sector += count;
+            }
+
+            Ok(sectors * 512)
+        } else {
+            Err(Error::new(ENOMEM))
+        }
+    }
+
+    /// Transfer with DMA. PRD addresses are 32 bits, so a buffer reaching above 4 GiB physical
+    /// is transferred through a bounce buffer
     fn ata_dma(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
         // debugln!("IDE DMA BLOCK: {} SECTORS: {} BUF: {:X} WRITE: {}", block, sectors, buf, write);
 
+        try!(IdeDisk::check_lba(block, sectors));
+
+        if buf > 0 && sectors > 0 && IdeDisk::needs_bounce(buf, sectors) {
+            return self.ata_dma_bounce(block, sectors, buf, write);
+        }
+
         if buf > 0 && sectors > 0 {
             let mut sector: usize = 0;
             while sectors - sector >= 65536 {
@@ -531,9 +1150,39 @@ impl IdeDisk {
     }
 }
 
+/// The ways a drive can transfer sectors. Choosing between them is kept apart from the hardware
+/// so that it can be tested
+trait AtaTransfer {
+    fn pio_only(&self) -> bool;
+    fn pio(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize>;
+    fn dma(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize>;
+
+    fn transfer(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
+        if self.pio_only() {
+            self.pio(block, sectors, buf, write)
+        } else {
+            self.dma(block, sectors, buf, write)
+        }
+    }
+}
+
+impl AtaTransfer for IdeDisk {
+    fn pio_only(&self) -> bool {
+        self.pio_only
+    }
+
+    fn pio(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
+        self.ata_pio(block, sectors, buf, write)
+    }
+
+    fn dma(&mut self, block: u64, sectors: usize, buf: usize, write: bool) -> Result<usize> {
+        self.ata_dma(block, sectors, buf, write)
+    }
+}
+
 impl Disk for IdeDisk {
     fn name(&self) -> String {
-        format!("IDE {} {}", if self.irq == 0xE {
+        format!("IDE {} {}", if self.primary {
             "Primary"
         } else {
             "Secondary"
@@ -545,10 +1194,234 @@ impl Disk for IdeDisk {
     }
 
     fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
-        self.ata_dma(block, buffer.len() / 512, buffer.as_ptr() as usize, false)
+        self.transfer(block, buffer.len() / 512, buffer.as_ptr() as usize, false)
     }
 
     fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
-        self.ata_dma(block, buffer.len() / 512, buffer.as_ptr() as usize, true)
+        self.transfer(block, buffer.len() / 512, buffer.as_ptr() as usize, true)
+    }
+
+    /// ATA has no simple command to write a run of zeros, so they are transferred like any other
+    /// data, from a zeroed buffer that is kept for the life of the disk instead of being allocated
+    /// on every call. It comes from the kernel heap, so DMA never bounces it
+    fn write_zeros(&mut self, block: u64, count: u64) -> Result<()> {
+        if self.zeros.is_none() {
+            match Memory::<u8>::new(BOUNCE_SECTORS * 512) {
+                Some(zeros) => {
+                    unsafe { ptr::write_bytes(zeros.ptr, 0, BOUNCE_SECTORS * 512) };
+                    self.zeros = Some(zeros);
+                },
+                None => return Err(Error::new(ENOMEM)),
+            }
+        }
+        let zeros = match self.zeros {
+            Some(ref zeros) => zeros.ptr as usize,
+            None => return Err(Error::new(ENOMEM)),
+        };
+
+        let chunk = cmp::min(BOUNCE_SECTORS, self.max_transfer_sectors()) as u64;
+        let mut sector = 0;
+        while sector < count {
+            let sectors = cmp::min(count - sector, chunk) as usize;
+            if try!(self.transfer(block + sector, sectors, zeros, true)) < sectors * 512 {
+                return Err(Error::new(EIO));
+            }
+            sector += sectors as u64;
+        }
+
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.identity.sectors * self.identity.sector_size as u64
+    }
+
+    fn is_rotational(&self) -> bool {
+        self.identity.rotational
+    }
+
+    /// Only the alternate status register is read, which unlike the status register does not
+    /// acknowledge a pending interrupt. ATA reports write protection only through media status
+    /// commands, which would have to wait for the transfer in progress, so it is not known
+    fn status(&mut self) -> DiskStatus {
+        let state = self.alt_sts.read();
+        DiskStatus {
+            // A bus without a drive floats high
+            online: state != 0xFF && state & ATA_SR_DF == 0,
+            write_protected: false,
+            error_count: self.error_count,
+        }
+    }
+
+    fn sector_size(&self) -> u64 {
+        self.identity.sector_size as u64
+    }
+
+    /// A sector count of 0 means 256 sectors with LBA28 and 65536 with LBA48
+    fn max_transfer_sectors(&self) -> usize {
+        if self.identity.lba48 {
+            65536
+        } else {
+            256
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.ata_flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use collections::vec::Vec;
+
+    use system::error::{Result, EINVAL};
+
+    use super::{read_capacity, AtaError, AtaTransfer, IdeDisk, IdentifyData, PioMode, UdmaMode};
+
+    /// Records which path each transfer took
+    struct MockTransfer {
+        pio_only: bool,
+        paths: Vec<&'static str>,
+    }
+
+    impl AtaTransfer for MockTransfer {
+        fn pio_only(&self) -> bool {
+            self.pio_only
+        }
+
+        fn pio(&mut self, _block: u64, sectors: usize, _buf: usize, _write: bool) -> Result<usize> {
+            self.paths.push("pio");
+            Ok(sectors * 512)
+        }
+
+        fn dma(&mut self, _block: u64, sectors: usize, _buf: usize, _write: bool) -> Result<usize> {
+            self.paths.push("dma");
+            Ok(sectors * 512)
+        }
+    }
+
+    /// Store an ATA string padded with spaces in the given words
+    fn put_string(words: &mut [u16], string: &str) {
+        let mut bytes = string.bytes().chain(b"                                        ".iter().cloned());
+        for word in words.iter_mut() {
+            *word = (bytes.next().unwrap() as u16) << 8 | bytes.next().unwrap() as u16;
+        }
+    }
+
+    #[test]
+    fn parse_identify() {
+        let mut words = [0; 256];
+        put_string(&mut words[10..20], "QM00001");
+        put_string(&mut words[23..27], "2.5+");
+        put_string(&mut words[27..47], "QEMU HARDDISK");
+        words[53] = 0x0007;
+        words[60] = 0x0000;
+        words[61] = 0x0010;
+        words[64] = 0x0003;
+        words[83] = 0x7400;
+        words[88] = 0x203F;
+        words[100] = 0x0000;
+        words[101] = 0x0020;
+
+        let identity = IdentifyData::parse(&words);
+        assert_eq!(identity.serial, "QM00001");
+        assert_eq!(identity.firmware, "2.5+");
+        assert_eq!(identity.model, "QEMU HARDDISK");
+        assert!(identity.lba48);
+        assert_eq!(identity.sectors, 0x200000);
+        assert_eq!(identity.sector_size, 512);
+        assert!(! identity.removable);
+        assert!(identity.rotational);
+        assert_eq!(identity.modes.pio, PioMode::Pio4);
+        assert_eq!(identity.modes.udma, Some(UdmaMode::Udma5));
+        assert_eq!(identity.modes.udma_selected, Some(UdmaMode::Udma5));
+    }
+
+    #[test]
+    fn parse_identify_without_lba48() {
+        let mut words = [0; 256];
+        words[0] = 0x0080;
+        words[60] = 0x5000;
+        words[61] = 0x0001;
+        words[100] = 0xFFFF;
+        words[106] = 0x5000;
+        words[117] = 0x0800;
+        words[217] = 0x0001;
+
+        let identity = IdentifyData::parse(&words);
+        assert!(! identity.lba48);
+        assert!(! identity.rotational);
+        assert_eq!(identity.sectors, 0x15000);
+        assert_eq!(identity.sector_size, 4096);
+        assert!(identity.removable);
+        assert_eq!(identity.modes.udma, None);
+    }
+
+    #[test]
+    fn parse_identify_packet() {
+        let mut words = [0; 256];
+        words[0] = 0x8580;
+        put_string(&mut words[27..47], "QEMU DVD-ROM");
+        words[83] = 0x7400;
+
+        let identity = IdentifyData::parse_packet(&words);
+        assert_eq!(identity.model, "QEMU DVD-ROM");
+        assert!(identity.atapi);
+        assert!(identity.removable);
+        assert!(! identity.lba48);
+        assert_eq!(identity.sectors, 0);
+        assert_eq!(identity.sector_size, 2048);
+    }
+
+    #[test]
+    fn parse_read_capacity() {
+        assert_eq!(read_capacity(&[0x00, 0x05, 0x7F, 0xFF, 0x00, 0x00, 0x08, 0x00]), (0x58000, 2048));
+        assert_eq!(read_capacity(&[0, 0, 0, 0, 0, 0, 0, 0]), (1, 2048));
+    }
+
+    #[test]
+    fn decode_error() {
+        assert_eq!(AtaError::decode(0x40, false), AtaError::Uncorrectable);
+        assert_eq!(AtaError::decode(0x04, false), AtaError::Aborted);
+        assert_eq!(AtaError::decode(0x14, false), AtaError::IdNotFound);
+        assert_eq!(AtaError::decode(0xC0, false), AtaError::BadBlock);
+        assert_eq!(AtaError::decode(0x02, false), AtaError::Track0NotFound);
+        assert_eq!(AtaError::decode(0x02, true), AtaError::NoMedia);
+        assert_eq!(AtaError::decode(0x20, true), AtaError::MediaChanged);
+        assert_eq!(AtaError::decode(0x00, false), AtaError::Unknown);
+        assert_eq!(format!("{}", AtaError::Uncorrectable), "uncorrectable data error");
+    }
+
+    #[test]
+    fn check_lba() {
+        assert!(IdeDisk::check_lba(0, 65536).is_ok());
+        assert!(IdeDisk::check_lba((1 << 48) - 1, 1).is_ok());
+        assert_eq!(IdeDisk::check_lba((1 << 48) - 1, 2).unwrap_err().errno, EINVAL);
+        assert_eq!(IdeDisk::check_lba(1 << 48, 1).unwrap_err().errno, EINVAL);
+    }
+
+    #[test]
+    fn needs_bounce() {
+        assert!(! IdeDisk::needs_bounce(0x1000, 8));
+        assert!(! IdeDisk::needs_bounce(0xFFFF_F000, 8));
+        assert!(IdeDisk::needs_bounce(0xFFFF_F000, 9));
+        if cfg!(target_pointer_width = "64") {
+            assert!(IdeDisk::needs_bounce(0x1_0000_0000u64 as usize, 1));
+        }
+    }
+
+    #[test]
+    fn pio_only() {
+        let mut mock = MockTransfer {
+            pio_only: false,
+            paths: Vec::new(),
+        };
+        assert_eq!(mock.transfer(0, 1, 0x1000, false).unwrap(), 512);
+
+        mock.pio_only = true;
+        assert_eq!(mock.transfer(0, 2, 0x1000, false).unwrap(), 1024);
+        assert_eq!(mock.transfer(0, 1, 0x1000, true).unwrap(), 512);
+        assert_eq!(mock.paths, ["dma", "pio", "pio"]);
     }
 }
@@ -1,12 +1,177 @@
 use collections::string::String;
 
-use system::error::Result;
+use core::{cmp, slice};
+
+use arch::memory::Memory;
+
+use system::error::{Error, Result, EIO, ENOMEM};
 
 pub mod ahci;
+pub mod cache;
+pub mod crypt;
 pub mod ide;
+pub mod partition;
+pub mod trace;
+
+/// The most sectors of zeros written at once by `Disk::write_zeros`
+pub const ZERO_SECTORS: usize = 128;
+
+/// The health of a disk, as reported by `Disk::status`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DiskStatus {
+    /// The disk is attached and responding
+    pub online: bool,
+    /// Writes to the disk are refused
+    pub write_protected: bool,
+    /// The number of transfers that failed since the disk was found
+    pub error_count: u64,
+}
+
+impl DiskStatus {
+    /// The status of a disk that cannot fail, like one in memory
+    pub fn healthy() -> Self {
+        DiskStatus {
+            online: true,
+            write_protected: false,
+            error_count: 0,
+        }
+    }
+}
 
 pub trait Disk {
     fn name(&self) -> String;
     fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize>;
     fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize>;
+
+    /// Write zeros to `count` sectors starting at `block`. By default a zeroed buffer of up to
+    /// `ZERO_SECTORS` sectors is allocated once and written over the range in as few writes as
+    /// `max_transfer_sectors` allows. A short write gives EIO
+    fn write_zeros(&mut self, block: u64, count: u64) -> Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+
+        let chunk = cmp::min(count, cmp::min(ZERO_SECTORS, cmp::max(self.max_transfer_sectors(), 1)) as u64) as usize;
+        if let Some(data) = Memory::<u8>::new(chunk * 512) {
+            let zeros = unsafe { slice::from_raw_parts_mut(data.ptr, chunk * 512) };
+            for b in zeros.iter_mut() {
+                *b = 0;
+            }
+
+            let mut sector = 0;
+            while sector < count {
+                let sectors = cmp::min(count - sector, chunk as u64) as usize;
+                if try!(self.write(block + sector, &zeros[..sectors * 512])) < sectors * 512 {
+                    return Err(Error::new(EIO));
+                }
+                sector += sectors as u64;
+            }
+
+            Ok(())
+        } else {
+            Err(Error::new(ENOMEM))
+        }
+    }
+
+    /// Get the size of the disk in bytes, 0 if it is not known
+    fn size(&self) -> u64 {
+        0
+    }
+
+    /// Get the size of a sector of the media in bytes. Blocks passed to `read` and `write` are
+    /// always 512 bytes, but a file system should not use blocks smaller than the media does
+    fn sector_size(&self) -> u64 {
+        512
+    }
+
+    /// Get the largest number of sectors the driver can transfer in one command. Callers that
+    /// batch transfers should not make them larger than this
+    fn max_transfer_sectors(&self) -> usize {
+        255
+    }
+
+    /// Check if the disk has spinning media, which makes seeks expensive. Disks that do not know
+    /// are assumed to be rotational
+    fn is_rotational(&self) -> bool {
+        true
+    }
+
+    /// Get the health of the disk. This is meant to be polled, so it must be cheap and must not
+    /// disturb transfers in progress
+    fn status(&mut self) -> DiskStatus {
+        DiskStatus::healthy()
+    }
+
+    /// Make sure all previous writes have reached the media
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called periodically by the kernel, for disks that do background work such as writing back
+    /// cached data
+    fn on_tick(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Read bytes starting at a byte offset that need not be sector aligned. The sectors covering
+    /// the range are read into a scratch buffer, so a span within one sector needs at most two
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let start = (offset % 512) as usize;
+        let size = (start + buf.len() + 511) / 512 * 512;
+
+        if let Some(data) = Memory::<u8>::new(size) {
+            let scratch = unsafe { slice::from_raw_parts_mut(data.ptr, size) };
+            let count = try!(self.read(offset / 512, scratch));
+
+            let end = cmp::min(count, start + buf.len());
+            if end <= start {
+                return Ok(0);
+            }
+
+            for (b, s) in buf.iter_mut().zip(scratch[start..end].iter()) {
+                *b = *s;
+            }
+
+            Ok(end - start)
+        } else {
+            Err(Error::new(ENOMEM))
+        }
+    }
+
+    /// Write bytes starting at a byte offset that need not be sector aligned. The sectors covering
+    /// the range are read, modified, and written back, so the bytes around the range are kept
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block = offset / 512;
+        let start = (offset % 512) as usize;
+        let size = (start + buf.len() + 511) / 512 * 512;
+
+        if let Some(data) = Memory::<u8>::new(size) {
+            let scratch = unsafe { slice::from_raw_parts_mut(data.ptr, size) };
+
+            // Only the partial first and last sectors need their old contents
+            if start > 0 {
+                try!(self.read(block, &mut scratch[..512]));
+            }
+            if (start + buf.len()) % 512 > 0 && (size > 512 || start == 0) {
+                try!(self.read(block + (size / 512 - 1) as u64, &mut scratch[size - 512..]));
+            }
+
+            for (s, b) in scratch[start..].iter_mut().zip(buf.iter()) {
+                *s = *b;
+            }
+
+            let count = try!(self.write(block, scratch));
+            Ok(cmp::min(count.saturating_sub(start), buf.len()))
+        } else {
+            Err(Error::new(ENOMEM))
+        }
+    }
 }
@@ -0,0 +1,529 @@
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+
+use collections::string::String;
+use collections::vec::Vec;
+
+use core::cell::UnsafeCell;
+use core::{cmp, slice};
+
+use arch::memory::Memory;
+
+use disk::{Disk, DiskStatus};
+
+use fs::redoxfs::crc32::crc32;
+
+use sync::{Intex, WaitCondition};
+
+use system::error::{Error, Result, EINVAL, EIO, ENOMEM};
+
+/// The offset of the partition entries in the MBR
+const MBR_ENTRIES: usize = 446;
+
+/// Partition types that hold extended partitions, which are not supported yet
+const EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+
+/// The partition type of the protective MBR entry of a GPT disk
+const PROTECTIVE_TYPE: u8 = 0xEE;
+
+/// The block of the primary GPT header
+const GPT_HEADER_BLOCK: u64 = 1;
+
+/// The largest partition entry array that is read, 1024 entries of the usual size
+const GPT_ENTRIES_MAX: usize = 1024 * 128;
+
+/// The type of a partition
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PartitionKind {
+    /// An MBR partition type
+    Mbr(u8),
+    /// A GPT partition type GUID
+    Gpt([u8; 16]),
+}
+
+/// A partition from the MBR or GPT
+#[derive(Clone, Debug, PartialEq)]
+pub struct Partition {
+    /// The number of the partition, starting at 1
+    pub number: usize,
+    /// The partition type
+    pub kind: PartitionKind,
+    /// The first sector
+    pub start: u64,
+    /// The number of sectors
+    pub sectors: u64,
+    /// The name of the partition, empty for MBR partitions
+    pub name: String,
+}
+
+/// Read a little endian integer of `size` bytes
+fn le(bytes: &[u8], offset: usize, size: usize) -> u64 {
+    let mut value = 0;
+    for i in 0..size {
+        value |= (bytes[offset + i] as u64) << (i * 8);
+    }
+    value
+}
+
+/// Parse the primary partitions of an MBR. Returns None if the sector has no 0x55AA signature.
+/// Empty and extended partitions are skipped
+pub fn parse_mbr(sector: &[u8]) -> Option<Vec<Partition>> {
+    if sector.len() < 512 || sector[510] != 0x55 || sector[511] != 0xAA {
+        return None;
+    }
+
+    let mut partitions = Vec::new();
+    for i in 0..4 {
+        let entry = &sector[MBR_ENTRIES + i * 16..MBR_ENTRIES + (i + 1) * 16];
+        let kind = entry[4];
+        let start = le(entry, 8, 4);
+        let sectors = le(entry, 12, 4);
+
+        if kind != 0 && sectors > 0 && start > 0 && kind != PROTECTIVE_TYPE &&
+           ! EXTENDED_TYPES.contains(&kind) {
+            partitions.push(Partition {
+                number: i + 1,
+                kind: PartitionKind::Mbr(kind),
+                start: start,
+                sectors: sectors,
+                name: String::new(),
+            });
+        }
+    }
+
+    Some(partitions)
+}
+
+/// The fields of a GPT header that locate the partition entries
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GptHeader {
+    pub entries_block: u64,
+    pub entries: usize,
+    pub entry_size: usize,
+    pub entries_crc: u32,
+}
+
+impl GptHeader {
+    /// Get the size of the partition entry array in bytes
+    pub fn entries_size(&self) -> usize {
+        self.entries * self.entry_size
+    }
+}
+
+/// Parse a GPT header. Returns None if the signature or the CRC does not match, or the entries
+/// are not of a usable size
+pub fn parse_gpt_header(sector: &[u8]) -> Option<GptHeader> {
+    if sector.len() < 512 || &sector[..8] != b"EFI PART" {
+        return None;
+    }
+
+    let size = le(sector, 12, 4) as usize;
+    if size < 92 || size > 512 {
+        return None;
+    }
+
+    let mut bytes = sector[..size].to_vec();
+    for b in bytes[16..20].iter_mut() {
+        *b = 0;
+    }
+    if crc32(&bytes) != le(sector, 16, 4) as u32 {
+        return None;
+    }
+
+    let header = GptHeader {
+        entries_block: le(sector, 72, 8),
+        entries: le(sector, 80, 4) as usize,
+        entry_size: le(sector, 84, 4) as usize,
+        entries_crc: le(sector, 88, 4) as u32,
+    };
+
+    if header.entry_size < 128 || header.entry_size % 8 != 0 || header.entries_size() > GPT_ENTRIES_MAX {
+        return None;
+    }
+
+    Some(header)
+}
+
+/// Parse the GPT partition entries described by a header. Returns None if the CRC does not
+/// match. Unused entries, which have a zero type GUID, are skipped
+pub fn parse_gpt_entries(header: &GptHeader, data: &[u8]) -> Option<Vec<Partition>> {
+    if data.len() < header.entries_size() || crc32(&data[..header.entries_size()]) != header.entries_crc {
+        return None;
+    }
+
+    let mut partitions = Vec::new();
+    for i in 0..header.entries {
+        let entry = &data[i * header.entry_size..(i + 1) * header.entry_size];
+
+        let mut kind = [0; 16];
+        for (k, e) in kind.iter_mut().zip(entry[..16].iter()) {
+            *k = *e;
+        }
+
+        let start = le(entry, 32, 8);
+        let end = le(entry, 40, 8);
+        if kind == [0; 16] || end < start {
+            continue;
+        }
+
+        let mut name = Vec::new();
+        for j in 0..36 {
+            match le(entry, 56 + j * 2, 2) as u16 {
+                0 => break,
+                c => name.push(c),
+            }
+        }
+
+        partitions.push(Partition {
+            number: i + 1,
+            kind: PartitionKind::Gpt(kind),
+            start: start,
+            sectors: end - start + 1,
+            name: String::from_utf16_lossy(&name),
+        });
+    }
+
+    Some(partitions)
+}
+
+/// Read whole sectors into a vector, returns EIO on a short read
+fn read_sectors(disk: &mut Box<Disk>, block: u64, sectors: usize) -> Result<Vec<u8>> {
+    let size = sectors * 512;
+    if let Some(data) = Memory::<u8>::new(size) {
+        let buffer = unsafe { slice::from_raw_parts_mut(data.ptr, size) };
+        if try!(disk.read(block, buffer)) < size {
+            return Err(Error::new(EIO));
+        }
+
+        Ok(buffer.to_vec())
+    } else {
+        Err(Error::new(ENOMEM))
+    }
+}
+
+/// Read the GPT header at a block and its partition entries, returns EINVAL if either is invalid
+fn read_gpt_at(disk: &mut Box<Disk>, block: u64) -> Result<Vec<Partition>> {
+    let sector = try!(read_sectors(disk, block, 1));
+    let header = try!(parse_gpt_header(&sector).ok_or(Error::new(EINVAL)));
+
+    let data = try!(read_sectors(disk, header.entries_block, (header.entries_size() + 511) / 512));
+    parse_gpt_entries(&header, &data).ok_or(Error::new(EINVAL))
+}
+
+/// Read the partitions from the GPT of a disk, using the backup at the last sector if the primary
+/// is damaged. Returns EINVAL if the disk has no valid GPT
+pub fn read_gpt(disk: &mut Box<Disk>) -> Result<Vec<Partition>> {
+    match read_gpt_at(disk, GPT_HEADER_BLOCK) {
+        Err(ref err) if err.errno == EINVAL => (),
+        result => return result,
+    }
+
+    let sectors = disk.size() / 512;
+    if sectors > GPT_HEADER_BLOCK + 1 {
+        let result = read_gpt_at(disk, sectors - 1);
+        if result.is_ok() {
+            debugln!("{}: Primary GPT invalid, using backup", disk.name());
+        }
+        result
+    } else {
+        Err(Error::new(EINVAL))
+    }
+}
+
+/// Read the primary partitions from the MBR in sector 0 of a disk. Returns EINVAL if the disk has
+/// no partition table
+pub fn read_mbr(disk: &mut Box<Disk>) -> Result<Vec<Partition>> {
+    let sector = try!(read_sectors(disk, 0, 1));
+    parse_mbr(&sector).ok_or(Error::new(EINVAL))
+}
+
+/// Read the partitions of a disk from its GPT, or from its MBR if it has no GPT. Returns EINVAL
+/// if the disk has neither
+pub fn read_partitions(disk: &mut Box<Disk>) -> Result<Vec<Partition>> {
+    match read_gpt(disk) {
+        Err(ref err) if err.errno == EINVAL => (),
+        result => return result,
+    }
+
+    read_mbr(disk)
+}
+
+/// A disk shared by the partitions on it. The file systems on different partitions may use it
+/// from different contexts, so every access is done holding the disk lock
+struct SharedDisk {
+    disk: UnsafeCell<Box<Disk>>,
+    /// Whether the disk is in use
+    busy: Intex<bool>,
+    condition: WaitCondition,
+}
+
+impl SharedDisk {
+    /// Lock the disk, waiting until the context using it is done
+    fn lock(&self) {
+        loop {
+            {
+                let mut busy = self.busy.lock();
+                if ! *busy {
+                    *busy = true;
+                    return;
+                }
+            }
+            unsafe { self.condition.wait(); }
+        }
+    }
+
+    /// Unlock the disk, waking up any waiters
+    fn unlock(&self) {
+        *self.busy.lock() = false;
+        unsafe { self.condition.notify(); }
+    }
+
+    /// Use the disk while holding the lock
+    fn with<T, F: FnOnce(&mut Box<Disk>) -> T>(&self, f: F) -> T {
+        self.lock();
+        let result = f(unsafe { &mut *self.disk.get() });
+        self.unlock();
+        result
+    }
+}
+
+/// A partition of a disk, with blocks relative to the start of the partition. All partitions of
+/// a disk share it
+pub struct PartitionDisk {
+    disk: Arc<SharedDisk>,
+    partition: Partition,
+}
+
+impl PartitionDisk {
+    /// Split a disk into the given partitions
+    pub fn split(disk: Box<Disk>, partitions: &[Partition]) -> Vec<PartitionDisk> {
+        let disk = Arc::new(SharedDisk {
+            disk: UnsafeCell::new(disk),
+            busy: Intex::new(false),
+            condition: WaitCondition::new(),
+        });
+        partitions.iter().map(|partition| PartitionDisk {
+            disk: disk.clone(),
+            partition: partition.clone(),
+        }).collect()
+    }
+
+    /// Get the partition
+    pub fn partition(&self) -> &Partition {
+        &self.partition
+    }
+
+    /// Get the number of bytes of a transfer at the given block that lie within the partition
+    fn bound(&self, block: u64, len: usize) -> usize {
+        if block >= self.partition.sectors {
+            0
+        } else {
+            cmp::min(len as u64, (self.partition.sectors - block) * 512) as usize
+        }
+    }
+}
+
+impl Disk for PartitionDisk {
+    fn name(&self) -> String {
+        let name = self.disk.with(|disk| disk.name());
+        if self.partition.name.is_empty() {
+            format!("{} partition {}", name, self.partition.number)
+        } else {
+            format!("{} partition {} ({})", name, self.partition.number, self.partition.name)
+        }
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let len = self.bound(block, buffer.len());
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let start = self.partition.start;
+        self.disk.with(|disk| disk.read(start + block, &mut buffer[..len]))
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let len = self.bound(block, buffer.len());
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let start = self.partition.start;
+        self.disk.with(|disk| disk.write(start + block, &buffer[..len]))
+    }
+
+    /// Zeros past the end of the partition are not written, and give EIO like a short write
+    fn write_zeros(&mut self, block: u64, count: u64) -> Result<()> {
+        if block >= self.partition.sectors || count > self.partition.sectors - block {
+            return Err(Error::new(EIO));
+        }
+
+        let start = self.partition.start;
+        self.disk.with(|disk| disk.write_zeros(start + block, count))
+    }
+
+    fn size(&self) -> u64 {
+        self.partition.sectors * 512
+    }
+
+    fn sector_size(&self) -> u64 {
+        self.disk.with(|disk| disk.sector_size())
+    }
+
+    fn max_transfer_sectors(&self) -> usize {
+        self.disk.with(|disk| disk.max_transfer_sectors())
+    }
+
+    fn is_rotational(&self) -> bool {
+        self.disk.with(|disk| disk.is_rotational())
+    }
+
+    fn status(&mut self) -> DiskStatus {
+        self.disk.with(|disk| disk.status())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.disk.with(|disk| disk.flush())
+    }
+
+    fn on_tick(&mut self) -> Result<()> {
+        self.disk.with(|disk| disk.on_tick())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use collections::string::ToString;
+
+    use disk::Disk;
+
+    use fs::redoxfs::crc32::crc32;
+    use fs::redoxfs::tests::MemDisk;
+
+    use super::{parse_mbr, read_gpt, read_partitions, Partition, PartitionDisk, PartitionKind};
+
+    fn put(bytes: &mut [u8], offset: usize, size: usize, value: u64) {
+        for i in 0..size {
+            bytes[offset + i] = (value >> (i * 8)) as u8;
+        }
+    }
+
+    fn entry(sector: &mut [u8], i: usize, kind: u8, start: u32, sectors: u32) {
+        let entry = &mut sector[446 + i * 16..446 + (i + 1) * 16];
+        entry[4] = kind;
+        put(entry, 8, 4, start as u64);
+        put(entry, 12, 4, sectors as u64);
+    }
+
+    /// Write a GPT header at `block` with entries at `entries_block`, and one partition entry
+    fn gpt(data: &mut [u8], block: usize, entries_block: usize) {
+        let entries = &mut data[entries_block * 512..(entries_block + 32) * 512];
+        for b in entries.iter_mut() {
+            *b = 0;
+        }
+        entries[0] = 0xAF;
+        put(entries, 32, 8, 34);
+        put(entries, 40, 8, 97);
+        for (i, c) in b"root".iter().enumerate() {
+            put(entries, 56 + i * 2, 2, *c as u64);
+        }
+        let entries_crc = crc32(entries) as u64;
+
+        let header = &mut data[block * 512..(block + 1) * 512];
+        for b in header.iter_mut() {
+            *b = 0;
+        }
+        header[..8].clone_from_slice(b"EFI PART");
+        put(header, 12, 4, 92);
+        put(header, 72, 8, entries_block as u64);
+        put(header, 80, 4, 128);
+        put(header, 84, 4, 128);
+        put(header, 88, 4, entries_crc);
+        let header_crc = crc32(&header[..92]) as u64;
+        put(header, 16, 4, header_crc);
+    }
+
+    fn root() -> Partition {
+        let mut kind = [0; 16];
+        kind[0] = 0xAF;
+        Partition {
+            number: 1,
+            kind: PartitionKind::Gpt(kind),
+            start: 34,
+            sectors: 64,
+            name: "root".to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_primary_partitions() {
+        let mut sector = [0; 512];
+        sector[510] = 0x55;
+        sector[511] = 0xAA;
+        entry(&mut sector, 0, 0x83, 2048, 0x10000);
+        entry(&mut sector, 1, 0x05, 0x12000, 0x1000);
+        entry(&mut sector, 3, 0x0C, 0x20000, 0x1000000);
+
+        assert_eq!(parse_mbr(&sector).unwrap(), vec![
+            Partition { number: 1, kind: PartitionKind::Mbr(0x83), start: 2048, sectors: 0x10000, name: "".to_string() },
+            Partition { number: 4, kind: PartitionKind::Mbr(0x0C), start: 0x20000, sectors: 0x1000000, name: "".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn parse_without_signature() {
+        let mut sector = [0; 512];
+        entry(&mut sector, 0, 0x83, 2048, 0x10000);
+        assert!(parse_mbr(&sector).is_none());
+    }
+
+    #[test]
+    fn read_gpt_primary() {
+        let mut data = vec![0; 128 * 512];
+        gpt(&mut data, 1, 2);
+
+        let mut disk: Box<Disk> = box MemDisk { data: data };
+        assert_eq!(read_partitions(&mut disk).unwrap(), vec![root()]);
+    }
+
+    #[test]
+    fn read_gpt_backup() {
+        let mut data = vec![0; 128 * 512];
+        gpt(&mut data, 1, 2);
+        gpt(&mut data, 127, 95);
+        data[512 + 40] ^= 1;
+
+        let mut disk: Box<Disk> = box MemDisk { data: data };
+        assert_eq!(read_gpt(&mut disk).unwrap(), vec![root()]);
+    }
+
+    #[test]
+    fn split_shares_disk() {
+        let mut first = root();
+        first.sectors = 4;
+        let mut second = root();
+        second.number = 2;
+        second.start = 38;
+
+        let disk: Box<Disk> = box MemDisk { data: vec![0; 128 * 512] };
+        let mut partitions = PartitionDisk::split(disk, &[first, second]);
+        assert_eq!(partitions[0].name(), "Memory partition 1 (root)");
+        assert_eq!(partitions[1].size(), 64 * 512);
+
+        // Writes are cut at the end of the partition
+        assert_eq!(partitions[0].write(3, &[1; 1024]).unwrap(), 512);
+        assert_eq!(partitions[0].write(4, &[1; 512]).unwrap(), 0);
+
+        // The second partition starts right after the first, on the same disk
+        assert_eq!(partitions[1].write(0, &[2; 512]).unwrap(), 512);
+        let mut data = vec![0; 1024];
+        assert_eq!(partitions[0].read(3, &mut data).unwrap(), 512);
+        assert!(data[..512].iter().all(|b| *b == 1));
+        assert_eq!(partitions[1].read(0, &mut data).unwrap(), 1024);
+        assert!(data[..512].iter().all(|b| *b == 2));
+        assert!(data[512..].iter().all(|b| *b == 0));
+    }
+}
@@ -0,0 +1,117 @@
+use alloc::boxed::Box;
+
+use collections::string::String;
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
+
+use disk::{Disk, DiskStatus};
+
+use system::error::Result;
+
+/// Direction of a disk access
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+/// A recorded disk access
+#[derive(Copy, Clone, Debug)]
+pub struct DiskAccess {
+    pub block: u64,
+    /// Number of blocks accessed
+    pub count: usize,
+    pub direction: Direction,
+}
+
+/// A disk wrapper that records every access into a bounded ring buffer
+pub struct TracingDisk {
+    disk: Box<Disk>,
+    accesses: VecDeque<DiskAccess>,
+    capacity: usize,
+    enabled: bool,
+}
+
+impl TracingDisk {
+    /// Wrap a disk, keeping the last `capacity` accesses
+    pub fn new(disk: Box<Disk>, capacity: usize) -> Self {
+        TracingDisk {
+            disk: disk,
+            accesses: VecDeque::with_capacity(capacity),
+            capacity: capacity,
+            enabled: true,
+        }
+    }
+
+    /// Enable or disable recording, accesses are passed through either way
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Get the recorded accesses, oldest first
+    pub fn trace(&self) -> Vec<DiskAccess> {
+        self.accesses.iter().cloned().collect()
+    }
+
+    /// Forget all recorded accesses
+    pub fn clear(&mut self) {
+        self.accesses.clear();
+    }
+
+    fn record(&mut self, block: u64, len: usize, direction: Direction) {
+        if self.enabled && self.capacity > 0 {
+            while self.accesses.len() >= self.capacity {
+                self.accesses.pop_front();
+            }
+            self.accesses.push_back(DiskAccess {
+                block: block,
+                count: (len + 511) / 512,
+                direction: direction,
+            });
+        }
+    }
+}
+
+impl Disk for TracingDisk {
+    fn name(&self) -> String {
+        self.disk.name()
+    }
+
+    fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        self.record(block, buffer.len(), Direction::Read);
+        self.disk.read(block, buffer)
+    }
+
+    fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        self.record(block, buffer.len(), Direction::Write);
+        self.disk.write(block, buffer)
+    }
+
+    fn size(&self) -> u64 {
+        self.disk.size()
+    }
+
+    fn sector_size(&self) -> u64 {
+        self.disk.sector_size()
+    }
+
+    fn max_transfer_sectors(&self) -> usize {
+        self.disk.max_transfer_sectors()
+    }
+
+    fn is_rotational(&self) -> bool {
+        self.disk.is_rotational()
+    }
+
+    fn status(&mut self) -> DiskStatus {
+        self.disk.status()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.disk.flush()
+    }
+
+    fn on_tick(&mut self) -> Result<()> {
+        self.disk.on_tick()
+    }
+}
@@ -14,7 +14,7 @@ use fs::{KScheme, Resource, Scheme, VecResource, Url};
 
 use sync::WaitQueue;
 
-use system::error::{Error, Result, ENOENT, EEXIST};
+use system::error::{Error, Result, ENOENT, EEXIST, EXDEV};
 use system::syscall::{O_CREAT, Stat};
 
 use self::console::Console;
@@ -163,4 +163,60 @@ impl Environment {
         }
         Err(Error::new(ENOENT))
     }
+
+    /// Rename a resource, both paths must be in the same scheme
+    /// Open a file by its node number, in the scheme of a URL
+    pub fn open_node(&self, url: Url, node: u64, flags: usize) -> Result<Box<Resource>> {
+        let url_scheme = url.scheme();
+        if !url_scheme.is_empty() {
+            for mut scheme in self.schemes.lock().iter_mut() {
+                if scheme.scheme() == url_scheme {
+                    return scheme.open_node(node, flags);
+                }
+            }
+        }
+        Err(Error::new(ENOENT))
+    }
+
+    pub fn rename(&self, from: Url, to: Url) -> Result<()> {
+        let url_scheme = from.scheme();
+        if url_scheme != to.scheme() {
+            return Err(Error::new(EXDEV));
+        }
+
+        if !url_scheme.is_empty() {
+            for mut scheme in self.schemes.lock().iter_mut() {
+                if scheme.scheme() == url_scheme {
+                    return scheme.rename(from, to);
+                }
+            }
+        }
+        Err(Error::new(ENOENT))
+    }
+
+    /// Get an extended attribute of a path, returns the size of the value
+    pub fn getxattr(&self, url: Url, key: &str, buf: &mut [u8]) -> Result<usize> {
+        let url_scheme = url.scheme();
+        if !url_scheme.is_empty() {
+            for mut scheme in self.schemes.lock().iter_mut() {
+                if scheme.scheme() == url_scheme {
+                    return scheme.getxattr(url, key, buf);
+                }
+            }
+        }
+        Err(Error::new(ENOENT))
+    }
+
+    /// Set an extended attribute of a path
+    pub fn setxattr(&self, url: Url, key: &str, value: &[u8]) -> Result<()> {
+        let url_scheme = url.scheme();
+        if !url_scheme.is_empty() {
+            for mut scheme in self.schemes.lock().iter_mut() {
+                if scheme.scheme() == url_scheme {
+                    return scheme.setxattr(url, key, value);
+                }
+            }
+        }
+        Err(Error::new(ENOENT))
+    }
 }
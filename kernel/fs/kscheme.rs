@@ -2,7 +2,7 @@ use super::{Resource, Url};
 
 use alloc::boxed::Box;
 
-use system::error::{Error, Result, ENOENT};
+use system::error::{Error, Result, ENOENT, ENOSYS};
 use system::syscall::Stat;
 
 #[allow(unused_variables)]
@@ -34,4 +34,22 @@ pub trait KScheme {
     fn unlink(&mut self, path: Url) -> Result<()> {
         Err(Error::new(ENOENT))
     }
+
+    /// Open a file by its node number, as given by `Stat` or `FileSystem::node_block`
+    fn open_node(&mut self, node: u64, flags: usize) -> Result<Box<Resource>> {
+        Err(Error::new(ENOSYS))
+    }
+
+    /// Rename a path within this scheme, replacing the destination if it exists
+    fn rename(&mut self, from: Url, to: Url) -> Result<()> {
+        Err(Error::new(ENOSYS))
+    }
+
+    fn getxattr(&mut self, path: Url, key: &str, buf: &mut [u8]) -> Result<usize> {
+        Err(Error::new(ENOENT))
+    }
+
+    fn setxattr(&mut self, path: Url, key: &str, value: &[u8]) -> Result<()> {
+        Err(Error::new(ENOENT))
+    }
 }
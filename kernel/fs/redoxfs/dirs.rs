@@ -0,0 +1,72 @@
+use collections::BTreeMap;
+use collections::string::{String, ToString};
+
+use common::slice::GetSlice;
+
+use super::node::Node;
+
+/// The number of nodes below each directory, indexed by the path of the directory
+///
+/// Directories are not nodes, they exist while a node name has them as a prefix, so finding if
+/// one is empty would otherwise scan every node. The counts follow the names of the nodes in the
+/// node list of the file system, and are rebuilt whenever that list is read from disk.
+pub struct DirCounts {
+    counts: BTreeMap<String, usize>,
+}
+
+impl DirCounts {
+    pub fn new() -> DirCounts {
+        DirCounts {
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Count the directories of a list of nodes
+    pub fn from_nodes(nodes: &[Node]) -> DirCounts {
+        let mut dirs = DirCounts::new();
+        for node in nodes.iter() {
+            dirs.add(&node.name);
+        }
+        dirs
+    }
+
+    /// Count a node name in each directory it is below
+    pub fn add(&mut self, name: &str) {
+        for (index, _) in name.match_indices('/') {
+            *self.counts.entry(name.get_slice(..index).to_string()).or_insert(0) += 1;
+        }
+    }
+
+    /// Stop counting a node name in each directory it is below. A directory that was not
+    /// counting the name is left alone, so removing a name twice can not make a count wrap
+    pub fn remove(&mut self, name: &str) {
+        for (index, _) in name.match_indices('/') {
+            let dir = name.get_slice(..index);
+            let empty = match self.counts.get_mut(dir) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                },
+                None => {
+                    debugln!("Directory {} counted below zero", dir);
+                    false
+                },
+            };
+
+            if empty {
+                self.counts.remove(dir);
+            }
+        }
+    }
+
+    /// Get the number of nodes below a directory, at any depth
+    pub fn count(&self, directory: &str) -> usize {
+        self.counts.get(directory.trim_right_matches('/')).cloned().unwrap_or(0)
+    }
+
+    /// Check if a directory has no nodes below it, which is also the case for paths that are not
+    /// directories
+    pub fn is_empty(&self, directory: &str) -> bool {
+        self.count(directory) == 0
+    }
+}
@@ -0,0 +1,30 @@
+use disk::ide::Extent;
+
+/// A structure that is stored on disk with every multi-byte field little-endian, so that images
+/// can be made and read on hosts of either byte order
+pub trait LittleEndian: Sized {
+    /// Convert from the byte order on disk to the byte order of the host. On a little-endian host
+    /// this does nothing
+    fn from_le(self) -> Self;
+
+    /// Convert from the byte order of the host to the byte order on disk. Swapping bytes is its
+    /// own inverse, so this is the same conversion as `from_le`
+    fn to_le(self) -> Self {
+        self.from_le()
+    }
+}
+
+impl LittleEndian for [u8; 512] {
+    fn from_le(self) -> Self {
+        self
+    }
+}
+
+impl LittleEndian for Extent {
+    fn from_le(self) -> Self {
+        Extent {
+            block: u64::from_le(self.block),
+            length: u64::from_le(self.length),
+        }
+    }
+}
@@ -1,17 +1,323 @@
+use collections::string::String;
+use collections::vec::Vec;
+
+use core::{mem, ptr, slice};
+
 use disk::ide::Extent;
 
+use super::crc32::crc32;
+use super::endian::LittleEndian;
+
+/// The block of the primary header
+pub const HEADER_BLOCK: u64 = 1;
+/// The block of the backup header, always written before the primary
+pub const HEADER_BACKUP_BLOCK: u64 = 2;
+
+/// The number of bad blocks the header can record
+pub const BAD_BLOCKS: usize = 12;
+
+/// The mount state was not recorded, as by images made before it was
+pub const STATE_UNKNOWN: u32 = 0;
+/// The file system is mounted, or was not unmounted cleanly
+pub const STATE_MOUNTED: u32 = 1;
+/// The file system was unmounted cleanly
+pub const STATE_CLEAN: u32 = 2;
+
 /// The header of the fs
 #[repr(packed)]
 pub struct Header {
     pub signature: [u8; 8],
     pub version: u64,
     pub free_space: Extent,
-    pub padding: [u8; 224],
+    pub journal: Extent,
+    /// The label of the fs, padded with zeros
+    pub label: [u8; 64],
+    /// CRC-32 of the header with this field set to zero, zero if not checksummed
+    pub checksum: u32,
+    /// When the fs was made, in seconds since the epoch, zero if not known
+    pub created: u64,
+    /// When the fs was last mounted, in seconds since the epoch, zero if not known
+    pub mounted: u64,
+    /// The number of times the fs has been mounted
+    pub mount_count: u64,
+    /// One of `STATE_UNKNOWN`, `STATE_MOUNTED`, or `STATE_CLEAN`
+    pub state: u32,
+    /// Blocks that failed to read, which are never allocated, zero for an unused slot
+    pub bad_blocks: [u64; BAD_BLOCKS],
+    pub padding: [u8; 16],
     pub extents: [Extent; 16],
 }
 
 impl Header {
+    /// Compute the checksum of the header, over its bytes as they are on disk
+    pub fn checksum(&self) -> u32 {
+        let header = unsafe { ptr::read(self) }.to_le();
+        let mut bytes = [0; 512];
+        bytes.clone_from_slice(unsafe {
+            slice::from_raw_parts(&header as *const Header as *const u8, mem::size_of::<Header>())
+        });
+        for b in bytes[112..116].iter_mut() {
+            *b = 0;
+        }
+        crc32(&bytes)
+    }
+
+    /// Check the signature and version, and the checksum if there is one
     pub fn valid(&self) -> bool {
-        &self.signature == b"REDOXFS\0" && self.version == 1
+        &self.signature == b"REDOXFS\0" && self.version == 1 &&
+        (self.checksum == 0 || self.checksum == self.checksum())
+    }
+
+    /// Get the label of the fs
+    pub fn label(&self) -> String {
+        let len = self.label.iter().position(|b| *b == 0).unwrap_or(self.label.len());
+        String::from_utf8_lossy(&self.label[..len]).into_owned()
+    }
+
+    /// Set the label of the fs, truncating it to 64 bytes
+    pub fn set_label(&mut self, label: &str) {
+        self.label = [0; 64];
+        for (l, b) in self.label.iter_mut().zip(label.bytes()) {
+            *l = b;
+        }
+    }
+
+    /// Convert the block numbers of the header from blocks of `per_block` sectors, as they are on
+    /// disk, to sectors, as they are kept in memory
+    pub fn blocks_to_sectors(&mut self, per_block: u64) {
+        self.free_space.block *= per_block;
+        self.journal.block *= per_block;
+        for extent in self.extents.iter_mut() {
+            extent.block *= per_block;
+        }
+        for block in self.bad_blocks.iter_mut() {
+            *block *= per_block;
+        }
+    }
+
+    /// Convert the block numbers of the header from sectors back to blocks of `per_block` sectors
+    pub fn sectors_to_blocks(&mut self, per_block: u64) {
+        self.free_space.block /= per_block;
+        self.journal.block /= per_block;
+        for extent in self.extents.iter_mut() {
+            extent.block /= per_block;
+        }
+        for block in self.bad_blocks.iter_mut() {
+            *block /= per_block;
+        }
+    }
+
+    /// Check if a block is on the bad block list
+    pub fn is_bad_block(&self, block: u64) -> bool {
+        block > 0 && self.bad_blocks.iter().any(|bad| *bad == block)
+    }
+
+    /// Check if a sector is in a block of `per_block` sectors that is on the bad block list,
+    /// wherever in the block the bad sector was found
+    pub fn is_bad_sector(&self, sector: u64, per_block: u64) -> bool {
+        self.bad_blocks.iter().any(|bad| *bad > 0 && *bad / per_block == sector / per_block)
+    }
+
+    /// Add a block to the bad block list. Returns false if the list is full
+    pub fn add_bad_block(&mut self, block: u64) -> bool {
+        if self.is_bad_block(block) {
+            return true;
+        }
+
+        match self.bad_blocks.iter().position(|bad| *bad == 0) {
+            Some(i) => {
+                self.bad_blocks[i] = block;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Find the first run of `sectors` sectors in the free space that starts on a block of
+    /// `per_block` sectors and has no sector in a bad block
+    pub fn find_free(&self, sectors: u64, per_block: u64) -> Option<u64> {
+        let end = self.free_space.block + self.free_space.length / 512;
+        let mut block = (self.free_space.block + per_block - 1) / per_block * per_block;
+        while block + sectors <= end {
+            match (block..block + sectors).rev().find(|sector| self.is_bad_sector(*sector, per_block)) {
+                Some(bad) => block = (bad / per_block + 1) * per_block,
+                None => return Some(block),
+            }
+        }
+
+        None
+    }
+
+    /// Allocate the run of `sectors` blocks at `block`, as found by `find_free`. The free space
+    /// is a single extent, so any blocks skipped before it are no longer free
+    pub fn take_free(&mut self, block: u64, sectors: u64) {
+        let end = block + sectors;
+        self.free_space.length -= (end - self.free_space.block) * 512;
+        self.free_space.block = end;
+    }
+
+    /// Get the indexes of node table extents that are out of range, because they cover the
+    /// headers, the journal, or the free space, or end past the last block, or that overlap an
+    /// earlier extent
+    pub fn bad_extents(&self) -> Vec<usize> {
+        let mut bad = Vec::new();
+        for (i, extent) in self.extents.iter().enumerate() {
+            if extent.empty() {
+                continue;
+            }
+
+            if extent.block <= HEADER_BACKUP_BLOCK || extent.block.checked_add(extent.sectors()).is_none()
+               || extent.overlaps(&self.journal) || extent.overlaps(&self.free_space)
+               || (0..i).any(|j| ! bad.contains(&j) && extent.overlaps(&self.extents[j])) {
+                bad.push(i);
+            }
+        }
+
+        bad
+    }
+}
+
+impl LittleEndian for Header {
+    fn from_le(mut self) -> Self {
+        self.version = u64::from_le(self.version);
+        self.free_space = self.free_space.from_le();
+        self.journal = self.journal.from_le();
+        self.checksum = u32::from_le(self.checksum);
+        self.created = u64::from_le(self.created);
+        self.mounted = u64::from_le(self.mounted);
+        self.mount_count = u64::from_le(self.mount_count);
+        self.state = u32::from_le(self.state);
+        for block in self.bad_blocks.iter_mut() {
+            *block = u64::from_le(*block);
+        }
+        for extent in self.extents.iter_mut() {
+            *extent = extent.from_le();
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{ptr, slice};
+
+    use disk::ide::Extent;
+
+    use super::{Header, BAD_BLOCKS, STATE_CLEAN};
+    use super::super::endian::LittleEndian;
+
+    fn header(extents: &[(u64, u64)]) -> Header {
+        let mut header = Header {
+            signature: *b"REDOXFS\0",
+            version: 1,
+            free_space: Extent { block: 1024, length: 512 * 1024 },
+            journal: Extent { block: 3, length: 512 * 16 },
+            label: [0; 64],
+            checksum: 0,
+            created: 0,
+            mounted: 0,
+            mount_count: 0,
+            state: 0,
+            bad_blocks: [0; BAD_BLOCKS],
+            padding: [0; 16],
+            extents: [Extent { block: 0, length: 0 }; 16],
+        };
+        for (i, &(block, length)) in extents.iter().enumerate() {
+            header.extents[i] = Extent { block: block, length: length };
+        }
+        header
+    }
+
+    #[test]
+    fn valid_extents() {
+        assert!(header(&[(19, 512 * 4), (23, 512 * 4)]).bad_extents().is_empty());
+    }
+
+    #[test]
+    fn overlapping_extents() {
+        assert_eq!(header(&[(19, 512 * 4), (22, 512 * 4), (30, 512)]).bad_extents(), vec![1]);
+    }
+
+    #[test]
+    fn out_of_range_extents() {
+        assert_eq!(header(&[(2, 512), (10, 512), (1000, 512 * 32), (!0, 512)]).bad_extents(),
+                   vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn checksum() {
+        let mut header = header(&[(19, 512 * 4)]);
+        header.set_label("root");
+        assert!(header.valid());
+
+        header.checksum = header.checksum();
+        assert!(header.valid());
+
+        header.free_space.block += 1;
+        assert!(! header.valid());
+    }
+
+    #[test]
+    fn find_free_skips_bad_blocks() {
+        let mut header = header(&[]);
+        assert_eq!(header.find_free(4, 1), Some(1024));
+
+        assert!(header.add_bad_block(1026));
+        assert!(header.add_bad_block(1031));
+        assert_eq!(header.find_free(4, 1), Some(1027));
+        assert_eq!(header.find_free(8, 1), Some(1032));
+        assert_eq!(header.find_free(2, 1), Some(1024));
+        assert_eq!(header.find_free(1024, 1), None);
+
+        // With blocks of 4 sectors, a bad sector anywhere in a block skips the whole block
+        assert_eq!(header.find_free(4, 4), Some(1032));
+        assert_eq!(header.find_free(1, 4), Some(1032));
+        assert!(header.is_bad_sector(1024, 4));
+        assert!(! header.is_bad_sector(1032, 4));
+
+        header.take_free(1027, 4);
+        assert_eq!(header.free_space.block, 1031);
+        assert_eq!(header.free_space.length, 512 * 1017);
+    }
+
+    #[test]
+    fn bad_block_list_full() {
+        let mut header = header(&[]);
+        for i in 0..BAD_BLOCKS as u64 {
+            assert!(header.add_bad_block(2000 + i));
+        }
+        assert!(header.add_bad_block(2000));
+        assert!(! header.add_bad_block(3000));
+        assert!(! header.is_bad_block(3000));
+    }
+
+    #[test]
+    fn little_endian() {
+        let mut bytes = [0u8; 512];
+        bytes[..8].clone_from_slice(b"REDOXFS\0");
+        bytes[8] = 1;
+        // Free space at block 0x0102, 0x030405 bytes long
+        bytes[16] = 0x02;
+        bytes[17] = 0x01;
+        bytes[24] = 0x05;
+        bytes[25] = 0x04;
+        bytes[26] = 0x03;
+        bytes[140] = STATE_CLEAN as u8;
+        // A node table extent at block 19, 0x0800 bytes long
+        bytes[256] = 19;
+        bytes[265] = 0x08;
+
+        let header = unsafe { ptr::read(bytes.as_ptr() as *const Header) }.from_le();
+        assert!(header.valid());
+        assert_eq!(header.version, 1);
+        assert_eq!(header.free_space.block, 0x0102);
+        assert_eq!(header.free_space.length, 0x030405);
+        assert_eq!(header.state, STATE_CLEAN);
+        assert_eq!(header.extents[0].block, 19);
+        assert_eq!(header.extents[0].length, 0x0800);
+
+        let header = header.to_le();
+        let written = unsafe { slice::from_raw_parts(&header as *const Header as *const u8, 512) };
+        assert_eq!(written, &bytes[..]);
     }
 }
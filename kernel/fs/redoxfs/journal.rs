@@ -0,0 +1,44 @@
+use super::endian::LittleEndian;
+
+/// The journal is clean, there is nothing to roll back
+pub const JOURNAL_CLEAN: u64 = 0;
+/// The journal holds the original data of blocks modified by an uncommitted transaction
+pub const JOURNAL_ACTIVE: u64 = 1;
+
+/// The maximum number of blocks a single transaction can modify
+pub const JOURNAL_RECORDS: usize = 62;
+
+/// The first block of the journal extent
+///
+/// The original data of `blocks[i]` is stored in the block at `journal.block + 1 + i`
+#[repr(packed)]
+pub struct JournalHeader {
+    pub state: u64,
+    pub count: u64,
+    pub blocks: [u64; JOURNAL_RECORDS],
+}
+
+impl JournalHeader {
+    pub fn new() -> Self {
+        JournalHeader {
+            state: JOURNAL_CLEAN,
+            count: 0,
+            blocks: [0; JOURNAL_RECORDS],
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.state == JOURNAL_ACTIVE
+    }
+}
+
+impl LittleEndian for JournalHeader {
+    fn from_le(mut self) -> Self {
+        self.state = u64::from_le(self.state);
+        self.count = u64::from_le(self.count);
+        for block in self.blocks.iter_mut() {
+            *block = u64::from_le(*block);
+        }
+        self
+    }
+}
@@ -0,0 +1,116 @@
+use collections::BTreeSet;
+
+use sync::{Intex, WaitCondition};
+
+/// A table of locked nodes, indexed by the block of the node
+///
+/// Operations that modify a node (create, write, truncate, remove) must hold the lock for that
+/// node. Operations on different nodes do not block each other.
+///
+/// Lock ordering: when an operation touches both a directory and a child, the directory must be
+/// locked first. Unrelated nodes must be locked in ascending block order. A lock must never be
+/// acquired while holding a lock on a node that comes after it in this order.
+///
+/// The table is currently advisory. Every caller holds `&mut FileSystem`, which already serializes
+/// them, so no lock is ever contended. It is kept so that the lock order is followed when the
+/// file system is shared between contexts.
+pub struct NodeLocks {
+    locked: Intex<BTreeSet<u64>>,
+    condition: WaitCondition,
+}
+
+impl NodeLocks {
+    pub fn new() -> NodeLocks {
+        NodeLocks {
+            locked: Intex::new(BTreeSet::new()),
+            condition: WaitCondition::new(),
+        }
+    }
+
+    /// Try to lock the node at `block`, returning false if it is already locked
+    pub fn try_lock(&self, block: u64) -> bool {
+        self.locked.lock().insert(block)
+    }
+
+    /// Lock the node at `block`, waiting until it is unlocked by its current holder
+    pub fn lock(&self, block: u64) {
+        while ! self.try_lock(block) {
+            unsafe { self.condition.wait(); }
+        }
+    }
+
+    /// Unlock the node at `block`, waking up any waiters
+    pub fn unlock(&self, block: u64) {
+        if self.locked.lock().remove(&block) {
+            unsafe { self.condition.notify(); }
+        }
+    }
+
+    /// Check if the node at `block` is locked
+    pub fn is_locked(&self, block: u64) -> bool {
+        self.locked.lock().contains(&block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use collections::vec::Vec;
+
+    use super::NodeLocks;
+
+    #[test]
+    fn different_blocks() {
+        let locks = NodeLocks::new();
+        assert!(locks.try_lock(3));
+        assert!(locks.try_lock(4));
+        assert!(locks.is_locked(3));
+        assert!(locks.is_locked(4));
+    }
+
+    #[test]
+    fn same_block() {
+        let locks = NodeLocks::new();
+        assert!(locks.try_lock(3));
+        assert!(! locks.try_lock(3));
+
+        locks.unlock(3);
+        assert!(! locks.is_locked(3));
+        assert!(locks.try_lock(3));
+    }
+
+    /// Two writers, each locking a directory and then a child, stepped in turn as contexts would
+    /// be scheduled. A writer that gets both locks writes on its next step and then unlocks
+    #[test]
+    fn two_writers() {
+        let locks = NodeLocks::new();
+        let writers = [[3, 5], [4, 5]];
+        let mut held = [0, 0];
+        let mut done = [false, false];
+        let mut waits = 0;
+        let mut order = Vec::new();
+
+        while done.iter().any(|done| ! *done) {
+            for (i, writer) in writers.iter().enumerate() {
+                if done[i] {
+                    continue;
+                }
+
+                if held[i] == 2 {
+                    order.push(i);
+                    locks.unlock(writer[1]);
+                    locks.unlock(writer[0]);
+                    done[i] = true;
+                } else if locks.try_lock(writer[held[i]]) {
+                    held[i] += 1;
+                } else {
+                    waits += 1;
+                }
+            }
+        }
+
+        // The directories do not block each other, the shared child is written by one at a time
+        assert_eq!(waits, 1);
+        assert_eq!(order, vec![0, 1]);
+        assert!(! locks.is_locked(3) && ! locks.is_locked(4) && ! locks.is_locked(5));
+    }
+}
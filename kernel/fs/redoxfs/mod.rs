@@ -1,105 +1,3468 @@
 use alloc::boxed::Box;
 
-use collections::borrow::ToOwned;
 use collections::string::{String, ToString};
 use collections::vec::Vec;
 
 use common::slice::GetSlice;
-use arch::memory::Memory;
+use common::time::Duration;
 
-use core::{cmp, ptr, slice};
+use core::{cmp, mem, ptr, slice};
 
 use disk::Disk;
+use disk::ide::Extent;
 
-use system::error::{Error, Result, ENOMEM, EINVAL};
+use system::error::{Error, Result, E2BIG, EBUSY, EEXIST, EIO, EISDIR, ENAMETOOLONG, ENODATA, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, EINVAL, EROFS};
 
-pub use self::header::Header;
-pub use self::node::{Node, NodeData};
+pub use self::dirs::DirCounts;
+pub use self::endian::LittleEndian;
+pub use self::header::{Header, BAD_BLOCKS, HEADER_BLOCK, HEADER_BACKUP_BLOCK, STATE_CLEAN, STATE_MOUNTED, STATE_UNKNOWN};
+pub use self::journal::{JournalHeader, JOURNAL_ACTIVE, JOURNAL_CLEAN, JOURNAL_RECORDS};
+pub use self::lock::NodeLocks;
+pub use self::node::{Node, NodeData, NAME_INLINE, NAME_LONG_INLINE, NAME_MAX, NODE_COMPRESSED};
+pub use self::pool::{BufferPool, PoolBuffer, POOL_BUFFERS, POOL_BUFFER_MAX};
+pub use self::snapshot::Snapshot;
+pub use self::stats::{FsStats, FSCK_MOUNT_COUNT};
+pub use self::xattr::{Xattr, XATTR_SIZE};
 
+pub mod crc32;
+pub mod dirs;
+pub mod endian;
 pub mod header;
+pub mod journal;
+pub mod lock;
 pub mod node;
+pub mod pool;
+pub mod rle;
+pub mod snapshot;
+pub mod stats;
+pub mod xattr;
+
+/// The largest buffer used to write many blocks at once
+const WRITE_CHUNK: usize = 65536;
+/// The number of sectors read at once by `scan_bad_blocks`
+const SCAN_CHUNK: u64 = 128;
+/// The number of sectors read and rewritten by each call to `scrub_step`
+const SCRUB_CHUNK: u64 = 128;
+
+/// The first block of the journal made by `mkfs`
+const MKFS_JOURNAL_BLOCK: u64 = 3;
+/// The number of disk sectors per node table slot made by `mkfs`
+const MKFS_SECTORS_PER_NODE: u64 = 64;
+/// The smallest node table made by `mkfs`, in nodes
+const MKFS_NODES_MIN: u64 = 16;
+/// The largest node table made by `mkfs`, in nodes
+const MKFS_NODES_MAX: u64 = 4096;
+
+/// Format a disk with an empty file system, without mounting it. Block 0 is left for a boot
+/// sector, followed by the headers, the journal, and a node table sized to the disk. The rest of
+/// the disk is free space. Returns EINVAL if the size of the disk is not known and ENOSPC if it
+/// is too small. The creation time is the current time
+pub fn mkfs(disk: &mut Box<Disk>, label: &str) -> Result<()> {
+    mkfs_at(disk, label, Duration::realtime().secs as u64)
+}
+
+/// Format a disk like `mkfs`, recording `created`, in seconds since the epoch, as the creation
+/// time. Tools that build images where the kernel clock is not running, or that need the same
+/// image from the same input, give the time themselves
+pub fn mkfs_at(disk: &mut Box<Disk>, label: &str, created: u64) -> Result<()> {
+    let sectors = disk.size() / 512;
+    if sectors == 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let journal = Extent {
+        block: MKFS_JOURNAL_BLOCK,
+        length: (JOURNAL_RECORDS as u64 + 1) * 512,
+    };
+    let nodes = cmp::max(MKFS_NODES_MIN, cmp::min(MKFS_NODES_MAX, sectors / MKFS_SECTORS_PER_NODE));
+    let table = Extent {
+        block: journal.block + journal.sectors(),
+        length: nodes * 512,
+    };
+    let free = table.block + nodes;
+    if free >= sectors {
+        return Err(Error::new(ENOSPC));
+    }
+
+    let mut pool = BufferPool::new(1);
+    try!(FileSystem::write_block(disk, &mut pool, journal.block, JournalHeader::new()));
+    try!(disk.write_zeros(table.block, nodes));
+    try!(disk.flush());
+
+    let mut header = Header {
+        signature: *b"REDOXFS\0",
+        version: 1,
+        free_space: Extent {
+            block: free,
+            length: (sectors - free) * 512,
+        },
+        journal: journal,
+        label: [0; 64],
+        checksum: 0,
+        created: created,
+        mounted: 0,
+        mount_count: 0,
+        state: STATE_CLEAN,
+        bad_blocks: [0; BAD_BLOCKS],
+        padding: [0; 16],
+        extents: [Extent {
+            block: 0,
+            length: 0,
+        }; 16],
+    };
+    header.extents[0] = table;
+    header.set_label(label);
+    header.checksum = header.checksum();
+
+    // As in write_header, the backup is written first
+    let backup = unsafe { ptr::read(&header) };
+    try!(FileSystem::write_block(disk, &mut pool, HEADER_BACKUP_BLOCK, backup));
+    try!(disk.flush());
+
+    try!(FileSystem::write_block(disk, &mut pool, HEADER_BLOCK, header));
+    disk.flush()
+}
+
+/// Get the path of a node name below a directory, or None if it is not inside the directory. The
+/// name is matched whole path components at a time, so `foobar/a` is not inside `foo`. Trailing
+/// slashes on the directory are ignored, and an empty directory is the root
+fn child_path<'a>(name: &'a str, directory: &str) -> Option<&'a str> {
+    let directory = directory.trim_right_matches('/');
+    if directory.is_empty() {
+        Some(name)
+    } else if name.starts_with(directory) && name.get_slice(directory.len()..).starts_with('/') {
+        Some(name.get_slice(directory.len() + 1..))
+    } else {
+        None
+    }
+}
+
+/// Check if two copies of a node use the same blocks
+fn same_blocks(a: &Node, b: &Node) -> bool {
+    a.block == b.block && a.extents.iter().zip(b.extents.iter()).all(|(a, b)| {
+        a.block == b.block && a.length == b.length
+    })
+}
 
 /// A file system
 pub struct FileSystem {
     pub disk: Box<Disk>,
     pub header: Header,
+    /// The nodes of the node table. Nodes are added and removed with `add_node` and
+    /// `remove_node`, which keep `dirs` up to date
     pub nodes: Vec<Node>,
+    /// The number of nodes below each directory
+    pub dirs: DirCounts,
+    pub locks: NodeLocks,
+    /// Blocks logged to the journal by the current transaction
+    pub txn: Option<Vec<u64>>,
+    /// Snapshots that pin the blocks of the data they reference
+    pub snapshots: Vec<Snapshot>,
+    /// The id of the next snapshot
+    pub next_snapshot: usize,
+    /// Free extents before the free space, from blocks released by dropped snapshots or found by
+    /// `collect_orphans`. They are used by allocations before the free space, and are only kept
+    /// in memory, so after a remount they are orphans again until a checked mount collects them
+    pub released: Vec<Extent>,
+    /// The mount state of the header before it was mounted
+    pub state_at_mount: u32,
+    /// Whether the file system was not unmounted cleanly and has not been checked since
+    pub needs_check: bool,
+    /// Nodes of open temporary files, which have no name and are never written to the node table
+    pub unnamed: Vec<Node>,
+    /// The node of each open file, as its handle last saw it. Their blocks count as used, even
+    /// once the file is unlinked or replaced, so they are not reclaimed while the handle can
+    /// still write to them
+    pub open: Vec<Node>,
+    /// Scratch buffers for disk transfers
+    pub pool: BufferPool,
+    /// Mounted read only, because the disk is write protected. The header is not updated at
+    /// mount
+    pub read_only: bool,
+    /// The size of a block in bytes, 512 unless overridden at mount. Block numbers are converted
+    /// to 512 byte sectors as the header and nodes are read, so everything in memory, including
+    /// the journal, is addressed in sectors
+    pub block_size: u64,
 }
 
 impl FileSystem {
-    /// Create a file system from a disk
-    pub fn from_disk(mut disk: Box<Disk>) -> Result<Self> {
-        if let Some(data) = Memory::<u8>::new(512) {
-            try!(disk.read(1, unsafe { slice::from_raw_parts_mut(data.ptr, 512) }));
-
-            let header = unsafe { ptr::read(data.ptr as *const Header) };
-            if header.valid() {
-                debugln!("{}: Redox Filesystem", disk.name());
-
-                let mut nodes = Vec::new();
-                for extent in &header.extents {
-                    if extent.block > 0 && extent.length > 0 {
-                        let current_sectors = (extent.length as usize + 511) / 512;
-                        let max_size = current_sectors * 512;
-
-                        let size = cmp::min(extent.length as usize, max_size);
-
-                        if let Some(data) = Memory::<u8>::new(max_size) {
-                            let mut buffer = unsafe {
-                                slice::from_raw_parts_mut(data.ptr, max_size)
-                            };
-                            try!(disk.read(extent.block, &mut buffer));
-
-                            for i in 0..size / 512 {
-                                nodes.push(Node::new(extent.block + i as u64, unsafe {
-                                    &*(data.ptr.offset(i as isize * 512) as *const NodeData)
-                                }));
+    /// Get the number of sectors in a block
+    fn per_block(&self) -> u64 {
+        self.block_size / 512
+    }
+
+    /// Round a number of sectors up to whole blocks
+    fn round_sectors(&self, sectors: u64) -> u64 {
+        let per_block = self.per_block();
+        (sectors + per_block - 1) / per_block * per_block
+    }
+
+    /// Read a block-sized structure from the given block, converting it from the byte order on
+    /// disk. Returns EIO on a short read
+    fn read_block<T: LittleEndian>(disk: &mut Box<Disk>, pool: &mut BufferPool, block: u64) -> Result<T> {
+        let mut buffer = try!(pool.take(512));
+        // A short read would leave the rest of the structure uninitialized
+        if try!(disk.read(block, buffer.get(512))) < 512 {
+            return Err(Error::new(EIO));
+        }
+
+        let value = unsafe { ptr::read(buffer.ptr() as *const T) };
+        pool.give(buffer);
+        Ok(value.from_le())
+    }
+
+    /// Write a block-sized structure to the given block, in the byte order on disk
+    fn write_block<T: LittleEndian>(disk: &mut Box<Disk>, pool: &mut BufferPool, block: u64, value: T) -> Result<usize> {
+        let size = mem::size_of::<T>();
+        let mut buffer = try!(pool.take(size));
+        unsafe { ptr::write(buffer.ptr() as *mut T, value.to_le()) };
+
+        let result = disk.write(block, buffer.get(size));
+        pool.give(buffer);
+        result
+    }
+
+    /// Read the header at a header block, with its block numbers converted from blocks of
+    /// `per_block` sectors to sectors. Returns None if the header is not valid
+    fn read_header(disk: &mut Box<Disk>, pool: &mut BufferPool, block: u64, per_block: u64) -> Result<Option<Header>> {
+        let mut header: Header = try!(FileSystem::read_block(disk, pool, block * per_block));
+        if header.valid() {
+            header.blocks_to_sectors(per_block);
+            Ok(Some(header))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Read the nodes listed by the extents of the header, skipping bad extents and unused slots.
+    /// Each block of `per_block` sectors holds one node
+    fn read_nodes(disk: &mut Box<Disk>, pool: &mut BufferPool, header: &Header, per_block: u64) -> Result<Vec<Node>> {
+        let bad = header.bad_extents();
+
+        let mut nodes = Vec::new();
+        for (i, extent) in header.extents.iter().enumerate() {
+            if extent.block > 0 && extent.length > 0 && ! bad.contains(&i) {
+                let current_sectors = (extent.length as usize + 511) / 512;
+                let max_size = current_sectors * 512;
+
+                let size = cmp::min(extent.length as usize, max_size);
+
+                let mut data = try!(pool.take(max_size));
+                {
+                    let buffer = data.get(max_size);
+                    if try!(disk.read(extent.block, buffer)) < max_size {
+                        return Err(Error::new(EIO));
+                    }
+
+                    for (i, slot) in buffer[..size].chunks(per_block as usize * 512).enumerate() {
+                        let block = extent.block + i as u64 * per_block;
+                        if let Some(node_data) = NodeData::from_sector(slot) {
+                            if node_data.valid() {
+                                // Unused slots are zeroed
+                                if node_data.name[0] != 0 {
+                                    let mut node = Node::new(block, &node_data);
+                                    node.blocks_to_sectors(per_block);
+                                    if ! node.name_extent.empty() {
+                                        let rest = try!(FileSystem::read_name(disk, pool, &node.name_extent));
+                                        node.name.push_str(&String::from_utf8_lossy(&rest));
+                                    }
+                                    nodes.push(node);
+                                }
+                            } else {
+                                debugln!("{}: Invalid node checksum at block {}", disk.name(), block);
                             }
                         }
                     }
                 }
+                pool.give(data);
+            }
+        }
 
-                Ok(FileSystem {
-                    disk: disk,
-                    header: header,
-                    nodes: nodes,
-                })
-            } else {
-                debugln!("{}: Unknown Filesystem", disk.name());
-                Err(Error::new(EINVAL))
+        Ok(nodes)
+    }
+
+    /// Read the rest of a long name from its name extent
+    fn read_name(disk: &mut Box<Disk>, pool: &mut BufferPool, extent: &Extent) -> Result<Vec<u8>> {
+        let length = cmp::min(extent.length as usize, NAME_MAX - NAME_LONG_INLINE);
+        let size = (length + 511) / 512 * 512;
+        let mut data = try!(pool.take(size));
+        let name = {
+            let buffer = data.get(size);
+            if try!(disk.read(extent.block, buffer)) < size {
+                return Err(Error::new(EIO));
             }
-        } else {
-            Err(Error::new(ENOMEM))
+
+            buffer[..length].to_vec()
+        };
+        pool.give(data);
+
+        Ok(name)
+    }
+
+    /// Check if the journal holds an uncommitted transaction
+    fn journal_active(disk: &mut Box<Disk>, pool: &mut BufferPool, header: &Header) -> Result<bool> {
+        if header.journal.empty() {
+            return Ok(false);
         }
+
+        let journal: JournalHeader = try!(FileSystem::read_block(disk, pool, header.journal.block));
+        Ok(journal.active())
     }
 
-    /// Get node with a given filename
-    pub fn node(&self, filename: &str) -> Option<Node> {
-        for node in self.nodes.iter() {
-            if node.name == filename {
-                return Some(node.clone());
+    /// Restore the original data of all blocks logged by an uncommitted transaction, returns
+    /// true if anything was rolled back
+    fn rollback(disk: &mut Box<Disk>, pool: &mut BufferPool, header: &Header) -> Result<bool> {
+        if ! try!(FileSystem::journal_active(disk, pool, header)) {
+            return Ok(false);
+        }
+
+        let journal: JournalHeader = try!(FileSystem::read_block(disk, pool, header.journal.block));
+
+        let count = cmp::min(journal.count as usize, JOURNAL_RECORDS);
+        for i in 0..count {
+            let data: [u8; 512] = try!(FileSystem::read_block(disk, pool, header.journal.block + 1 + i as u64));
+            try!(FileSystem::write_block(disk, pool, journal.blocks[i], data));
+        }
+        try!(disk.flush());
+
+        try!(FileSystem::write_block(disk, pool, header.journal.block, JournalHeader::new()));
+        try!(disk.flush());
+
+        Ok(true)
+    }
+
+    /// Create a file system from a disk. Node table extents that are out of range or overlap
+    /// are logged and skipped
+    pub fn from_disk(disk: Box<Disk>) -> Result<Self> {
+        FileSystem::mount(disk, 512, false, Duration::realtime().secs as u64)
+    }
+
+    /// Create a file system from a disk that was made with blocks of `block_size` bytes instead
+    /// of 512. Returns EINVAL if the block size is not a power of two, or is smaller than a sector
+    /// of the disk
+    pub fn from_disk_with_block_size(disk: Box<Disk>, block_size: u64) -> Result<Self> {
+        if ! block_size.is_power_of_two() || block_size < 512 || block_size < disk.sector_size() {
+            return Err(Error::new(EINVAL));
+        }
+
+        FileSystem::mount(disk, block_size, false, Duration::realtime().secs as u64)
+    }
+
+    /// Create a file system from a disk, returns EINVAL if any node table extent is out of range
+    /// or overlaps
+    pub fn from_disk_strict(disk: Box<Disk>) -> Result<Self> {
+        FileSystem::mount(disk, 512, true, Duration::realtime().secs as u64)
+    }
+
+    /// Create a file system from a disk, checking it if it was not unmounted cleanly. With
+    /// `repair`, orphaned blocks are also reclaimed and `needs_check` is cleared, otherwise
+    /// `needs_check` is left set for the caller
+    pub fn from_disk_checked(disk: Box<Disk>, repair: bool) -> Result<Self> {
+        FileSystem::mount_checked(disk, repair, Duration::realtime().secs as u64)
+    }
+
+    fn mount_checked(disk: Box<Disk>, repair: bool, now: u64) -> Result<Self> {
+        let mut fs = try!(FileSystem::mount(disk, 512, false, now));
+        if fs.needs_check {
+            debugln!("{}: Not unmounted cleanly, checking", fs.disk.name());
+            for problem in fs.check().iter() {
+                debugln!("{}: {}", fs.disk.name(), problem);
+            }
+
+            if repair && ! fs.read_only {
+                try!(fs.reclaim_orphans());
+                fs.collect_orphans();
+                fs.needs_check = false;
             }
         }
 
-        None
+        Ok(fs)
     }
 
-    /// List nodes in a given directory
-    pub fn list(&self, directory_str: &str) -> Vec<String> {
-        let mut ret = Vec::new();
+    /// Check if a disk holds a file system, by looking for a valid primary or backup header
+    pub fn probe(disk: &mut Box<Disk>) -> bool {
+        let mut pool = BufferPool::new(1);
+        for block in [HEADER_BLOCK, HEADER_BACKUP_BLOCK].iter() {
+            if let Ok(header) = FileSystem::read_block::<Header>(disk, &mut pool, *block) {
+                if header.valid() {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Mount a file system made with blocks of `block_size` bytes, recording `now` as the time
+    /// of the mount
+    fn mount(mut disk: Box<Disk>, block_size: u64, strict: bool, now: u64) -> Result<Self> {
+        let per_block = block_size / 512;
+        let mut pool = BufferPool::new(POOL_BUFFERS);
+        let mut header = try!(FileSystem::read_header(&mut disk, &mut pool, HEADER_BLOCK, per_block));
+        let mut restore = false;
+        if header.is_none() {
+            header = try!(FileSystem::read_header(&mut disk, &mut pool, HEADER_BACKUP_BLOCK, per_block));
+            if header.is_some() {
+                debugln!("{}: Primary header invalid, using backup", disk.name());
+                restore = true;
+            }
+        }
+
+        if let Some(mut header) = header {
+            debugln!("{}: Redox Filesystem", disk.name());
+
+            // An incomplete transaction can not be rolled back without writing, and the blocks it
+            // wrote can not be trusted, so a write protected disk with one is not mounted
+            let read_only = disk.status().write_protected;
+            if read_only && try!(FileSystem::journal_active(&mut disk, &mut pool, &header)) {
+                debugln!("{}: Write protected with an incomplete transaction", disk.name());
+                return Err(Error::new(EROFS));
+            }
+
+            if try!(FileSystem::rollback(&mut disk, &mut pool, &header)) {
+                debugln!("{}: Rolled back incomplete transaction", disk.name());
+                header = match try!(FileSystem::read_header(&mut disk, &mut pool, HEADER_BLOCK, per_block)) {
+                    Some(header) => header,
+                    None => {
+                        restore = true;
+                        match try!(FileSystem::read_header(&mut disk, &mut pool, HEADER_BACKUP_BLOCK, per_block)) {
+                            Some(header) => header,
+                            None => return Err(Error::new(EINVAL)),
+                        }
+                    },
+                };
+            }
+
+            let bad = header.bad_extents();
+            for i in bad.iter() {
+                let extent = header.extents[*i];
+                debugln!("{}: Bad node table extent {} at block {}", disk.name(), i, extent.block);
+            }
+            if strict && ! bad.is_empty() {
+                return Err(Error::new(EINVAL));
+            }
+
+            let nodes = try!(FileSystem::read_nodes(&mut disk, &mut pool, &header, per_block));
+            let state = header.state;
 
-        let directory = if directory_str.is_empty() {
-            directory_str.to_owned()
+            let mut fs = FileSystem {
+                disk: disk,
+                header: header,
+                dirs: DirCounts::from_nodes(&nodes),
+                nodes: nodes,
+                locks: NodeLocks::new(),
+                txn: None,
+                snapshots: Vec::new(),
+                next_snapshot: 0,
+                released: Vec::new(),
+                state_at_mount: state,
+                needs_check: state == STATE_MOUNTED,
+                unnamed: Vec::new(),
+                open: Vec::new(),
+                pool: pool,
+                read_only: read_only,
+                block_size: block_size,
+            };
+
+            if read_only {
+                debugln!("{}: Write protected, mounting read only", fs.disk.name());
+                return Ok(fs);
+            }
+
+            if restore {
+                debugln!("{}: Restoring primary header", fs.disk.name());
+            }
+
+            // Writing the header also restores the primary header from the backup
+            fs.header.mounted = now;
+            fs.header.mount_count += 1;
+            fs.header.state = STATE_MOUNTED;
+            try!(fs.write_header());
+
+            Ok(fs)
+        } else {
+            debugln!("{}: Unknown Filesystem", disk.name());
+            Err(Error::new(EINVAL))
+        }
+    }
+
+    /// Unmount the file system. Cached sectors are written back and flushed, and then the header
+    /// is marked as cleanly unmounted, so that the next mount knows no check is needed
+    pub fn unmount(mut self) -> Result<()> {
+        self.mark_clean()
+    }
+
+    /// Flush everything to disk and mark the header as cleanly unmounted. Nothing is done on a
+    /// read only mount, which never marked the header as mounted
+    fn mark_clean(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        try!(self.disk.flush());
+        self.header.state = STATE_CLEAN;
+        try!(self.write_header());
+        self.disk.flush()
+    }
+
+    /// Get the administrative statistics of the file system
+    pub fn stats(&self) -> FsStats {
+        let known = |value: u64| if value == 0 {
+            None
         } else {
-            directory_str.to_owned() + "/"
+            Some(value)
         };
 
-        for node in self.nodes.iter() {
-            if node.name.starts_with(&directory) {
-                ret.push(node.name.get_slice(directory.len()..).to_string());
+        FsStats {
+            created: known(self.header.created),
+            last_mounted: known(self.header.mounted),
+            mount_count: known(self.header.mount_count),
+            clean: match self.state_at_mount {
+                STATE_MOUNTED => Some(false),
+                STATE_CLEAN => Some(true),
+                _ => None,
+            },
+        }
+    }
+
+    /// Read the header and node table again, for when the disk was changed behind the back of the
+    /// file system, as by a repair tool. The disk, snapshots, and unnamed nodes are kept. Returns
+    /// EBUSY during a transaction, and EINVAL if neither header is valid, leaving everything as it
+    /// was
+    pub fn reload(&mut self) -> Result<()> {
+        if self.txn.is_some() {
+            return Err(Error::new(EBUSY));
+        }
+
+        let per_block = self.per_block();
+        let header = match try!(FileSystem::read_header(&mut self.disk, &mut self.pool, HEADER_BLOCK, per_block)) {
+            Some(header) => header,
+            None => match try!(FileSystem::read_header(&mut self.disk, &mut self.pool, HEADER_BACKUP_BLOCK, per_block)) {
+                Some(header) => header,
+                None => return Err(Error::new(EINVAL)),
+            },
+        };
+
+        let nodes = try!(FileSystem::read_nodes(&mut self.disk, &mut self.pool, &header, per_block));
+        self.header = header;
+        self.dirs = DirCounts::from_nodes(&nodes);
+        self.nodes = nodes;
+        // The released extents may be in use on the changed disk
+        self.released.clear();
+        Ok(())
+    }
+
+    /// Begin a transaction. Until `commit_txn` is called, the original data of every metadata
+    /// block that is written is logged to the journal, so that an interrupted transaction is
+    /// rolled back on the next mount. Every change to the file system starts with a transaction,
+    /// so this returns EROFS on a read only mount
+    pub fn begin_txn(&mut self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+        if self.txn.is_some() {
+            return Err(Error::new(EBUSY));
+        }
+
+        self.txn = Some(Vec::new());
+        Ok(())
+    }
+
+    /// Commit the current transaction, making all of its writes permanent
+    pub fn commit_txn(&mut self) -> Result<()> {
+        if let Some(txn) = self.txn.take() {
+            if ! txn.is_empty() && ! self.header.journal.empty() {
+                try!(self.disk.flush());
+                try!(FileSystem::write_block(&mut self.disk, &mut self.pool, self.header.journal.block, JournalHeader::new()));
+                try!(self.disk.flush());
             }
         }
 
-        ret
+        Ok(())
+    }
+
+    /// Abort the current transaction, restoring the original data of all blocks it wrote and
+    /// reloading the header and nodes from disk
+    pub fn abort_txn(&mut self) -> Result<()> {
+        if self.txn.take().is_some() {
+            if try!(FileSystem::rollback(&mut self.disk, &mut self.pool, &self.header)) {
+                let per_block = self.per_block();
+                self.header = match try!(FileSystem::read_header(&mut self.disk, &mut self.pool, HEADER_BLOCK, per_block)) {
+                    Some(header) => header,
+                    None => return Err(Error::new(EINVAL)),
+                };
+                self.nodes = try!(FileSystem::read_nodes(&mut self.disk, &mut self.pool, &self.header, per_block));
+            }
+            // Nodes renamed in memory before the failure are counted again either way
+            self.dirs = DirCounts::from_nodes(&self.nodes);
+        }
+
+        Ok(())
+    }
+
+    /// Log the original data of a block to the journal, if a transaction is active. A transaction
+    /// can log at most `JOURNAL_RECORDS` blocks, or fewer if the journal is smaller, and E2BIG is
+    /// returned past that, after which the transaction has to be aborted. Operations that may
+    /// write more blocks have to be split into several transactions by the caller
+    fn journal_block(&mut self, block: u64) -> Result<()> {
+        let journal = self.header.journal;
+        if journal.empty() {
+            return Ok(());
+        }
+
+        let mut journal_header = JournalHeader::new();
+        match self.txn {
+            Some(ref txn) => {
+                if txn.contains(&block) {
+                    return Ok(());
+                }
+
+                if txn.len() >= JOURNAL_RECORDS || txn.len() as u64 + 1 >= journal.length / 512 {
+                    return Err(Error::new(E2BIG));
+                }
+
+                for (i, logged) in txn.iter().enumerate() {
+                    journal_header.blocks[i] = *logged;
+                }
+                journal_header.blocks[txn.len()] = block;
+                journal_header.count = txn.len() as u64 + 1;
+                journal_header.state = JOURNAL_ACTIVE;
+            },
+            None => return Ok(()),
+        }
+
+        let index = journal_header.count - 1;
+        let data: [u8; 512] = try!(FileSystem::read_block(&mut self.disk, &mut self.pool, block));
+        try!(FileSystem::write_block(&mut self.disk, &mut self.pool, journal.block + 1 + index, data));
+        try!(self.disk.flush());
+
+        try!(FileSystem::write_block(&mut self.disk, &mut self.pool, journal.block, journal_header));
+        try!(self.disk.flush());
+
+        if let Some(ref mut txn) = self.txn {
+            txn.push(block);
+        }
+
+        Ok(())
+    }
+
+    /// Write the header to disk, the backup is written and flushed before the primary so that
+    /// one valid copy always exists
+    pub fn write_header(&mut self) -> Result<()> {
+        let per_block = self.per_block();
+        let mut header = unsafe { ptr::read(&self.header) };
+        header.sectors_to_blocks(per_block);
+        header.checksum = header.checksum();
+        self.header.checksum = header.checksum;
+
+        try!(self.journal_block(HEADER_BACKUP_BLOCK * per_block));
+        try!(self.journal_block(HEADER_BLOCK * per_block));
+
+        let backup = unsafe { ptr::read(&header) };
+        try!(FileSystem::write_block(&mut self.disk, &mut self.pool, HEADER_BACKUP_BLOCK * per_block, backup));
+        try!(self.disk.flush());
+
+        try!(FileSystem::write_block(&mut self.disk, &mut self.pool, HEADER_BLOCK * per_block, header));
+        self.disk.flush()
+    }
+
+    /// Write a node to disk and update the cached copy
+    pub fn write_node(&mut self, node: &Node) -> Result<()> {
+        try!(self.journal_block(node.block));
+
+        let mut stored = node.clone();
+        stored.sectors_to_blocks(self.per_block());
+        try!(FileSystem::write_block(&mut self.disk, &mut self.pool, node.block, stored.data()));
+
+        let mut renamed = None;
+        for mut cached in self.nodes.iter_mut() {
+            if cached.block == node.block {
+                if cached.name != node.name {
+                    renamed = Some(cached.name.clone());
+                }
+                *cached = node.clone();
+            }
+        }
+        if let Some(old) = renamed {
+            self.dirs.remove(&old);
+            self.dirs.add(&node.name);
+        }
+
+        Ok(())
+    }
+
+    /// Add a node to the node list, counting it in the directories it is below. The node is not
+    /// written
+    pub fn add_node(&mut self, node: Node) {
+        self.dirs.add(&node.name);
+        self.nodes.push(node);
+    }
+
+    /// Remove the node at `block` from the node list, returning it, or None if there is no such
+    /// node. The node table is not written
+    pub fn remove_node(&mut self, block: u64) -> Option<Node> {
+        match self.nodes.iter().position(|node| node.block == block) {
+            Some(i) => {
+                let node = self.nodes.remove(i);
+                self.dirs.remove(&node.name);
+                Some(node)
+            },
+            None => None,
+        }
+    }
+
+    /// Check if a directory has no entries, without scanning the nodes. Directories only exist
+    /// while they have entries, so this is also true for paths that are not directories. An empty
+    /// path is the root
+    pub fn dir_empty(&self, directory: &str) -> bool {
+        if directory.trim_right_matches('/').is_empty() {
+            self.nodes.is_empty()
+        } else {
+            self.dirs.is_empty(directory)
+        }
+    }
+
+    /// Check the nodes for blocks that are also free space or used by another node, returning a
+    /// description of each problem
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let block = Extent {
+                block: node.block,
+                length: 512,
+            };
+            if block.overlaps(&self.header.free_space) {
+                problems.push(format!("Node {} at block {} is in free space", node.name, node.block));
+            }
+
+            for extent in node.extents.iter().filter(|extent| ! extent.empty()) {
+                if extent.overlaps(&self.header.free_space) {
+                    problems.push(format!("Node {} extent at block {} is in free space", node.name, extent.block));
+                }
+
+                for other in self.nodes[i + 1..].iter() {
+                    if other.extents.iter().any(|other_extent| other_extent.overlaps(extent)) {
+                        problems.push(format!("Node {} extent at block {} is shared with {}",
+                                              node.name, extent.block, other.name));
+                    }
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Describe where everything is on disk, for debugging tools that make images: the headers,
+    /// the journal, the node table, the free space, the bad blocks and the extents of every node,
+    /// followed by the number of fragmented nodes, those with more than one data extent. Unlike
+    /// `check`, nothing is judged, and nothing is read from the disk. Blocks are in sectors
+    pub fn dump_layout(&self) -> String {
+        let mut dump = String::new();
+        dump.push_str(&format!("Label: {}\n", self.header.label()));
+        dump.push_str(&format!("Block size: {}\n", self.block_size));
+        dump.push_str(&format!("Headers: block {}, backup at block {}\n",
+                               HEADER_BLOCK * self.per_block(), HEADER_BACKUP_BLOCK * self.per_block()));
+
+        if self.header.journal.empty() {
+            dump.push_str("Journal: none\n");
+        } else {
+            dump.push_str(&format!("Journal: block {}, {} sectors\n", self.header.journal.block, self.header.journal.sectors()));
+        }
+
+        let bad = self.header.bad_extents();
+        for (i, extent) in self.header.extents.iter().enumerate() {
+            if ! extent.empty() {
+                dump.push_str(&format!("Node table {}: block {}, {} slots{}\n", i, extent.block,
+                                       extent.length / self.block_size,
+                                       if bad.contains(&i) { " (ignored, overlaps other metadata)" } else { "" }));
+            }
+        }
+
+        // The free space is a single extent, there is no allocation bitmap
+        dump.push_str(&format!("Free space: block {}, {} sectors\n", self.header.free_space.block, self.header.free_space.sectors()));
+
+        let mut bad_blocks = String::new();
+        for block in self.header.bad_blocks.iter().filter(|block| **block > 0) {
+            if ! bad_blocks.is_empty() {
+                bad_blocks.push_str(", ");
+            }
+            bad_blocks.push_str(&block.to_string());
+        }
+        if ! bad_blocks.is_empty() {
+            dump.push_str(&format!("Bad blocks: {}\n", bad_blocks));
+        }
+
+        let mut fragmented = 0;
+        dump.push_str(&format!("Nodes: {}, unnamed: {}\n", self.nodes.len(), self.unnamed.len()));
+        for node in self.nodes.iter().chain(self.unnamed.iter()) {
+            let name = if node.name.is_empty() { "(unnamed)" } else { node.name.as_str() };
+            dump.push_str(&format!("  {} at block {}\n", name, node.block));
+            if ! node.name_extent.empty() {
+                dump.push_str(&format!("    name: block {}, {} bytes\n", node.name_extent.block, node.name_extent.length));
+            }
+            if ! node.xattrs.empty() {
+                dump.push_str(&format!("    xattrs: block {}, {} bytes\n", node.xattrs.block, node.xattrs.length));
+            }
+
+            let mut extents = 0;
+            for extent in node.extents.iter().filter(|extent| ! extent.empty()) {
+                dump.push_str(&format!("    data: block {}, {} bytes\n", extent.block, extent.length));
+                extents += 1;
+            }
+            if extents > 1 {
+                fragmented += 1;
+            }
+        }
+        dump.push_str(&format!("Fragmented nodes: {}\n", fragmented));
+
+        dump
+    }
+
+    /// Check if a block is used by the headers, the journal, the node table, any node, including
+    /// unnamed ones and those of open files, or a snapshot. Bad blocks count as used, so they are
+    /// never reclaimed
+    pub fn block_used(&self, block: u64) -> bool {
+        if block < (HEADER_BACKUP_BLOCK + 1) * self.per_block() || self.header.journal.contains(block)
+           || self.header.is_bad_sector(block, self.per_block()) {
+            return true;
+        }
+
+        for extent in self.header.extents.iter() {
+            if extent.contains(block) {
+                return true;
+            }
+        }
+
+        for node in self.nodes.iter().chain(self.unnamed.iter()).chain(self.open.iter()) {
+            if node.block == block || node.xattrs.contains(block) || node.name_extent.contains(block) {
+                return true;
+            }
+
+            for extent in node.extents.iter() {
+                if extent.contains(block) {
+                    return true;
+                }
+            }
+        }
+
+        self.block_refs(block) > 0
+    }
+
+    /// Find the first run of `sectors` free sectors, rounded up to whole blocks, that has no bad
+    /// blocks, in the released extents and then in the free space
+    pub fn find_free(&self, sectors: u64) -> Option<u64> {
+        let sectors = self.round_sectors(sectors);
+        let per_block = self.per_block();
+        for extent in self.released.iter() {
+            let end = extent.block + extent.sectors();
+            let mut block = extent.block;
+            while block + sectors <= end {
+                match (block..block + sectors).rev().find(|sector| self.header.is_bad_sector(*sector, per_block)) {
+                    Some(bad) => block = (bad / per_block + 1) * per_block,
+                    None => return Some(block),
+                }
+            }
+        }
+
+        self.header.find_free(sectors, per_block)
+    }
+
+    /// Allocate the run of `sectors` sectors at `block`, as found by `find_free`. A run in a
+    /// released extent is cut out of it, which only changes memory, as released extents are not
+    /// recorded in the header
+    pub fn take_free(&mut self, block: u64, sectors: u64) {
+        let sectors = self.round_sectors(sectors);
+        let found = self.released.iter().position(|extent| extent.contains(block));
+        match found {
+            Some(i) => {
+                let extent = self.released.remove(i);
+                let end = extent.block + extent.sectors();
+                if block > extent.block {
+                    self.released.push(Extent {
+                        block: extent.block,
+                        length: (block - extent.block) * 512,
+                    });
+                }
+                if block + sectors < end {
+                    self.released.push(Extent {
+                        block: block + sectors,
+                        length: (end - block - sectors) * 512,
+                    });
+                }
+            },
+            None => self.header.take_free(block, sectors),
+        }
+    }
+
+    /// Add a run of free blocks to the released extents, joining it to the free space if it is
+    /// directly before it. The header is written if the free space changed
+    fn release(&mut self, block: u64, sectors: u64) -> Result<()> {
+        self.released.push(Extent {
+            block: block,
+            length: sectors * 512,
+        });
+
+        let mut joined = false;
+        loop {
+            let free = self.header.free_space.block;
+            let i = match self.released.iter().position(|extent| extent.block + extent.sectors() == free) {
+                Some(i) => i,
+                None => break,
+            };
+            let extent = self.released.remove(i);
+            self.header.free_space.block = extent.block;
+            self.header.free_space.length += extent.length;
+            joined = true;
+        }
+
+        if joined && ! self.read_only {
+            try!(self.begin_txn());
+            if let Err(err) = self.write_header() {
+                let _ = self.abort_txn();
+                return Err(err);
+            }
+            try!(self.commit_txn());
+        }
+
+        Ok(())
+    }
+
+    /// Find the orphans anywhere before the free space, not only those directly before it that
+    /// `reclaim_orphans` returns, and add them to the released extents so that allocations reuse
+    /// them. Nothing is written. Blocks of nodes that could not be read are orphans too. Returns
+    /// the number of sectors found
+    pub fn collect_orphans(&mut self) -> u64 {
+        let per_block = self.per_block();
+
+        // The used runs of sectors, as start and end
+        let mut used: Vec<(u64, u64)> = Vec::new();
+        used.push((0, (HEADER_BACKUP_BLOCK + 1) * per_block));
+        let mut extents: Vec<Extent> = Vec::new();
+        extents.push(self.header.journal);
+        for extent in self.header.extents.iter() {
+            extents.push(*extent);
+        }
+        for node in self.nodes.iter().chain(self.unnamed.iter()).chain(self.open.iter()) {
+            extents.push(Extent {
+                block: node.block,
+                length: per_block * 512,
+            });
+            extents.push(node.xattrs);
+            extents.push(node.name_extent);
+            for extent in node.extents.iter() {
+                extents.push(*extent);
+            }
+        }
+        for snapshot in self.snapshots.iter() {
+            for node in snapshot.nodes.iter() {
+                for extent in node.extents.iter() {
+                    extents.push(*extent);
+                }
+            }
+        }
+        for extent in self.released.iter() {
+            extents.push(*extent);
+        }
+        for extent in extents.iter().filter(|extent| ! extent.empty()) {
+            used.push((extent.block, extent.block + extent.sectors()));
+        }
+        for bad in self.header.bad_blocks.iter().filter(|bad| **bad > 0) {
+            used.push((*bad / per_block * per_block, (*bad / per_block + 1) * per_block));
+        }
+        used.sort();
+
+        let mut found = 0;
+        let mut start = 0;
+        for &(block, end) in used.iter().chain([(self.header.free_space.block, 0)].iter()) {
+            // Only whole blocks are free
+            let first = (start + per_block - 1) / per_block * per_block;
+            let last = cmp::min(block, self.header.free_space.block) / per_block * per_block;
+            if last > first {
+                self.released.push(Extent {
+                    block: first,
+                    length: (last - first) * 512,
+                });
+                found += last - first;
+            }
+            start = cmp::max(start, end);
+        }
+
+        if found > 0 {
+            debugln!("{}: Collected {} orphaned sectors", self.disk.name(), found);
+        }
+        found
+    }
+
+    /// Allocate `sectors` contiguous sectors, rounded up to whole blocks, from the start of the
+    /// free space, skipping bad blocks. Returns the first sector, or ENOSPC. The header is not
+    /// written
+    pub fn allocate(&mut self, sectors: u64) -> Result<u64> {
+        match self.find_free(sectors) {
+            Some(block) => {
+                self.take_free(block, sectors);
+                Ok(block)
+            },
+            None => Err(Error::new(ENOSPC)),
+        }
+    }
+
+    /// Get the runs of free sectors that can be allocated, in order of position, for placing a
+    /// large file. This is the free space split around bad blocks. The released extents before
+    /// it are left for smaller allocations. Returns nothing if the free space is full
+    pub fn free_extents(&self) -> Vec<Extent> {
+        let per_block = self.per_block();
+        let end = self.header.free_space.block + self.header.free_space.length / 512;
+
+        let mut bad: Vec<u64> = self.header.bad_blocks.iter()
+                                    .filter(|block| **block > 0)
+                                    .map(|block| *block / per_block * per_block)
+                                    .filter(|block| *block + per_block > self.header.free_space.block && *block < end)
+                                    .collect();
+        bad.sort();
+
+        let mut extents = Vec::new();
+        let mut block = self.header.free_space.block;
+        for bad_block in bad.iter() {
+            if *bad_block > block {
+                extents.push(Extent {
+                    block: block,
+                    length: (*bad_block - block) * 512,
+                });
+            }
+            block = cmp::max(block, *bad_block + per_block);
+        }
+        if block < end {
+            extents.push(Extent {
+                block: block,
+                length: (end - block) * 512,
+            });
+        }
+
+        extents
+    }
+
+    /// Read every block of the file system, from the headers to the end of the free space, and
+    /// return those that fail with EIO, or read short. Blocks are read in chunks, and a chunk that
+    /// fails is read again one block at a time. Nothing is written
+    pub fn scan_bad_blocks(&mut self) -> Result<Vec<u64>> {
+        let end = self.header.free_space.block + self.header.free_space.sectors();
+        let mut bad = Vec::new();
+
+        let mut data = try!(self.pool.take(SCAN_CHUNK as usize * 512));
+        let mut block = HEADER_BLOCK * self.per_block();
+        while block < end {
+            let sectors = cmp::min(SCAN_CHUNK, end - block);
+            let size = sectors as usize * 512;
+            let read = match self.disk.read(block, data.get(size)) {
+                Ok(count) => count == size,
+                Err(ref err) if err.errno == EIO => false,
+                Err(err) => return Err(err),
+            };
+
+            if ! read {
+                for i in block..block + sectors {
+                    let read = match self.disk.read(i, data.get(512)) {
+                        Ok(count) => count == 512,
+                        Err(ref err) if err.errno == EIO => false,
+                        Err(err) => return Err(err),
+                    };
+                    if ! read {
+                        bad.push(i);
+                    }
+                }
+            }
+
+            block += sectors;
+        }
+        self.pool.give(data);
+
+        if ! bad.is_empty() {
+            debugln!("{}: {} bad blocks", self.disk.name(), bad.len());
+        }
+
+        Ok(bad)
+    }
+
+    /// Scrub the next `SCRUB_CHUNK` sectors from `cursor`. Used sectors are read, node table
+    /// slots are checked against their checksums, and the data is written back to refresh it.
+    /// Sectors that fail to read or fail their checksum are put on the bad block list. Returns the
+    /// cursor to pass next, 0 once the end of the file system is reached, so that a daemon can
+    /// scrub the whole disk a little at a time. Nothing is done on a read only mount
+    pub fn scrub_step(&mut self, cursor: u64) -> Result<u64> {
+        if self.read_only {
+            return Ok(cursor);
+        }
+
+        let start = cmp::max(cursor, HEADER_BLOCK * self.per_block());
+        let end = self.header.free_space.block + self.header.free_space.sectors();
+        if start >= end {
+            return Ok(0);
+        }
+
+        let sectors = cmp::min(SCRUB_CHUNK, end - start);
+        let size = sectors as usize * 512;
+        let mut data = try!(self.pool.take(size));
+        let result = self.scrub_sectors(start, data.get(size));
+        self.pool.give(data);
+
+        let bad = try!(result);
+        if ! bad.is_empty() {
+            debugln!("{}: Scrub found {} bad blocks", self.disk.name(), bad.len());
+            try!(self.mark_bad_blocks(&bad));
+        }
+
+        if start + sectors < end {
+            Ok(start + sectors)
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Scrub the sectors from `start` that fit in `buffer`, returning those that are bad
+    fn scrub_sectors(&mut self, start: u64, buffer: &mut [u8]) -> Result<Vec<u64>> {
+        let per_block = self.per_block();
+        let sectors = buffer.len() / 512;
+
+        let mut readable = vec![true; sectors];
+        let read = match self.disk.read(start, buffer) {
+            Ok(count) => count == buffer.len(),
+            Err(ref err) if err.errno == EIO => false,
+            Err(err) => return Err(err),
+        };
+        if ! read {
+            for i in 0..sectors {
+                readable[i] = match self.disk.read(start + i as u64, &mut buffer[i * 512..(i + 1) * 512]) {
+                    Ok(count) => count == 512,
+                    Err(ref err) if err.errno == EIO => false,
+                    Err(err) => return Err(err),
+                };
+            }
+        }
+
+        let mut bad = Vec::new();
+        // The first sector of the run of good sectors to write back
+        let mut run = None;
+        for i in 0..sectors + 1 {
+            let good = i < sectors && {
+                let block = start + i as u64;
+                let slot = self.header.extents.iter().any(|extent| {
+                    extent.contains(block) && (block - extent.block) % per_block == 0
+                });
+
+                if ! readable[i] {
+                    bad.push(block);
+                    false
+                } else if ! self.block_used(block) || self.header.is_bad_sector(block, per_block) {
+                    false
+                } else if slot && ! NodeData::from_sector(&buffer[i * 512..]).map_or(false, |data| data.valid()) {
+                    debugln!("{}: Invalid node checksum at block {}", self.disk.name(), block);
+                    bad.push(block);
+                    false
+                } else {
+                    true
+                }
+            };
+
+            if good {
+                if run.is_none() {
+                    run = Some(i);
+                }
+            } else if let Some(first) = run.take() {
+                let data = &buffer[first * 512..i * 512];
+                if try!(self.disk.write(start + first as u64, data)) < data.len() {
+                    return Err(Error::new(EIO));
+                }
+            }
+        }
+
+        Ok(bad)
+    }
+
+    /// Record blocks on the bad block list in the header, so that they are never allocated. A
+    /// bad sector marks the whole block it is in. Returns the number of blocks that did not fit
+    /// on the list
+    pub fn mark_bad_blocks(&mut self, blocks: &[u64]) -> Result<usize> {
+        let per_block = self.per_block();
+        let mut missed = 0;
+        for block in blocks.iter() {
+            if ! self.header.add_bad_block(*block / per_block * per_block) {
+                missed += 1;
+            }
+        }
+
+        try!(self.begin_txn());
+        if let Err(err) = self.write_header() {
+            let _ = self.abort_txn();
+            return Err(err);
+        }
+        try!(self.commit_txn());
+
+        Ok(missed)
+    }
+
+    /// Return orphaned blocks, which are allocated but not used by anything, to the free space.
+    /// The free space is a single extent, so only the run of orphans directly before it can be
+    /// returned. Orphans with a used block between them and the free space stay allocated until
+    /// the blocks after them are freed too. The blocks of open files are used, as registered with
+    /// `open_node`, so this is safe to run while files are open, as long as every handle keeps its
+    /// node up to date with `update_open`. Returns the number of sectors recovered, or EROFS if
+    /// the file system is mounted read only
+    pub fn reclaim_orphans(&mut self) -> Result<u64> {
+        if self.read_only {
+            return Err(Error::new(EROFS));
+        }
+
+        let mut block = self.header.free_space.block;
+        while block > 0 && ! self.block_used(block - 1) {
+            block -= 1;
+        }
+        // The free space has to start on a block, and the end of the last used block is not used
+        block = self.round_sectors(block);
+
+        let reclaimed = self.header.free_space.block - block;
+        if reclaimed > 0 {
+            try!(self.begin_txn());
+
+            self.header.free_space.block = block;
+            self.header.free_space.length += reclaimed * 512;
+            // Released extents are not used, so any that were reclaimed are entirely in the run
+            self.released.retain(|extent| extent.block < block);
+
+            if let Err(err) = self.write_header() {
+                let _ = self.abort_txn();
+                return Err(err);
+            }
+
+            try!(self.commit_txn());
+
+            debugln!("{}: Reclaimed {} orphaned sectors", self.disk.name(), reclaimed);
+        }
+
+        Ok(reclaimed)
+    }
+
+    /// Get the unused slots of the node table, at most `max` of them, in order
+    fn free_slots(&self, max: usize) -> Vec<u64> {
+        let bad = self.header.bad_extents();
+        let per_block = self.per_block();
+
+        let mut slots = Vec::new();
+        for (i, extent) in self.header.extents.iter().enumerate() {
+            if extent.empty() || bad.contains(&i) {
+                continue;
+            }
+
+            for j in 0..extent.length / self.block_size {
+                let slot = extent.block + j * per_block;
+                // The slot of a node unlinked while open is still in use by its file
+                let used = self.nodes.iter().chain(self.unnamed.iter()).chain(self.open.iter()).any(|node| node.block == slot);
+                if slots.len() < max && ! used {
+                    slots.push(slot);
+                }
+            }
+        }
+
+        slots
+    }
+
+    /// Create empty files for many names at once, for filling a new image. The nodes are put in
+    /// unused slots of the node table, and runs of adjacent slots are written together, then the
+    /// header is written once. Returns EEXIST if a name exists or is given twice, EINVAL for an
+    /// empty name, ENAMETOOLONG past `NAME_MAX`, ENOSPC if the node table does not have a slot
+    /// for every name or the long names do not fit, and E2BIG if the long names need more
+    /// sectors than one transaction can log. On any error, the slots already written
+    /// are cleared and the header is left as it was, so none of the nodes are created
+    pub fn create_nodes(&mut self, names: &[&str]) -> Result<Vec<Node>> {
+        for (i, name) in names.iter().enumerate() {
+            if name.is_empty() {
+                return Err(Error::new(EINVAL));
+            }
+            if name.len() > NAME_MAX {
+                return Err(Error::new(ENAMETOOLONG));
+            }
+            if self.node(name).is_some() || names[..i].contains(name) {
+                return Err(Error::new(EEXIST));
+            }
+        }
+
+        let slots = self.free_slots(names.len());
+        if slots.len() < names.len() {
+            return Err(Error::new(ENOSPC));
+        }
+
+        try!(self.begin_txn());
+        let header = unsafe { ptr::read(&self.header) };
+
+        let mut nodes = Vec::new();
+        let mut written = 0;
+        let mut result = self.create_nodes_at(names, &slots, &mut nodes, &mut written);
+        if result.is_ok() {
+            result = self.write_header();
+        }
+
+        if let Err(err) = result {
+            // The slots were unused, so they are cleared rather than logged to the journal
+            for slot in slots[..written].iter() {
+                let _ = FileSystem::write_block(&mut self.disk, &mut self.pool, *slot, [0u8; 512]);
+            }
+            let _ = self.disk.flush();
+            self.header = header;
+            let _ = self.abort_txn();
+            return Err(err);
+        }
+        try!(self.commit_txn());
+
+        for node in nodes.iter() {
+            self.add_node(node.clone());
+        }
+        Ok(nodes)
+    }
+
+    /// Build the nodes of `create_nodes` in the given slots and write them, counting the slots
+    /// written in `written`. The rest of a long name is written to a new name extent
+    fn create_nodes_at(&mut self, names: &[&str], slots: &[u64], nodes: &mut Vec<Node>, written: &mut usize) -> Result<()> {
+        let empty = Extent {
+            block: 0,
+            length: 0,
+        };
+
+        for (name, slot) in names.iter().zip(slots.iter()) {
+            let mut name_extent = empty;
+            if name.len() > NAME_INLINE {
+                let rest = &name.as_bytes()[NAME_LONG_INLINE..];
+                name_extent.block = try!(self.allocate((rest.len() as u64 + 511) / 512));
+                name_extent.length = rest.len() as u64;
+                try!(self.write_name(name_extent.block, rest));
+            }
+
+            nodes.push(Node {
+                block: *slot,
+                name: name.to_string(),
+                name_extent: name_extent,
+                xattrs: empty,
+                extents: [empty; 16],
+                compressed: false,
+            });
+        }
+
+        let per_block = self.per_block();
+        let slot_size = self.block_size as usize;
+        let max = cmp::max(cmp::min(WRITE_CHUNK / 512, cmp::max(self.disk.max_transfer_sectors(), 1)) * 512 / slot_size, 1);
+
+        let mut start = 0;
+        while start < nodes.len() {
+            let mut end = start + 1;
+            while end < nodes.len() && end - start < max && nodes[end].block == nodes[end - 1].block + per_block {
+                end += 1;
+            }
+
+            let size = (end - start) * slot_size;
+            let mut data = try!(self.pool.take(size));
+            let result = {
+                let buffer = data.get(size);
+                for b in buffer.iter_mut() {
+                    *b = 0;
+                }
+                for (i, node) in nodes[start..end].iter().enumerate() {
+                    let mut stored = node.clone();
+                    stored.sectors_to_blocks(per_block);
+                    unsafe { ptr::write(buffer[i * slot_size..].as_mut_ptr() as *mut NodeData, stored.data().to_le()) };
+                }
+
+                self.disk.write(nodes[start].block, buffer)
+            };
+            self.pool.give(data);
+            // A failed write may have reached some of the slots
+            *written = end;
+            try!(result);
+
+            start = end;
+        }
+
+        // Barrier between the nodes and the header that makes their name extents used
+        self.disk.flush()
+    }
+
+    /// Register the node of a newly opened file, so that its blocks stay used until `close_node`
+    pub fn open_node(&mut self, node: &Node) {
+        self.open.push(node.clone());
+    }
+
+    /// Update the node of an open file after its handle changed its blocks, from `old`, the node
+    /// as it was registered
+    pub fn update_open(&mut self, old: &Node, node: &Node) {
+        if let Some(open) = self.open.iter_mut().find(|open| same_blocks(open, old)) {
+            *open = node.clone();
+        }
+    }
+
+    /// Forget the node of a file that was closed. Handles with the same blocks are
+    /// interchangeable, so any one of them is removed
+    pub fn close_node(&mut self, node: &Node) {
+        if let Some(i) = self.open.iter().position(|open| same_blocks(open, node)) {
+            self.open.remove(i);
+        }
+    }
+
+    /// Allocate a node without a name for a temporary file. It is only kept in memory, so after a
+    /// crash its blocks are orphans that a checked mount reclaims
+    pub fn create_unnamed(&mut self) -> Result<Node> {
+        let block = match self.find_free(1) {
+            Some(block) => block,
+            None => return Err(Error::new(ENOSPC)),
+        };
+
+        let empty = Extent {
+            block: 0,
+            length: 0,
+        };
+        let node = Node {
+            block: block,
+            name: String::new(),
+            name_extent: empty,
+            xattrs: empty,
+            extents: [empty; 16],
+            compressed: false,
+        };
+
+        try!(self.begin_txn());
+        self.take_free(block, 1);
+        if let Err(err) = self.write_header() {
+            let _ = self.abort_txn();
+            return Err(err);
+        }
+        try!(self.commit_txn());
+
+        self.unnamed.push(node.clone());
+        Ok(node)
+    }
+
+    /// Update the extents of an unnamed node after its data was written
+    pub fn update_unnamed(&mut self, node: &Node) {
+        for mut cached in self.unnamed.iter_mut() {
+            if cached.block == node.block {
+                *cached = node.clone();
+            }
+        }
+    }
+
+    /// Forget an unnamed node once its file is closed, and return its blocks to the free space
+    /// if they are directly before it. Returns the number of sectors recovered
+    pub fn remove_unnamed(&mut self, block: u64) -> Result<u64> {
+        self.unnamed.retain(|node| node.block != block);
+        self.reclaim_orphans()
+    }
+
+    /// Move a node of the node table to another slot, clearing the old slot in the same
+    /// transaction so that the node is never in both
+    fn move_node(&mut self, from: u64, to: u64) -> Result<()> {
+        let mut node = match self.nodes.iter().find(|node| node.block == from) {
+            Some(node) => node.clone(),
+            None => return Err(Error::new(ENOENT)),
+        };
+        node.block = to;
+
+        self.locks.lock(from);
+        let mut result = self.begin_txn();
+        if result.is_ok() {
+            result = self.write_node(&node);
+            if result.is_ok() {
+                result = self.journal_block(from);
+            }
+            if result.is_ok() {
+                result = FileSystem::write_block(&mut self.disk, &mut self.pool, from, [0u8; 512]).and(Ok(()));
+            }
+
+            if result.is_ok() {
+                result = self.commit_txn();
+            } else {
+                let _ = self.abort_txn();
+            }
+        }
+
+        if result.is_ok() {
+            for cached in self.nodes.iter_mut() {
+                if cached.block == from {
+                    cached.block = to;
+                }
+            }
+        }
+        self.locks.unlock(from);
+
+        result
+    }
+
+    /// Move the nodes of the node table to its front, closing the holes left by removed nodes,
+    /// and shrink the table extents to fit. Every move clears the old slot in the same
+    /// transaction and the header is only written once all nodes are moved, so an interrupted
+    /// compaction leaves a valid table. Open files keep the old block of their node, so this
+    /// must only be run with no files open. Blocks dropped from the table are left for
+    /// `reclaim_orphans`
+    pub fn compact_node_table(&mut self) -> Result<()> {
+        let bad = self.header.bad_extents();
+        let block_size = self.block_size;
+        let per_block = self.per_block();
+
+        let mut slots = Vec::new();
+        for (i, extent) in self.header.extents.iter().enumerate() {
+            if ! extent.empty() && ! bad.contains(&i) {
+                for j in 0..extent.length / block_size {
+                    slots.push(extent.block + j * per_block);
+                }
+            }
+        }
+
+        let mut live = Vec::new();
+        for slot in slots.iter() {
+            if self.nodes.iter().any(|node| node.block == *slot) {
+                live.push(*slot);
+            }
+        }
+
+        for (i, block) in live.iter().enumerate() {
+            if slots[i] != *block {
+                try!(self.move_node(*block, slots[i]));
+            }
+        }
+
+        let mut remaining = live.len() as u64;
+        let mut shrunk = false;
+        for (i, extent) in self.header.extents.iter_mut().enumerate() {
+            if extent.empty() || bad.contains(&i) {
+                continue;
+            }
+
+            let blocks = extent.length / block_size;
+            if remaining >= blocks {
+                remaining -= blocks;
+            } else {
+                if remaining > 0 {
+                    extent.length = remaining * block_size;
+                } else {
+                    extent.block = 0;
+                    extent.length = 0;
+                }
+                remaining = 0;
+                shrunk = true;
+            }
+        }
+
+        if shrunk {
+            try!(self.begin_txn());
+
+            if let Err(err) = self.write_header() {
+                let _ = self.abort_txn();
+                return Err(err);
+            }
+
+            try!(self.commit_txn());
+
+            debugln!("{}: Compacted node table to {} nodes", self.disk.name(), live.len());
+        }
+
+        Ok(())
+    }
+
+    /// Set the name of a node. The part of a name longer than `NAME_INLINE` that does not fit in
+    /// the node data is written to a name extent, which is allocated from free space if the node
+    /// has none or it is too small. Returns ENAMETOOLONG past `NAME_MAX`. The node itself is not
+    /// written. Must be called inside a transaction
+    pub fn set_name(&mut self, node: &mut Node, name: &str) -> Result<()> {
+        if name.len() > NAME_MAX {
+            return Err(Error::new(ENAMETOOLONG));
+        }
+
+        if name.len() > NAME_INLINE {
+            let rest = &name.as_bytes()[NAME_LONG_INLINE..];
+            let sectors = (rest.len() as u64 + 511) / 512;
+
+            let mut extent = node.name_extent;
+            if extent.empty() || extent.sectors() < sectors {
+                extent.block = try!(self.allocate(sectors));
+                try!(self.write_header());
+            }
+            extent.length = rest.len() as u64;
+
+            for i in 0..sectors {
+                try!(self.journal_block(extent.block + i));
+            }
+            try!(self.write_name(extent.block, rest));
+
+            node.name_extent = extent;
+        } else {
+            node.name_extent = Extent {
+                block: 0,
+                length: 0,
+            };
+        }
+
+        node.name = name.to_string();
+        Ok(())
+    }
+
+    /// Write the rest of a long name to the sectors at `block`, padded with zeros
+    fn write_name(&mut self, block: u64, rest: &[u8]) -> Result<()> {
+        let size = (rest.len() + 511) / 512 * 512;
+        let mut data = try!(self.pool.take(size));
+        let result = {
+            let buffer = data.get(size);
+            for (i, b) in buffer.iter_mut().enumerate() {
+                *b = if i < rest.len() {
+                    rest[i]
+                } else {
+                    0
+                };
+            }
+
+            self.disk.write(block, buffer)
+        };
+        self.pool.give(data);
+
+        result.and(Ok(()))
+    }
+
+    /// Rename a file or a directory, which may move it to another directory. A file replaces an
+    /// existing file of the destination name in the same transaction, so the destination name
+    /// always refers to one of the two files. Open resources of the replaced file keep their
+    /// contents, and its blocks become orphans. A directory is renamed by renaming every node
+    /// below it in one transaction, and since directories only exist while they have entries, a
+    /// directory can not replace one. Returns ENOENT if there is nothing to rename or the
+    /// destination directory does not exist, ENOTDIR if the destination directory is a file or a
+    /// directory would replace a file, EISDIR if a file would replace a directory, ENOTEMPTY if
+    /// a directory would replace a directory, EINVAL when moving a directory into itself or one
+    /// of its descendants, and E2BIG if a directory has more nodes below it than one transaction
+    /// can log, at most `JOURNAL_RECORDS`, in which case nothing is renamed
+    pub fn rename_node(&mut self, from: &str, to: &str) -> Result<()> {
+        let from = from.trim_matches('/');
+        let to = to.trim_matches('/');
+        if from.is_empty() || to.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        if let Some(index) = to.rfind('/') {
+            let parent = to.get_slice(..index);
+            if self.node(parent).is_some() {
+                return Err(Error::new(ENOTDIR));
+            }
+            if self.dir_empty(parent) {
+                return Err(Error::new(ENOENT));
+            }
+        }
+
+        let mut replaced = None;
+        let mut renames: Vec<(Node, String)> = Vec::new();
+        if let Some(node) = self.node(from) {
+            if ! self.dir_empty(to) {
+                return Err(Error::new(EISDIR));
+            }
+            if to == from {
+                return Ok(());
+            }
+
+            replaced = self.node(to);
+            renames.push((node, to.to_string()));
+        } else {
+            if to == from || (to.starts_with(from) && to.get_slice(from.len()..).starts_with('/')) {
+                return Err(Error::new(EINVAL));
+            }
+
+            for node in self.nodes.iter() {
+                if let Some(child) = child_path(&node.name, from) {
+                    let name = to.to_string() + "/" + child;
+                    renames.push((node.clone(), name));
+                }
+            }
+
+            if ! renames.is_empty() {
+                if self.node(to).is_some() {
+                    return Err(Error::new(ENOTDIR));
+                }
+                if ! self.dir_empty(to) {
+                    return Err(Error::new(ENOTEMPTY));
+                }
+            }
+        }
+
+        if renames.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        try!(self.begin_txn());
+        let mut result = Ok(());
+        if let Some(ref old) = replaced {
+            result = self.journal_block(old.block);
+            if result.is_ok() {
+                result = FileSystem::write_block(&mut self.disk, &mut self.pool, old.block, [0u8; 512]).and(Ok(()));
+            }
+        }
+        if result.is_ok() {
+            for &mut (ref mut node, ref name) in renames.iter_mut() {
+                result = self.set_name(node, name);
+                if result.is_ok() {
+                    result = self.write_node(node);
+                }
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+
+        match result {
+            Ok(()) => {
+                try!(self.commit_txn());
+                if let Some(old) = replaced {
+                    self.remove_node(old.block);
+                }
+                Ok(())
+            },
+            Err(err) => {
+                let _ = self.abort_txn();
+                Err(err)
+            },
+        }
+    }
+
+    /// Take a snapshot of the nodes, returning its id. Until it is dropped, data blocks the
+    /// snapshot references are copied on write instead of overwritten
+    pub fn snapshot(&mut self) -> usize {
+        let id = self.next_snapshot;
+        self.next_snapshot += 1;
+
+        self.snapshots.push(Snapshot {
+            id: id,
+            nodes: self.nodes.clone(),
+        });
+
+        id
+    }
+
+    /// Drop a snapshot. The blocks of its data extents that are no longer referenced, by another
+    /// snapshot, a node, or an open file, are released for reuse. Returns ENOENT if there is no
+    /// such snapshot
+    pub fn drop_snapshot(&mut self, id: usize) -> Result<()> {
+        let i = match self.snapshots.iter().position(|snapshot| snapshot.id == id) {
+            Some(i) => i,
+            None => return Err(Error::new(ENOENT)),
+        };
+        let snapshot = self.snapshots.remove(i);
+
+        // Copied extents are whole blocks, the old ones are released a block at a time, in runs
+        let per_block = self.per_block();
+        for node in snapshot.nodes.iter() {
+            for extent in node.extents.iter().filter(|extent| ! extent.empty()) {
+                let end = self.round_sectors(extent.block + extent.sectors());
+                let mut block = extent.block;
+                let mut run = None;
+                while block <= end {
+                    let free = block < end && (block..block + per_block).all(|sector| ! self.block_used(sector))
+                               && ! self.released.iter().any(|released| released.contains(block));
+                    if free {
+                        if run.is_none() {
+                            run = Some(block);
+                        }
+                    } else if let Some(first) = run.take() {
+                        try!(self.release(first, block - first));
+                    }
+                    block += per_block;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the number of snapshots that reference a block. Blocks are released once the last
+    /// snapshot referencing them is dropped, unless a node still uses them
+    pub fn block_refs(&self, block: u64) -> usize {
+        let extent = Extent {
+            block: block,
+            length: 512,
+        };
+        self.snapshots.iter().filter(|snapshot| snapshot.pins(&extent)).count()
+    }
+
+    /// Prepare a data extent to be overwritten. If a snapshot references any of its blocks, the
+    /// extent is moved to newly allocated blocks, so that the snapshot keeps the old data.
+    /// Returns true if the extent was moved, after which the node and header must be written
+    pub fn cow_extent(&mut self, extent: &mut Extent) -> Result<bool> {
+        if extent.empty() || ! self.snapshots.iter().any(|snapshot| snapshot.pins(extent)) {
+            return Ok(false);
+        }
+
+        let sectors = extent.sectors();
+        extent.block = try!(self.allocate(sectors));
+
+        Ok(true)
+    }
+
+    /// Read the contents of a file as it was when a snapshot was taken. Returns ENOENT if there
+    /// is no such snapshot or file
+    pub fn read_snapshot(&mut self, id: usize, name: &str) -> Result<Vec<u8>> {
+        let extents = match self.snapshots.iter().find(|snapshot| snapshot.id == id)
+                                .and_then(|snapshot| snapshot.node(name)) {
+            Some(node) => node.extents,
+            None => return Err(Error::new(ENOENT)),
+        };
+
+        let mut contents = Vec::new();
+        for extent in extents.iter() {
+            if extent.empty() {
+                continue;
+            }
+
+            let size = extent.sectors() as usize * 512;
+            let mut data = try!(self.pool.take(size));
+            {
+                let buffer = data.get(size);
+                if try!(self.disk.read(extent.block, buffer)) < size {
+                    return Err(Error::new(EIO));
+                }
+                contents.extend_from_slice(&buffer[..extent.length as usize]);
+            }
+            self.pool.give(data);
+        }
+
+        Ok(contents)
+    }
+
+    /// Read all of the data of a node into one buffer, decompressing it if the node is
+    /// compressed. Each extent is read in as few disk reads as the disk allows, no larger than
+    /// `max_transfer_sectors`. Returns EIO on a short read, if compressed data is malformed, or if
+    /// an extent of the node reaches past the end of the disk
+    pub fn read_node_all(&mut self, node: &Node) -> Result<Vec<u8>> {
+        if ! node.extents_valid(self.disk.size() / 512) {
+            debugln!("{}: Node {} has an extent past the end of the disk", self.disk.name(), node.block);
+            return Err(Error::new(EIO));
+        }
+
+        let max = cmp::max(self.disk.max_transfer_sectors(), 1) as u64;
+
+        let mut data = Vec::new();
+        for extent in node.extents.iter() {
+            if extent.empty() {
+                continue;
+            }
+
+            let pos = data.len();
+            let sectors = extent.sectors();
+            data.resize(pos + sectors as usize * 512, 0);
+
+            let mut sector = 0;
+            while sector < sectors {
+                let count = cmp::min(sectors - sector, max);
+                let start = pos + sector as usize * 512;
+                let end = start + count as usize * 512;
+                if try!(self.disk.read(extent.block + sector, &mut data[start..end])) < end - start {
+                    return Err(Error::new(EIO));
+                }
+                sector += count;
+            }
+
+            data.truncate(pos + extent.length as usize);
+        }
+
+        if node.compressed {
+            match rle::decompress(&data) {
+                Some(data) => Ok(data),
+                None => {
+                    debugln!("{}: Node {} has malformed compressed data", self.disk.name(), node.block);
+                    Err(Error::new(EIO))
+                }
+            }
+        } else {
+            Ok(data)
+        }
+    }
+
+    /// Get the length of the data of a node. For a compressed node this is the length after
+    /// decompressing, which is read from the start of its data
+    pub fn node_size(&mut self, node: &Node) -> Result<u64> {
+        if ! node.compressed {
+            return Ok(node.extents.iter().filter(|extent| ! extent.empty()).fold(0, |size, extent| size + extent.length));
+        }
+
+        if let Some(extent) = node.extents.iter().find(|extent| ! extent.empty()) {
+            let mut header = [0; 8];
+            if try!(self.read_extent(extent, &mut header)) == header.len() {
+                return rle::decompressed_len(&header).ok_or(Error::new(EIO));
+            }
+        }
+
+        // The header is split between extents
+        Ok(try!(self.read_node_all(node)).len() as u64)
+    }
+
+    /// Read the contents of one extent, up to the length of the extent or the buffer, whichever
+    /// is shorter, in a single disk read. Returns EINVAL if the extent is empty or reaches past
+    /// the end of the disk
+    pub fn read_extent(&mut self, extent: &Extent, buf: &mut [u8]) -> Result<usize> {
+        let size = self.disk.size();
+        if extent.empty() || (size > 0 && extent.block + extent.sectors() > size / 512) {
+            return Err(Error::new(EINVAL));
+        }
+
+        let len = cmp::min(buf.len() as u64, extent.length) as usize;
+        self.disk.read_at(extent.block * 512, &mut buf[..len])
+    }
+
+    /// Read the extended attributes of a node, legacy nodes have none
+    fn read_xattrs(&mut self, node: &Node) -> Result<Vec<Xattr>> {
+        if node.xattrs.empty() {
+            return Ok(Vec::new());
+        }
+
+        let data: [u8; 512] = try!(FileSystem::read_block(&mut self.disk, &mut self.pool, node.xattrs.block));
+        let size = cmp::min(node.xattrs.length as usize, XATTR_SIZE);
+        Ok(xattr::decode(&data[..size]))
+    }
+
+    /// Write the encoded extended attributes of a node, allocating a block for them if the node
+    /// has none yet. Must be called inside a transaction
+    fn write_xattrs(&mut self, node: &mut Node, bytes: &[u8]) -> Result<()> {
+        if node.xattrs.empty() {
+            node.xattrs.block = try!(self.allocate(1));
+            try!(self.write_header());
+        }
+        node.xattrs.length = bytes.len() as u64;
+
+        let mut data = [0; 512];
+        for (b, v) in data.iter_mut().zip(bytes.iter()) {
+            *b = *v;
+        }
+
+        try!(self.journal_block(node.xattrs.block));
+        try!(FileSystem::write_block(&mut self.disk, &mut self.pool, node.xattrs.block, data));
+
+        self.write_node(node)
+    }
+
+    /// Get the value of an extended attribute of a node
+    pub fn get_xattr(&mut self, node: &Node, key: &str) -> Result<Vec<u8>> {
+        for xattr in try!(self.read_xattrs(node)) {
+            if xattr.key == key {
+                return Ok(xattr.value);
+            }
+        }
+
+        Err(Error::new(ENODATA))
+    }
+
+    /// Set an extended attribute of a node, replacing any previous value. Returns E2BIG if the
+    /// attributes of the node would no longer fit in `XATTR_SIZE` bytes
+    pub fn set_xattr(&mut self, node: &mut Node, key: &str, value: &[u8]) -> Result<()> {
+        if node.block == 0 {
+            return Err(Error::new(ENOSPC));
+        }
+
+        let mut xattrs = try!(self.read_xattrs(node));
+
+        let mut found = false;
+        for xattr in xattrs.iter_mut() {
+            if xattr.key == key {
+                xattr.value = value.to_vec();
+                found = true;
+            }
+        }
+        if ! found {
+            xattrs.push(Xattr {
+                key: key.to_string(),
+                value: value.to_vec(),
+            });
+        }
+
+        let bytes = try!(xattr::encode(&xattrs));
+
+        try!(self.begin_txn());
+        if let Err(err) = self.write_xattrs(node, &bytes) {
+            let _ = self.abort_txn();
+            return Err(err);
+        }
+        self.commit_txn()
+    }
+
+    /// List the keys of the extended attributes of a node
+    pub fn list_xattr(&mut self, node: &Node) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for xattr in try!(self.read_xattrs(node)) {
+            keys.push(xattr.key);
+        }
+
+        Ok(keys)
+    }
+
+    /// Reserve space for a node to hold at least `bytes` bytes, so that a file whose size is
+    /// known up front is not fragmented. Only the missing `bytes` minus the space already
+    /// allocated is allocated, as one new extent that is zeroed so it reads back as zeros. The
+    /// existing extents are not moved, so a node that already has data ends up with another
+    /// extent. With `contiguous`, this fails with ENOSPC instead of leaving the node with more
+    /// than one extent. As the free space is a single extent, the new extent is always
+    /// contiguous, or fails with ENOSPC
+    pub fn reserve(&mut self, node: &mut Node, bytes: u64, contiguous: bool) -> Result<()> {
+        let mut allocated = 0;
+        let mut extents = 0;
+        for extent in node.extents.iter() {
+            if ! extent.empty() {
+                allocated += extent.length;
+                extents += 1;
+            }
+        }
+        if allocated >= bytes {
+            if contiguous && extents > 1 {
+                return Err(Error::new(ENOSPC));
+            }
+            return Ok(());
+        }
+        if contiguous && extents > 0 {
+            return Err(Error::new(ENOSPC));
+        }
+
+        let length = bytes - allocated;
+        let sectors = (length + 511) / 512;
+        let block = match self.find_free(sectors) {
+            Some(block) => block,
+            None => return Err(Error::new(ENOSPC)),
+        };
+
+        let slot = match node.extents.iter().position(|extent| extent.empty()) {
+            Some(slot) => slot,
+            None => return Err(Error::new(ENOSPC)),
+        };
+
+        // Begin first, so that nothing is written on a read only mount
+        try!(self.begin_txn());
+
+        if let Err(err) = self.disk.write_zeros(block, sectors) {
+            let _ = self.abort_txn();
+            return Err(err);
+        }
+
+        self.take_free(block, sectors);
+        node.extents[slot] = Extent {
+            block: block,
+            length: length,
+        };
+
+        let mut result = self.write_header();
+        if result.is_ok() && node.block > 0 {
+            result = self.write_node(node);
+        }
+        if let Err(err) = result {
+            let _ = self.abort_txn();
+            return Err(err);
+        }
+
+        self.commit_txn()
+    }
+
+    /// Get the number of bytes a node can hold, which is the space of its extents plus the free
+    /// space if it has an empty extent to allocate it with
+    pub fn capacity(&self, node: &Node) -> u64 {
+        let mut capacity = 0;
+        let mut slot = false;
+        for extent in node.extents.iter() {
+            if extent.empty() {
+                slot = true;
+            } else {
+                capacity += self.round_sectors(extent.sectors()) * 512;
+            }
+        }
+
+        if slot {
+            capacity += self.header.free_space.length;
+        }
+
+        capacity
+    }
+
+    /// Get node with a given filename
+    pub fn node(&self, filename: &str) -> Option<Node> {
+        for node in self.nodes.iter() {
+            if node.name == filename {
+                return Some(node.clone());
+            }
+        }
+
+        None
+    }
+
+    /// Iterate over every node, including those that `list` hides. Nodes are in the order of the
+    /// node table on disk, followed by the nodes created since mounting
+    pub fn nodes_iter(&self) -> slice::Iter<Node> {
+        self.nodes.iter()
+    }
+
+    /// Get the block holding the data of a node
+    pub fn node_block(&self, name: &str) -> Option<u64> {
+        self.nodes.iter().find(|node| node.name == name).map(|node| node.block)
+    }
+
+    /// Get the extents holding the contents of a node, in file order
+    pub fn node_extents(&self, name: &str) -> Option<Vec<Extent>> {
+        self.nodes.iter().find(|node| node.name == name).map(|node| {
+            node.extents.iter().filter(|extent| ! extent.empty()).map(|extent| *extent).collect()
+        })
+    }
+
+    /// Resolve a path one component at a time, checking that every intermediate component is a
+    /// directory
+    pub fn resolve(&self, path: &str) -> Result<Node> {
+        let mut prefix = String::new();
+        let mut components = path.split('/').filter(|component| ! component.is_empty()).peekable();
+        while let Some(component) = components.next() {
+            if ! prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(component);
+
+            if components.peek().is_some() {
+                if self.node(&prefix).is_some() {
+                    return Err(Error::new(ENOTDIR));
+                }
+                if self.list(&prefix).is_empty() {
+                    return Err(Error::new(ENOENT));
+                }
+            }
+        }
+
+        self.node(&prefix).ok_or(Error::new(ENOENT))
+    }
+
+    /// Get the node stored at a block, as returned by `node_block`. Blocks outside of the node
+    /// table never hold a node
+    pub fn open_node(&self, block: u64) -> Option<Node> {
+        if ! self.header.extents.iter().any(|extent| extent.contains(block)) {
+            return None;
+        }
+
+        self.nodes.iter().find(|node| node.block == block).cloned()
+    }
+
+    /// Count the entries of a directory, files and subdirectories, without building their names
+    pub fn count_dir(&self, directory: &str) -> usize {
+        let mut dirs: Vec<&str> = Vec::new();
+        let mut count = 0;
+
+        for node in self.nodes.iter() {
+            let child = match child_path(&node.name, directory) {
+                Some(child) => child,
+                None => continue,
+            };
+
+            match child.find('/') {
+                Some(index) => {
+                    let dirname = child.get_slice(..index);
+                    if ! dirs.contains(&dirname) {
+                        dirs.push(dirname);
+                        count += 1;
+                    }
+                },
+                None => count += 1,
+            }
+        }
+
+        count
+    }
+
+    /// List the names of the immediate subdirectories of a directory, without trailing slashes.
+    /// A directory holding only files has none
+    pub fn list_subdirs(&self, directory: &str) -> Vec<String> {
+        let mut dirs: Vec<String> = Vec::new();
+
+        for node in self.nodes.iter() {
+            let child = match child_path(&node.name, directory) {
+                Some(child) => child,
+                None => continue,
+            };
+
+            if let Some(index) = child.find('/') {
+                let dirname = child.get_slice(..index);
+                if ! dirs.iter().any(|dir| dir == dirname) {
+                    dirs.push(dirname.to_string());
+                }
+            }
+        }
+
+        dirs
+    }
+
+    /// List the paths of the nodes below a directory, relative to it
+    pub fn list(&self, directory: &str) -> Vec<String> {
+        let mut ret = Vec::new();
+
+        for node in self.nodes.iter() {
+            if let Some(child) = child_path(&node.name, directory) {
+                ret.push(child.to_string());
+            }
+        }
+
+        ret
+    }
+}
+
+impl Drop for FileSystem {
+    /// Flush the disk if the file system was never unmounted, so that cached writes are not lost.
+    /// The header is left marked as mounted, so the next mount still knows to check it
+    fn drop(&mut self) {
+        if self.header.state == STATE_MOUNTED && ! self.read_only {
+            debugln!("{}: Dropped without unmounting, flushing", self.disk.name());
+            if let Err(err) = self.disk.flush() {
+                debugln!("{}: Failed to flush: {}", self.disk.name(), err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use collections::string::{String, ToString};
+    use collections::vec::Vec;
+
+    use disk::{Disk, DiskStatus};
+    use disk::crypt::{CryptDisk, XorCipher};
+    use disk::ide::Extent;
+
+    use alloc::boxed::Box;
+
+    use system::error::{Error, Result, E2BIG, EBUSY, EEXIST, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, EROFS};
+
+    use core::{mem, ptr};
+    use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    use super::endian::LittleEndian;
+    use super::{mkfs_at, BufferPool, DirCounts, FileSystem, Header, Node, NodeData, NodeLocks, BAD_BLOCKS, HEADER_BLOCK, HEADER_BACKUP_BLOCK, JOURNAL_RECORDS, POOL_BUFFERS, POOL_BUFFER_MAX, STATE_CLEAN, STATE_MOUNTED, STATE_UNKNOWN};
+
+    /// Sectors written back by `PendingDisk::flush`
+    static FLUSHED: AtomicUsize = ATOMIC_USIZE_INIT;
+    /// The state of the last primary header written to a `HeaderDisk`
+    static HEADER_STATE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// A disk in memory
+    pub struct MemDisk {
+        pub data: Vec<u8>,
+    }
+
+    impl Disk for MemDisk {
+        fn name(&self) -> String {
+            "Memory".to_string()
+        }
+
+        fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+            let start = block as usize * 512;
+            for (b, d) in buffer.iter_mut().zip(self.data[start..].iter()) {
+                *b = *d;
+            }
+            Ok(buffer.len())
+        }
+
+        fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+            let start = block as usize * 512;
+            for (d, b) in self.data[start..].iter_mut().zip(buffer.iter()) {
+                *d = *b;
+            }
+            Ok(buffer.len())
+        }
+
+        fn size(&self) -> u64 {
+            self.data.len() as u64
+        }
+    }
+
+    /// A disk in memory that fails to read some blocks
+    struct BadDisk {
+        data: Vec<u8>,
+        bad: Vec<u64>,
+    }
+
+    impl Disk for BadDisk {
+        fn name(&self) -> String {
+            "Bad".to_string()
+        }
+
+        fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+            let sectors = (buffer.len() as u64 + 511) / 512;
+            if (block..block + sectors).any(|block| self.bad.contains(&block)) {
+                return Err(Error::new(EIO));
+            }
+
+            let start = block as usize * 512;
+            for (b, d) in buffer.iter_mut().zip(self.data[start..].iter()) {
+                *b = *d;
+            }
+            Ok(buffer.len())
+        }
+
+        fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+            let start = block as usize * 512;
+            for (d, b) in self.data[start..].iter_mut().zip(buffer.iter()) {
+                *d = *b;
+            }
+            Ok(buffer.len())
+        }
+
+        fn size(&self) -> u64 {
+            self.data.len() as u64
+        }
+    }
+
+    /// A disk in memory that records the state of each primary header written to it, which
+    /// outlives the file system that owns the disk
+    struct HeaderDisk {
+        inner: MemDisk,
+    }
+
+    impl Disk for HeaderDisk {
+        fn name(&self) -> String {
+            "Header".to_string()
+        }
+
+        fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+            self.inner.read(block, buffer)
+        }
+
+        fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+            if block == HEADER_BLOCK {
+                let header = unsafe { ptr::read(buffer.as_ptr() as *const Header) }.from_le();
+                HEADER_STATE.store(header.state as usize, Ordering::SeqCst);
+            }
+            self.inner.write(block, buffer)
+        }
+
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+    }
+
+    /// A disk that holds writes until it is flushed
+    struct PendingDisk {
+        pending: usize,
+    }
+
+    impl Disk for PendingDisk {
+        fn name(&self) -> String {
+            "Pending".to_string()
+        }
+
+        fn read(&mut self, _block: u64, buffer: &mut [u8]) -> Result<usize> {
+            Ok(buffer.len())
+        }
+
+        fn write(&mut self, _block: u64, buffer: &[u8]) -> Result<usize> {
+            self.pending += buffer.len() / 512;
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            FLUSHED.fetch_add(self.pending, Ordering::SeqCst);
+            self.pending = 0;
+            Ok(())
+        }
+    }
+
+    /// Take the disk of a file system to mount it again, as after a crash
+    fn take_disk(fs: &mut FileSystem) -> Box<Disk> {
+        mem::replace(&mut fs.disk, box MemDisk { data: Vec::new() })
+    }
+
+    fn empty() -> Extent {
+        Extent { block: 0, length: 0 }
+    }
+
+    /// A file system on a memory disk, with all but the first 4 sectors free
+    pub fn fs(sectors: u64) -> FileSystem {
+        FileSystem {
+            disk: box MemDisk { data: vec![0xFF; sectors as usize * 512] },
+            header: Header {
+                signature: *b"REDOXFS\0",
+                version: 1,
+                free_space: Extent { block: 4, length: (sectors - 4) * 512 },
+                journal: empty(),
+                label: [0; 64],
+                checksum: 0,
+                created: 0,
+                mounted: 0,
+                mount_count: 0,
+                state: 0,
+                bad_blocks: [0; BAD_BLOCKS],
+                padding: [0; 16],
+                extents: [empty(); 16],
+            },
+            nodes: Vec::new(),
+            dirs: DirCounts::new(),
+            locks: NodeLocks::new(),
+            txn: None,
+            snapshots: Vec::new(),
+            next_snapshot: 0,
+            released: Vec::new(),
+            state_at_mount: STATE_UNKNOWN,
+            needs_check: false,
+            unnamed: Vec::new(),
+            open: Vec::new(),
+            pool: BufferPool::new(POOL_BUFFERS),
+            read_only: false,
+            block_size: 512,
+        }
+    }
+
+    pub fn node() -> Node {
+        Node {
+            block: 3,
+            name: "file".to_string(),
+            name_extent: empty(),
+            xattrs: empty(),
+            extents: [empty(); 16],
+            compressed: false,
+        }
+    }
+
+    #[test]
+    fn reserve_read_only() {
+        let mut fs = fs(64);
+        fs.disk = box ProtectedDisk { data: vec![0xFF; 64 * 512] };
+        fs.read_only = true;
+
+        // Zeroing would panic
+        let mut node = node();
+        assert_eq!(fs.reserve(&mut node, 5000, false).unwrap_err().errno, EROFS);
+        assert!(node.extents[0].empty());
+        assert_eq!(fs.header.free_space.block, 4);
+    }
+
+    #[test]
+    fn reserve_contiguous() {
+        let mut fs = fs(64);
+        let mut node = node();
+        fs.reserve(&mut node, 5000, true).unwrap();
+
+        let extents: Vec<&Extent> = node.extents.iter().filter(|extent| ! extent.empty()).collect();
+        assert_eq!(extents.len(), 1);
+        assert_eq!(extents[0].block, 4);
+        assert_eq!(extents[0].length, 5000);
+        assert_eq!(fs.header.free_space.block, 14);
+
+        let data = fs.read_node_all(&node).unwrap();
+        assert_eq!(data.len(), 5000);
+        assert!(data.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn reclaim_orphans() {
+        let mut fs = fs(64);
+        for i in 0..2 {
+            let block = fs.allocate(8).unwrap();
+            let mut node = node();
+            node.block = 2 + i;
+            node.name = i.to_string();
+            node.extents[0] = Extent { block: block, length: 512 * 8 };
+            fs.add_node(node);
+        }
+        assert_eq!(fs.header.free_space.block, 20);
+
+        // The used blocks of the second node keep the first node's blocks from the free space
+        fs.remove_node(2).unwrap();
+        assert_eq!(fs.reclaim_orphans().unwrap(), 0);
+        assert_eq!(fs.header.free_space.block, 20);
+
+        fs.remove_node(3).unwrap();
+        assert_eq!(fs.reclaim_orphans().unwrap(), 16);
+        assert_eq!(fs.header.free_space.block, 4);
+        assert_eq!(fs.header.free_space.length, 512 * 60);
+
+        fs.read_only = true;
+        assert_eq!(fs.reclaim_orphans().unwrap_err().errno, EROFS);
+    }
+
+    #[test]
+    fn reclaim_open() {
+        let mut fs = fs(64);
+        let block = fs.allocate(8).unwrap();
+        let mut node = node();
+        node.extents[0] = Extent { block: block, length: 512 * 8 };
+        fs.add_node(node.clone());
+        fs.open_node(&node);
+
+        // An unlinked file keeps its blocks while it is open
+        fs.remove_node(3).unwrap();
+        assert_eq!(fs.reclaim_orphans().unwrap(), 0);
+
+        // The handle grew the file
+        let old = node.clone();
+        node.extents[1] = Extent { block: fs.allocate(4).unwrap(), length: 512 * 4 };
+        fs.update_open(&old, &node);
+        assert_eq!(fs.reclaim_orphans().unwrap(), 0);
+
+        fs.close_node(&node);
+        assert!(fs.open.is_empty());
+        // The slot of the node is not in a node table extent, so it is reclaimed too
+        assert_eq!(fs.reclaim_orphans().unwrap(), 13);
+    }
+
+    #[test]
+    fn drop_snapshot_releases() {
+        let mut fs = fs(64);
+        for i in 0..2 {
+            let block = fs.allocate(4).unwrap();
+            let mut node = node();
+            node.block = 2 + i;
+            node.name = i.to_string();
+            node.extents[0] = Extent { block: block, length: 512 * 4 };
+            fs.add_node(node);
+        }
+        let first = fs.snapshot();
+        let second = fs.snapshot();
+
+        // Copy the first file, which is not next to the free space
+        let mut extent = fs.nodes[0].extents[0];
+        assert!(fs.cow_extent(&mut extent).unwrap());
+        assert_eq!(extent.block, 12);
+        fs.nodes[0].extents[0] = extent;
+
+        // The other snapshot still references the old blocks
+        fs.drop_snapshot(first).unwrap();
+        assert!(fs.released.is_empty());
+        assert_eq!(fs.allocate(4).unwrap(), 16);
+
+        fs.drop_snapshot(second).unwrap();
+        assert_eq!(fs.released.len(), 1);
+        assert_eq!(fs.allocate(2).unwrap(), 4);
+        assert_eq!(fs.allocate(2).unwrap(), 6);
+        assert!(fs.released.is_empty());
+        assert_eq!(fs.allocate(4).unwrap(), 20);
+        assert_eq!(fs.drop_snapshot(second).unwrap_err().errno, ENOENT);
+    }
+
+    #[test]
+    fn drop_snapshot_joins_free_space() {
+        let mut fs = fs(64);
+        let block = fs.allocate(4).unwrap();
+        let mut node = node();
+        node.extents[0] = Extent { block: block, length: 512 * 4 };
+        fs.add_node(node);
+        let id = fs.snapshot();
+
+        // The file is unlinked, so only the snapshot keeps the blocks before the free space
+        fs.remove_node(3).unwrap();
+        fs.drop_snapshot(id).unwrap();
+        assert!(fs.released.is_empty());
+        assert_eq!(fs.header.free_space.block, 4);
+        assert_eq!(fs.header.free_space.length, 512 * 60);
+    }
+
+    #[test]
+    fn collect_orphans() {
+        let mut fs = fs(64);
+        for i in 0..3 {
+            let block = fs.allocate(4).unwrap();
+            let mut node = node();
+            node.block = 2 + i;
+            node.name = i.to_string();
+            node.extents[0] = Extent { block: block, length: 512 * 4 };
+            fs.add_node(node);
+        }
+        // The blocks and slot of the middle file leak, as the last file is after them
+        fs.remove_node(3).unwrap();
+        assert_eq!(fs.reclaim_orphans().unwrap(), 0);
+
+        assert_eq!(fs.collect_orphans(), 5);
+        assert_eq!(fs.released.len(), 2);
+        assert_eq!(fs.collect_orphans(), 0);
+        assert_eq!(fs.allocate(4).unwrap(), 8);
+        assert_eq!(fs.header.free_space.block, 16);
+    }
+
+    #[test]
+    fn free_extents() {
+        let mut fs = fs(64);
+        assert_eq!(fs.free_extents().len(), 1);
+
+        // Free the second of two allocations, which is orphaned next to the free space
+        assert_eq!(fs.allocate(8).unwrap(), 4);
+        let mut node = node();
+        node.extents[0] = Extent { block: 4, length: 512 * 8 };
+        fs.add_node(node);
+        assert_eq!(fs.allocate(8).unwrap(), 12);
+        assert_eq!(fs.reclaim_orphans().unwrap(), 8);
+
+        let extents = fs.free_extents();
+        assert_eq!(extents.len(), 1);
+        assert_eq!(extents[0].block, 12);
+        assert_eq!(extents[0].length, 512 * 52);
+
+        // Bad blocks split the free space
+        fs.header.add_bad_block(20);
+        fs.header.add_bad_block(21);
+        fs.header.add_bad_block(63);
+        let extents = fs.free_extents();
+        assert_eq!(extents.len(), 2);
+        assert_eq!((extents[0].block, extents[0].length), (12, 512 * 8));
+        assert_eq!((extents[1].block, extents[1].length), (22, 512 * 41));
+
+        fs.take_free(22, 42);
+        assert!(fs.free_extents().is_empty());
+    }
+
+    #[test]
+    fn reserve_after_data() {
+        let mut fs = fs(64);
+        let mut node = node();
+        fs.reserve(&mut node, 1000, false).unwrap();
+
+        // Only the missing space is allocated, as a second extent
+        assert_eq!(fs.reserve(&mut node, 3000, true).unwrap_err().errno, ENOSPC);
+        fs.reserve(&mut node, 3000, false).unwrap();
+        assert_eq!((node.extents[0].block, node.extents[0].length), (4, 1000));
+        assert_eq!((node.extents[1].block, node.extents[1].length), (6, 2000));
+
+        // Enough space is already allocated, but not as one extent
+        fs.reserve(&mut node, 3000, false).unwrap();
+        assert_eq!(fs.reserve(&mut node, 3000, true).unwrap_err().errno, ENOSPC);
+    }
+
+    #[test]
+    fn reserve_no_space() {
+        let mut fs = fs(8);
+        let mut node = node();
+        assert_eq!(fs.reserve(&mut node, 4096, false).unwrap_err().errno, ENOSPC);
+        assert!(node.extents.iter().all(|extent| extent.empty()));
+    }
+
+    #[test]
+    fn compact_node_table() {
+        let mut fs = fs(64);
+        fs.header.extents[0] = Extent { block: 4, length: 512 * 4 };
+        fs.header.free_space = Extent { block: 8, length: 512 * 56 };
+
+        for (i, name) in ["a", "b", "c", "d"].iter().enumerate() {
+            let mut node = node();
+            node.block = 4 + i as u64;
+            node.name = name.to_string();
+            fs.write_node(&node).unwrap();
+            fs.add_node(node);
+        }
+        fs.nodes.retain(|node| node.name != "b" && node.name != "c");
+
+        fs.compact_node_table().unwrap();
+        assert_eq!(fs.header.extents[0].length, 512 * 2);
+        assert_eq!(fs.node("d").unwrap().block, 5);
+
+        let nodes = FileSystem::read_nodes(&mut fs.disk, &mut fs.pool, &fs.header, 1).unwrap();
+        let names: Vec<&str> = nodes.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "d"]);
+    }
+
+    #[test]
+    fn create_nodes() {
+        let mut fs = fs(64);
+        fs.header.extents[0] = Extent { block: 4, length: 512 * 4 };
+        fs.header.free_space = Extent { block: 8, length: 512 * 56 };
+
+        let mut node = node();
+        node.block = 5;
+        node.name = "a".to_string();
+        fs.write_node(&node).unwrap();
+        fs.add_node(node);
+
+        let long: String = (0..300).map(|_| 'l').collect();
+        let nodes = fs.create_nodes(&["b", "c", long.as_str()]).unwrap();
+        let blocks: Vec<u64> = nodes.iter().map(|node| node.block).collect();
+        assert_eq!(blocks, vec![4, 6, 7]);
+        assert_eq!(fs.node(&long).unwrap().name_extent.block, 8);
+        assert_eq!(fs.header.free_space.block, 9);
+
+        fs.reload().unwrap();
+        let names: Vec<&str> = fs.nodes.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, vec!["b", "a", "c", long.as_str()]);
+
+        assert_eq!(fs.create_nodes(&["a"]).unwrap_err().errno, EEXIST);
+        assert_eq!(fs.create_nodes(&["d"]).unwrap_err().errno, ENOSPC);
+    }
+
+    #[test]
+    fn rename_journal_limit() {
+        let mut disk: Box<Disk> = box MemDisk { data: vec![0xFF; 4096 * 512] };
+        mkfs_at(&mut disk, "test", 1000).unwrap();
+        let mut fs = FileSystem::mount(disk, 512, false, 2000).unwrap();
+
+        // Each renamed node logs its slot, and the journal holds as many
+        let names: Vec<String> = (0..JOURNAL_RECORDS).map(|i| format!("d/{}", i)).collect();
+        let refs: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+        fs.create_nodes(&refs).unwrap();
+        fs.rename_node("d", "e").unwrap();
+        assert!(fs.dir_empty("d"));
+        assert_eq!(fs.dirs.count("e"), JOURNAL_RECORDS);
+
+        // One more node is past the limit, and nothing is renamed
+        fs.create_nodes(&["e/last"]).unwrap();
+        assert_eq!(fs.rename_node("e", "f").unwrap_err().errno, E2BIG);
+        assert!(fs.txn.is_none());
+        assert!(fs.dir_empty("f"));
+        assert_eq!(fs.dirs.count("e"), JOURNAL_RECORDS + 1);
+        fs.reload().unwrap();
+        assert!(fs.nodes.iter().all(|node| node.name.starts_with("e/")));
+    }
+
+    #[test]
+    fn create_nodes_rolled_back() {
+        let mut fs = fs(16);
+        fs.header.extents[0] = Extent { block: 4, length: 512 * 4 };
+        fs.header.free_space = Extent { block: 8, length: 512 };
+
+        assert_eq!(fs.create_nodes(&["x", "x"]).unwrap_err().errno, EEXIST);
+
+        // The second long name finds no free space after the first one took it
+        let first: String = (0..300).map(|_| 'a').collect();
+        let second: String = (0..300).map(|_| 'b').collect();
+        assert_eq!(fs.create_nodes(&["x", first.as_str(), second.as_str()]).unwrap_err().errno, ENOSPC);
+        assert!(fs.nodes.is_empty());
+        assert_eq!(fs.header.free_space.block, 8);
+        assert!(fs.txn.is_none());
+
+        let nodes = FileSystem::read_nodes(&mut fs.disk, &mut fs.pool, &fs.header, 1).unwrap();
+        assert!(nodes.is_empty());
+    }
+
+    #[test]
+    fn mkfs_mount() {
+        let mut disk: Box<Disk> = box MemDisk { data: vec![0xFF; 2048 * 512] };
+        mkfs_at(&mut disk, "test", 1000).unwrap();
+
+        let fs = FileSystem::mount(disk, 512, false, 2000).unwrap();
+        assert_eq!(fs.header.label(), "test");
+        assert!(fs.nodes.is_empty());
+        assert_eq!(fs.header.extents[0].length, 32 * 512);
+        assert_eq!(fs.header.free_space.block + fs.header.free_space.length / 512, 2048);
+    }
+
+    #[test]
+    fn mkfs_too_small() {
+        let mut disk: Box<Disk> = box MemDisk { data: vec![0; 64 * 512] };
+        assert_eq!(mkfs_at(&mut disk, "test", 1000).unwrap_err().errno, ENOSPC);
+    }
+
+    #[test]
+    fn mount_stats() {
+        let mut disk: Box<Disk> = box MemDisk { data: vec![0xFF; 2048 * 512] };
+        mkfs_at(&mut disk, "test", 1000).unwrap();
+
+        let mut fs = FileSystem::mount(disk, 512, false, 2000).unwrap();
+        let stats = fs.stats();
+        assert_eq!(stats.created, Some(1000));
+        assert_eq!(stats.last_mounted, Some(2000));
+        assert_eq!(stats.mount_count, Some(1));
+        assert_eq!(stats.clean, Some(true));
+
+        // Mounting again without unmounting
+        let mut fs = FileSystem::mount(take_disk(&mut fs), 512, false, 3000).unwrap();
+        let stats = fs.stats();
+        assert_eq!(stats.last_mounted, Some(3000));
+        assert_eq!(stats.mount_count, Some(2));
+        assert_eq!(stats.clean, Some(false));
+
+        fs.mark_clean().unwrap();
+        let header: Header = FileSystem::read_block(&mut fs.disk, &mut fs.pool, HEADER_BLOCK).unwrap();
+        assert_eq!(header.state, STATE_CLEAN);
+        let backup: Header = FileSystem::read_block(&mut fs.disk, &mut fs.pool, HEADER_BACKUP_BLOCK).unwrap();
+        assert_eq!(backup.state, STATE_CLEAN);
+
+        let fs = FileSystem::mount(take_disk(&mut fs), 512, false, 4000).unwrap();
+        assert_eq!(fs.stats().mount_count, Some(3));
+        assert_eq!(fs.stats().clean, Some(true));
+        assert!(! fs.stats().fsck_recommended());
+    }
+
+    #[test]
+    fn mount_backup_header() {
+        let mut disk: Box<Disk> = box MemDisk { data: vec![0xFF; 2048 * 512] };
+        mkfs_at(&mut disk, "test", 1000).unwrap();
+        disk.write(HEADER_BLOCK, &[0; 512]).unwrap();
+
+        let mut fs = FileSystem::mount(disk, 512, false, 2000).unwrap();
+        assert_eq!(fs.header.label(), "test");
+        assert_eq!(fs.header.mounted, 2000);
+
+        // The primary header is restored from the backup
+        let header: Header = FileSystem::read_block(&mut fs.disk, &mut fs.pool, HEADER_BLOCK).unwrap();
+        assert!(header.valid());
+        assert_eq!(header.label(), "test");
+    }
+
+    #[test]
+    fn corrupt_node() {
+        let mut disk: Box<Disk> = box MemDisk { data: vec![0xFF; 2048 * 512] };
+        mkfs_at(&mut disk, "test", 1000).unwrap();
+
+        let mut fs = FileSystem::mount(disk, 512, false, 2000).unwrap();
+        let nodes = fs.create_nodes(&["a", "b", "c"]).unwrap();
+        fs.disk.write(nodes[1].block, &[0x55; 512]).unwrap();
+
+        let nodes = FileSystem::read_nodes(&mut fs.disk, &mut fs.pool, &fs.header, 1).unwrap();
+        assert_eq!(nodes.iter().map(|node| node.name.as_str()).collect::<Vec<_>>(), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn unmount_clean() {
+        let mut disk: Box<Disk> = box MemDisk { data: vec![0xFF; 2048 * 512] };
+        mkfs_at(&mut disk, "test", 1000).unwrap();
+        let mut data = vec![0; 2048 * 512];
+        disk.read(0, &mut data).unwrap();
+
+        let fs = FileSystem::mount(box HeaderDisk { inner: MemDisk { data: data } }, 512, false, 2000).unwrap();
+        assert_eq!(HEADER_STATE.load(Ordering::SeqCst), STATE_MOUNTED as usize);
+
+        fs.unmount().unwrap();
+        assert_eq!(HEADER_STATE.load(Ordering::SeqCst), STATE_CLEAN as usize);
+    }
+
+    #[test]
+    fn mount_encrypted() {
+        let mut disk: Box<Disk> = box CryptDisk::new(box MemDisk { data: vec![0xFF; 2048 * 512] }, box XorCipher::new(b"key"));
+        mkfs_at(&mut disk, "test", 1000).unwrap();
+
+        let mut fs = FileSystem::mount(disk, 512, true, 2000).unwrap();
+        assert_eq!(fs.header.label[..4], b"test"[..]);
+        fs.create_nodes(&["a"]).unwrap();
+
+        let fs = FileSystem::mount(take_disk(&mut fs), 512, true, 3000).unwrap();
+        assert_eq!(fs.header.mount_count, 2);
+        assert!(fs.node("a").is_some());
+    }
+
+    #[test]
+    fn mount_write_protected() {
+        let mut disk: Box<Disk> = box MemDisk { data: vec![0xFF; 2048 * 512] };
+        mkfs_at(&mut disk, "test", 1000).unwrap();
+        let mut data = vec![0; 2048 * 512];
+        disk.read(0, &mut data).unwrap();
+
+        // Writing would panic
+        let mut fs = FileSystem::mount(box ProtectedDisk { data: data }, 512, false, 2000).unwrap();
+        assert!(fs.read_only);
+        assert_eq!(fs.header.mounted, 0);
+        assert_eq!(fs.header.state, STATE_CLEAN);
+        assert_eq!(fs.stats().clean, Some(true));
+
+        assert_eq!(fs.scrub_step(5).unwrap(), 5);
+        assert_eq!(fs.begin_txn().unwrap_err().errno, EROFS);
+        assert_eq!(fs.create_nodes(&["a"]).unwrap_err().errno, EROFS);
+        fs.unmount().unwrap();
+    }
+
+    #[test]
+    fn mount_write_protected_incomplete_txn() {
+        let mut disk: Box<Disk> = box MemDisk { data: vec![0xFF; 2048 * 512] };
+        mkfs_at(&mut disk, "test", 1000).unwrap();
+
+        // Stop in the middle of a transaction, as a crash would
+        let mut fs = FileSystem::mount(disk, 512, false, 2000).unwrap();
+        fs.begin_txn().unwrap();
+        fs.header.label[0] = b'x';
+        fs.write_header().unwrap();
+        let mut data = vec![0; 2048 * 512];
+        fs.disk.read(0, &mut data).unwrap();
+
+        // Rolling back would write, which panics
+        assert_eq!(FileSystem::mount(box ProtectedDisk { data: data.clone() }, 512, false, 3000).unwrap_err().errno, EROFS);
+
+        let fs = FileSystem::mount(box MemDisk { data: data }, 512, false, 3000).unwrap();
+        assert_eq!(fs.header.label[0], b't');
+    }
+
+    #[test]
+    fn mount_checked() {
+        let mut disk: Box<Disk> = box MemDisk { data: vec![0xFF; 2048 * 512] };
+        mkfs_at(&mut disk, "test", 1000).unwrap();
+
+        // Leak blocks and stop without unmounting, as a crash would
+        let mut fs = FileSystem::mount(disk, 512, false, 2000).unwrap();
+        assert!(! fs.needs_check);
+        let free = fs.header.free_space;
+        fs.header.free_space.block += 4;
+        fs.header.free_space.length -= 4 * 512;
+        fs.write_header().unwrap();
+
+        let mut fs = FileSystem::mount_checked(take_disk(&mut fs), false, 3000).unwrap();
+        assert!(fs.needs_check);
+        assert!(fs.check().is_empty());
+        assert_eq!(fs.header.free_space.block, free.block + 4);
+
+        let fs = FileSystem::mount_checked(take_disk(&mut fs), true, 4000).unwrap();
+        assert!(! fs.needs_check);
+        assert_eq!(fs.header.free_space.block, free.block);
+        assert_eq!(fs.header.free_space.length, free.length);
+    }
+
+    #[test]
+    fn check_shared_extent() {
+        let mut fs = fs(64);
+        fs.header.free_space = Extent { block: 32, length: 512 * 32 };
+        for (i, name) in ["a", "b"].iter().enumerate() {
+            let mut node = node();
+            node.block = 4 + i as u64;
+            node.name = name.to_string();
+            node.extents[0] = Extent { block: 8, length: 1024 };
+            fs.add_node(node);
+        }
+        fs.nodes[1].extents[1] = Extent { block: 33, length: 512 };
+
+        assert_eq!(fs.check().len(), 2);
+    }
+
+    #[test]
+    fn dump_layout() {
+        let mut fs = fs(64);
+        fs.header.extents[0] = Extent { block: 4, length: 512 * 4 };
+        fs.header.free_space = Extent { block: 32, length: 512 * 32 };
+        fs.header.add_bad_block(40);
+
+        let mut node = node();
+        node.block = 4;
+        node.extents[0] = Extent { block: 8, length: 1024 };
+        node.extents[1] = Extent { block: 12, length: 100 };
+        fs.add_node(node);
+
+        let dump = fs.dump_layout();
+        assert!(dump.contains("Node table 0: block 4, 4 slots\n"));
+        assert!(dump.contains("Free space: block 32, 32 sectors\n"));
+        assert!(dump.contains("Bad blocks: 40\n"));
+        assert!(dump.contains("  file at block 4\n    data: block 8, 1024 bytes\n    data: block 12, 100 bytes\n"));
+        assert!(dump.contains("Fragmented nodes: 1\n"));
+    }
+
+    #[test]
+    fn legacy_stats() {
+        let fs = fs(16);
+        let stats = fs.stats();
+        assert_eq!(stats.created, None);
+        assert_eq!(stats.mount_count, None);
+        assert_eq!(stats.clean, None);
+    }
+
+    #[test]
+    fn node_layout() {
+        let mut fs = fs(64);
+        let mut node = node();
+        fs.reserve(&mut node, 1000, false).unwrap();
+        fs.add_node(node);
+
+        assert_eq!(fs.node_block("file"), Some(3));
+        let extents = fs.node_extents("file").unwrap();
+        assert_eq!(extents.len(), 1);
+        assert_eq!((extents[0].block, extents[0].length), (4, 1000));
+
+        assert_eq!(fs.node_block("missing"), None);
+        assert!(fs.node_extents("missing").is_none());
+    }
+
+    #[test]
+    fn long_name() {
+        let mut fs = fs(64);
+        fs.header.extents[0] = Extent { block: 3, length: 512 };
+
+        let name: String = (0..300).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        let mut node = node();
+        fs.set_name(&mut node, &name).unwrap();
+        assert_eq!(node.name_extent.block, 4);
+        fs.write_node(&node).unwrap();
+
+        let nodes = FileSystem::read_nodes(&mut fs.disk, &mut fs.pool, &fs.header, 1).unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].name, name);
+
+        let name: String = (0..5000).map(|_| 'a').collect();
+        assert_eq!(fs.set_name(&mut node, &name).unwrap_err().errno, ENAMETOOLONG);
+    }
+
+    /// A memory disk that can transfer at most two sectors at a time, and counts its writes
+    struct SmallDisk {
+        data: Vec<u8>,
+        writes: usize,
+    }
+
+    impl Disk for SmallDisk {
+        fn name(&self) -> String {
+            "Small".to_string()
+        }
+
+        fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+            assert!(buffer.len() <= 2 * 512);
+
+            let start = block as usize * 512;
+            for (b, d) in buffer.iter_mut().zip(self.data[start..].iter()) {
+                *b = *d;
+            }
+            Ok(buffer.len())
+        }
+
+        fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+            assert!(buffer.len() <= 2 * 512);
+            self.writes += 1;
+
+            let start = block as usize * 512;
+            for (d, b) in self.data[start..].iter_mut().zip(buffer.iter()) {
+                *d = *b;
+            }
+            Ok(buffer.len())
+        }
+
+        fn max_transfer_sectors(&self) -> usize {
+            2
+        }
+    }
+
+    /// A disk in memory that refuses writes
+    struct ProtectedDisk {
+        data: Vec<u8>,
+    }
+
+    impl Disk for ProtectedDisk {
+        fn name(&self) -> String {
+            "Protected".to_string()
+        }
+
+        fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+            let start = block as usize * 512;
+            buffer.clone_from_slice(&self.data[start..start + buffer.len()]);
+            Ok(buffer.len())
+        }
+
+        fn write(&mut self, _block: u64, _buffer: &[u8]) -> Result<usize> {
+            panic!("write to a write protected disk");
+        }
+
+        fn status(&mut self) -> DiskStatus {
+            DiskStatus {
+                online: true,
+                write_protected: true,
+                error_count: 0,
+            }
+        }
+    }
+
+    #[test]
+    fn scrub_step() {
+        let mut data = vec![0; 64 * 512];
+        for b in data[3 * 512..4 * 512].iter_mut() {
+            *b = 0xAB;
+        }
+        for (i, b) in data[8 * 512..12 * 512].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        let mut fs = fs(64);
+        fs.disk = box BadDisk { data: data, bad: vec![10] };
+        fs.header.extents[0] = Extent { block: 3, length: 512 };
+        fs.header.free_space = Extent { block: 32, length: 32 * 512 };
+        let mut node = node();
+        node.extents[0] = Extent { block: 8, length: 4 * 512 };
+        fs.add_node(node);
+        fs.write_header().unwrap();
+
+        assert_eq!(fs.scrub_step(0).unwrap(), 0);
+        assert!(fs.header.is_bad_block(3));
+        assert!(fs.header.is_bad_block(10));
+        assert!(! fs.header.is_bad_block(9));
+
+        // Good sectors are written back unchanged
+        let mut buf = [0; 1024];
+        fs.disk.read(8, &mut buf).unwrap();
+        assert_eq!(buf[513], 1);
+
+        // As mounted from a write protected disk
+        fs.disk = box ProtectedDisk { data: vec![0; 64 * 512] };
+        fs.read_only = true;
+        assert_eq!(fs.scrub_step(5).unwrap(), 5);
+    }
+
+    #[test]
+    fn read_node_all() {
+        let data: Vec<u8> = (0..16 * 512).map(|i| (i / 512) as u8).collect();
+        let mut fs = fs(16);
+        fs.disk = box SmallDisk { data: data, writes: 0 };
+
+        let mut node = node();
+        node.extents[0] = Extent { block: 4, length: 512 * 2 + 100 };
+        node.extents[1] = Extent { block: 10, length: 10 };
+
+        let contents = fs.read_node_all(&node).unwrap();
+        assert_eq!(contents.len(), 512 * 2 + 110);
+        assert_eq!(contents[0], 4);
+        assert_eq!(contents[512 * 2 + 99], 6);
+        assert_eq!(contents[512 * 2 + 100], 10);
+    }
+
+    #[test]
+    fn read_node_past_end() {
+        let mut fs = fs(8);
+        let mut node = node();
+        node.extents[0] = Extent { block: 6, length: 512 * 4 };
+
+        assert_eq!(fs.read_node_all(&node).unwrap_err().errno, EIO);
+    }
+
+    #[test]
+    fn write_zeros() {
+        let mut disk = SmallDisk { data: vec![0xFF; 16 * 512], writes: 0 };
+        disk.write_zeros(3, 5).unwrap();
+        assert_eq!(disk.writes, 3);
+        assert!(disk.data[3 * 512..8 * 512].iter().all(|b| *b == 0));
+        assert_eq!(disk.data[3 * 512 - 1], 0xFF);
+        assert_eq!(disk.data[8 * 512], 0xFF);
+    }
+
+    #[test]
+    fn read_extent() {
+        let mut fs = fs(16);
+        let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        fs.disk.write(5, &data).unwrap();
+
+        let mut buf = [0; 1024];
+        let extent = Extent { block: 5, length: 516 };
+        assert_eq!(fs.read_extent(&extent, &mut buf).unwrap(), 516);
+        assert_eq!(buf[515], (515 % 256) as u8);
+        assert_eq!(buf[516], 0);
+
+        assert_eq!(fs.read_extent(&extent, &mut buf[..10]).unwrap(), 10);
+        assert_eq!(fs.read_extent(&empty(), &mut buf).unwrap_err().errno, EINVAL);
+        let past_end = Extent { block: 15, length: 1024 };
+        assert_eq!(fs.read_extent(&past_end, &mut buf).unwrap_err().errno, EINVAL);
+    }
+
+    #[test]
+    fn rename_subtree() {
+        let mut fs = fs(64);
+        fs.header.extents[0] = Extent { block: 4, length: 512 * 4 };
+        fs.header.free_space = Extent { block: 8, length: 512 * 56 };
+
+        for (i, name) in ["a/x", "a/sub/y", "b/z", "f"].iter().enumerate() {
+            let mut node = node();
+            node.block = 4 + i as u64;
+            node.name = name.to_string();
+            fs.write_node(&node).unwrap();
+            fs.add_node(node);
+        }
+
+        fs.rename_node("a", "b/a").unwrap();
+        let mut names: Vec<&str> = fs.nodes_iter().map(|node| node.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["b/a/sub/y", "b/a/x", "b/z", "f"]);
+
+        let nodes = FileSystem::read_nodes(&mut fs.disk, &mut fs.pool, &fs.header, 1).unwrap();
+        assert_eq!(nodes[1].name, "b/a/sub/y");
+
+        assert_eq!(fs.rename_node("b", "b/a/c").unwrap_err().errno, EINVAL);
+        assert_eq!(fs.rename_node("b", "b").unwrap_err().errno, EINVAL);
+        assert_eq!(fs.rename_node("f", "c/f").unwrap_err().errno, ENOENT);
+        assert_eq!(fs.rename_node("f", "f/g").unwrap_err().errno, ENOTDIR);
+        assert_eq!(fs.rename_node("f", "b").unwrap_err().errno, EISDIR);
+        assert_eq!(fs.rename_node("b/a/sub", "f").unwrap_err().errno, ENOTDIR);
+        assert_eq!(fs.rename_node("b/a/sub", "b").unwrap_err().errno, ENOTEMPTY);
+        assert_eq!(fs.rename_node("missing", "g").unwrap_err().errno, ENOENT);
+
+        fs.rename_node("b/z", "f2").unwrap();
+        assert_eq!(fs.node("f2").unwrap().block, 6);
+    }
+
+    #[test]
+    fn dir_counts() {
+        let mut fs = fs(64);
+        fs.header.extents[0] = Extent { block: 4, length: 512 * 8 };
+        fs.header.free_space = Extent { block: 12, length: 512 * 52 };
+        assert!(fs.dir_empty(""));
+
+        let nodes = fs.create_nodes(&["a/x", "a/sub/y", "b"]).unwrap();
+        assert_eq!(fs.dirs.count("a"), 2);
+        assert_eq!(fs.dirs.count("a/sub/"), 1);
+        assert!(! fs.dir_empty("a"));
+        assert!(! fs.dir_empty(""));
+        assert!(fs.dir_empty("b"));
+
+        fs.rename_node("a/sub", "c").unwrap();
+        assert_eq!(fs.dirs.count("a"), 1);
+        assert!(fs.dir_empty("a/sub"));
+        assert_eq!(fs.dirs.count("c"), 1);
+
+        FileSystem::write_block(&mut fs.disk, &mut fs.pool, nodes[0].block, [0u8; 512]).unwrap();
+        assert!(fs.remove_node(nodes[0].block).is_some());
+        assert!(fs.remove_node(nodes[0].block).is_none());
+        assert!(fs.dir_empty("a"));
+        assert_eq!(fs.dirs.count("c"), 1);
+
+        // Removing a name that is not counted leaves the counts alone
+        fs.dirs.remove("c/y");
+        fs.dirs.remove("c/y");
+        assert_eq!(fs.dirs.count("c"), 0);
+        fs.dirs.add("c/y");
+        assert_eq!(fs.dirs.count("c"), 1);
+
+        fs.reload().unwrap();
+        assert_eq!(fs.dirs.count("c"), 1);
+        assert!(fs.dir_empty("a"));
+    }
+
+    #[test]
+    fn rename_replace() {
+        let mut fs = fs(64);
+        fs.header.extents[0] = Extent { block: 4, length: 512 * 2 };
+        fs.header.free_space = Extent { block: 8, length: 512 * 56 };
+
+        for (i, name) in ["a", "b"].iter().enumerate() {
+            let mut node = node();
+            node.block = 4 + i as u64;
+            node.name = name.to_string();
+            fs.write_node(&node).unwrap();
+            fs.add_node(node);
+        }
+
+        fs.rename_node("a", "b").unwrap();
+        assert_eq!(fs.nodes.len(), 1);
+        assert_eq!(fs.node("b").unwrap().block, 4);
+
+        let nodes = FileSystem::read_nodes(&mut fs.disk, &mut fs.pool, &fs.header, 1).unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].name, "b");
+    }
+
+    #[test]
+    fn mem_disk_status() {
+        let mut disk = MemDisk { data: vec![0; 512] };
+        assert_eq!(disk.status(), DiskStatus::healthy());
+        assert!(disk.status().online);
+        assert_eq!(disk.status().error_count, 0);
+    }
+
+    #[test]
+    fn reload() {
+        let mut disk: Box<Disk> = box MemDisk { data: vec![0xFF; 2048 * 512] };
+        mkfs_at(&mut disk, "test", 1000).unwrap();
+        let mut fs = FileSystem::mount(disk, 512, false, 2000).unwrap();
+
+        // Change the disk without going through the file system
+        let mut header: Header = FileSystem::read_block(&mut fs.disk, &mut fs.pool, HEADER_BLOCK).unwrap();
+        header.set_label("changed");
+        header.checksum = header.checksum();
+        let mut node = node();
+        node.block = header.extents[0].block;
+        FileSystem::write_block(&mut fs.disk, &mut fs.pool, node.block, node.data()).unwrap();
+        FileSystem::write_block(&mut fs.disk, &mut fs.pool, HEADER_BLOCK, header).unwrap();
+        assert!(fs.nodes.is_empty());
+
+        fs.reload().unwrap();
+        assert_eq!(fs.header.label(), "changed");
+        assert_eq!(fs.nodes.len(), 1);
+        assert_eq!(fs.node("file").unwrap().block, node.block);
+
+        fs.begin_txn().unwrap();
+        assert_eq!(fs.reload().unwrap_err().errno, EBUSY);
+    }
+
+    #[test]
+    fn list_subdirs() {
+        let mut fs = fs(8);
+        for name in ["a/x", "a/y/z", "a/y/w", "a/file", "ab/c", "file"].iter() {
+            let mut node = node();
+            node.name = name.to_string();
+            fs.add_node(node);
+        }
+
+        assert_eq!(fs.list_subdirs(""), ["a", "ab"]);
+        assert_eq!(fs.list_subdirs("a"), ["y"]);
+        assert!(fs.list_subdirs("a/y").is_empty());
+        assert!(fs.list_subdirs("missing").is_empty());
+    }
+
+    #[test]
+    fn list_prefix_siblings() {
+        let mut fs = fs(8);
+        for name in ["foo/child", "foo/sub/x", "foobar/a", "foobar", "foo.txt"].iter() {
+            let mut node = node();
+            node.name = name.to_string();
+            fs.add_node(node);
+        }
+
+        assert_eq!(fs.list("foo"), ["child", "sub/x"]);
+        assert_eq!(fs.list("foo/"), ["child", "sub/x"]);
+        assert_eq!(fs.list("foobar"), ["a"]);
+        assert!(fs.list("fo").is_empty());
+        assert_eq!(fs.count_dir("foo"), 2);
+        assert_eq!(fs.list_subdirs("foo"), ["sub"]);
+        assert_eq!(fs.list("").len(), 5);
+    }
+
+    #[test]
+    fn open_node() {
+        let mut fs = fs(16);
+        fs.header.extents[0] = Extent { block: 4, length: 512 * 2 };
+
+        let mut table_node = node();
+        table_node.block = 4;
+        fs.add_node(table_node);
+        // A node cached at a block outside of the node table is not trusted
+        fs.add_node(node());
+
+        assert_eq!(fs.open_node(4).unwrap().name, "file");
+        assert!(fs.open_node(5).is_none());
+        assert!(fs.open_node(3).is_none());
+        assert!(fs.open_node(100).is_none());
+    }
+
+    #[test]
+    fn buffer_pool_bounded() {
+        let mut pool = BufferPool::new(1);
+        let small = pool.take(512).unwrap();
+        let large = pool.take(4096).unwrap();
+        assert_eq!(pool.allocations(), 2);
+
+        // Only the larger buffer is kept, and it serves smaller requests
+        pool.give(small);
+        pool.give(large);
+        let buffer = pool.take(1024).unwrap();
+        assert_eq!(pool.allocations(), 2);
+
+        // An empty pool allocates instead of waiting
+        pool.take(512).unwrap();
+        assert_eq!(pool.allocations(), 3);
+        pool.give(buffer);
+    }
+
+    #[test]
+    fn buffer_pool_size_cap() {
+        let mut pool = BufferPool::new(2);
+        let large = pool.take(POOL_BUFFER_MAX + 512).unwrap();
+        let max = pool.take(POOL_BUFFER_MAX).unwrap();
+        assert_eq!(pool.allocations(), 2);
+
+        // Only the buffer within the cap is kept
+        pool.give(large);
+        pool.give(max);
+        pool.take(512).unwrap();
+        assert_eq!(pool.allocations(), 2);
+        pool.take(512).unwrap();
+        assert_eq!(pool.allocations(), 3);
+    }
+
+    #[test]
+    fn reload_without_allocating() {
+        let mut fs = fs(64);
+        fs.header.extents[0] = Extent { block: 3, length: 512 * 4 };
+        fs.header.free_space = Extent { block: 8, length: 512 * 56 };
+
+        for i in 0..4 {
+            let name: String = (0..300).map(|j| (b'a' + ((i + j) % 26) as u8) as char).collect();
+            let mut node = node();
+            node.block = 3 + i as u64;
+            fs.set_name(&mut node, &name).unwrap();
+            fs.write_node(&node).unwrap();
+        }
+        fs.write_header().unwrap();
+
+        // Reading the node table and names fills the pool, after which the walk allocates nothing
+        fs.reload().unwrap();
+        assert_eq!(fs.nodes.len(), 4);
+        let allocations = fs.pool.allocations();
+        assert!(allocations <= POOL_BUFFERS);
+        for _ in 0..8 {
+            fs.reload().unwrap();
+        }
+        assert_eq!(fs.pool.allocations(), allocations);
+    }
+
+    #[test]
+    fn drop_flushes() {
+        let mut fs = fs(16);
+        fs.disk = box PendingDisk { pending: 0 };
+        fs.disk.write(5, &[1; 1024]).unwrap();
+        drop(fs);
+        assert_eq!(FLUSHED.load(Ordering::SeqCst), 0);
+
+        let mut fs = fs(16);
+        fs.disk = box PendingDisk { pending: 0 };
+        fs.header.state = STATE_MOUNTED;
+        fs.disk.write(5, &[1; 1024]).unwrap();
+        drop(fs);
+        assert_eq!(FLUSHED.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn scan_bad_blocks() {
+        let mut fs = fs(64);
+        fs.disk = box BadDisk { data: vec![0; 64 * 512], bad: vec![10, 11, 40] };
+        fs.write_header().unwrap();
+
+        let bad = fs.scan_bad_blocks().unwrap();
+        assert_eq!(bad, vec![10, 11, 40]);
+        assert_eq!(fs.mark_bad_blocks(&bad).unwrap(), 0);
+
+        fs.reload().unwrap();
+        assert!(fs.header.is_bad_block(11));
+        assert!(fs.block_used(40));
+
+        // Runs that would cover a bad block start after it instead
+        assert_eq!(fs.allocate(4).unwrap(), 4);
+        assert_eq!(fs.allocate(4).unwrap(), 12);
+        assert_eq!(fs.allocate(24).unwrap(), 16);
+        assert_eq!(fs.allocate(4).unwrap(), 41);
+        assert_eq!(fs.allocate(24).unwrap_err().errno, ENOSPC);
+    }
+
+    #[test]
+    fn block_size_override() {
+        // Blocks of 4096 bytes, with a one node table at block 3 and free space from block 4
+        let mut fs = fs(256);
+        fs.block_size = 4096;
+        fs.header.extents[0] = Extent { block: 3 * 8, length: 4096 };
+        fs.header.free_space = Extent { block: 4 * 8, length: 512 * (256 - 32) };
+
+        let mut node = node();
+        node.block = 3 * 8;
+        node.extents[0] = Extent { block: fs.allocate(1).unwrap(), length: 100 };
+        assert_eq!(node.extents[0].block, 4 * 8);
+        assert_eq!(fs.header.free_space.block, 5 * 8);
+        fs.write_node(&node).unwrap();
+        fs.write_header().unwrap();
+
+        // On disk, block numbers count blocks
+        let header: Header = FileSystem::read_block(&mut fs.disk, &mut fs.pool, HEADER_BLOCK * 8).unwrap();
+        assert!(header.valid());
+        assert_eq!(header.free_space.block, 5);
+        assert_eq!(header.extents[0].block, 3);
+        let data: NodeData = FileSystem::read_block(&mut fs.disk, &mut fs.pool, 3 * 8).unwrap();
+        assert_eq!(data.extents[0].block, 4);
+
+        let mut fs = FileSystem::mount(take_disk(&mut fs), 4096, false, 2000).unwrap();
+        assert_eq!(fs.nodes.len(), 1);
+        assert_eq!(fs.nodes[0].block, 3 * 8);
+        assert_eq!(fs.nodes[0].extents[0].block, 4 * 8);
+        assert_eq!(fs.allocate(9).unwrap(), 5 * 8);
+        assert_eq!(fs.header.free_space.block, 7 * 8);
+
+        assert!(FileSystem::mount(take_disk(&mut fs), 512, false, 3000).is_err());
+    }
+
+    #[test]
+    fn block_size_bad_blocks() {
+        let mut fs = fs(256);
+        fs.block_size = 4096;
+        fs.header.free_space = Extent { block: 4 * 8, length: 512 * (256 - 32) };
+
+        // A bad sector in the middle of block 4, and the first sector of block 6
+        fs.header.add_bad_block(4 * 8 + 3);
+        fs.header.add_bad_block(6 * 8);
+        assert!(fs.block_used(4 * 8));
+        assert!(fs.block_used(6 * 8 + 7));
+
+        assert_eq!(fs.allocate(1).unwrap(), 5 * 8);
+        assert_eq!(fs.allocate(9).unwrap(), 7 * 8);
+        assert_eq!(fs.header.free_space.block, 9 * 8);
+        assert_eq!(fs.allocate(8).unwrap() % 8, 0);
+
+        let extents = fs.free_extents();
+        assert_eq!(extents[0].block, 10 * 8);
+    }
+
+    #[test]
+    fn block_size_invalid() {
+        for block_size in [0, 256, 1000, 4097].iter() {
+            let disk: Box<Disk> = box MemDisk { data: vec![0; 64 * 512] };
+            assert_eq!(FileSystem::from_disk_with_block_size(disk, *block_size).err().unwrap().errno, EINVAL);
+        }
     }
 }
@@ -1,27 +1,117 @@
 use collections::string::String;
 use collections::vec::Vec;
 
+use core::{mem, ptr, slice};
+
 use disk::ide::Extent;
 
+use super::crc32::crc32;
+use super::endian::LittleEndian;
+
+/// The length of a name that fits in the node data
+pub const NAME_INLINE: usize = 236;
+/// The length of the start of a long name that is kept in the node data
+pub const NAME_LONG_INLINE: usize = 219;
+/// Marks a long name, stored after its start. It never occurs in UTF-8, so names written before
+/// long names existed are never mistaken for one
+pub const NAME_LONG: u8 = 0xFF;
+/// The maximum length of a name
+pub const NAME_MAX: usize = 4096;
+/// Set in the length of the xattrs extent of node data whose extents hold compressed data.
+/// Attributes are never that long, so it is never set in legacy nodes
+pub const NODE_COMPRESSED: u64 = 1 << 63;
+
 /// Data for a node
 #[repr(packed)]
 pub struct NodeData {
-    pub name: [u8; 256],
+    /// The name, padded with zeros. A long name instead has its first `NAME_LONG_INLINE` bytes
+    /// followed by `NAME_LONG` and the extent holding the rest
+    pub name: [u8; 236],
+    /// Block holding the extended attributes, empty for legacy nodes which have none
+    pub xattrs: Extent,
+    /// CRC-32 of the node data with this field set to zero, zero if not checksummed
+    pub checksum: u32,
     pub extents: [Extent; 16],
 }
 
+impl NodeData {
+    /// Read node data from the start of a sector, converting it from the byte order on disk.
+    /// Returns None if the slice is too short. The struct is packed, so it can be read from any
+    /// address
+    pub fn from_sector(sector: &[u8]) -> Option<NodeData> {
+        if sector.len() >= mem::size_of::<NodeData>() {
+            Some(unsafe { ptr::read(sector.as_ptr() as *const NodeData) }.from_le())
+        } else {
+            None
+        }
+    }
+
+    /// Compute the checksum of the node data, over its bytes as they are on disk
+    pub fn checksum(&self) -> u32 {
+        let data = unsafe { ptr::read(self) }.to_le();
+        let mut bytes = [0; 512];
+        bytes.clone_from_slice(unsafe {
+            slice::from_raw_parts(&data as *const NodeData as *const u8, mem::size_of::<NodeData>())
+        });
+        for b in bytes[252..256].iter_mut() {
+            *b = 0;
+        }
+        crc32(&bytes)
+    }
+
+    /// Check the node data against its checksum. Legacy nodes without a checksum are valid
+    pub fn valid(&self) -> bool {
+        self.checksum == 0 || self.checksum == self.checksum()
+    }
+
+    /// Get the extent holding the rest of a long name
+    pub fn name_extent(&self) -> Option<Extent> {
+        if self.name[NAME_LONG_INLINE] == NAME_LONG {
+            Some(unsafe { ptr::read(self.name[NAME_LONG_INLINE + 1..].as_ptr() as *const Extent) }.from_le())
+        } else {
+            None
+        }
+    }
+}
+
+impl LittleEndian for NodeData {
+    fn from_le(mut self) -> Self {
+        // The name is bytes, and a name extent in it is converted when it is read
+        self.xattrs = self.xattrs.from_le();
+        self.checksum = u32::from_le(self.checksum);
+        for extent in self.extents.iter_mut() {
+            *extent = extent.from_le();
+        }
+        self
+    }
+}
+
 /// A file node
 pub struct Node {
     pub block: u64,
     pub name: String,
+    /// Extent holding the rest of a name longer than `NAME_INLINE`, empty otherwise
+    pub name_extent: Extent,
+    pub xattrs: Extent,
     pub extents: [Extent; 16],
+    /// The extents hold data compressed by `rle::compress`, flagged on disk by `NODE_COMPRESSED`
+    pub compressed: bool,
 }
 
 impl Node {
-    /// Create a new file node from an address and some data
+    /// Create a new file node from an address and some data. A long name only has its start,
+    /// the caller has to append the rest from the name extent
     pub fn new(block: u64, data: &NodeData) -> Self {
+        let name_extent = data.name_extent();
+
+        let inline = if name_extent.is_some() {
+            &data.name[..NAME_LONG_INLINE]
+        } else {
+            &data.name[..]
+        };
+
         let mut bytes = Vec::new();
-        for b in data.name.iter() {
+        for b in inline.iter() {
             if *b > 0 {
                 bytes.push(*b);
             } else {
@@ -32,25 +122,84 @@ impl Node {
         Node {
             block: block,
             name: unsafe { String::from_utf8_unchecked(bytes) },
+            name_extent: name_extent.unwrap_or(Extent {
+                block: 0,
+                length: 0,
+            }),
+            xattrs: Extent {
+                block: data.xattrs.block,
+                length: data.xattrs.length & ! NODE_COMPRESSED,
+            },
             extents: data.extents,
+            compressed: data.xattrs.length & NODE_COMPRESSED == NODE_COMPRESSED,
+        }
+    }
+
+    /// Convert the block numbers of the extents from blocks of `per_block` sectors, as they are on
+    /// disk, to sectors, as they are kept in memory. The block of the node itself is not changed
+    pub fn blocks_to_sectors(&mut self, per_block: u64) {
+        self.name_extent.block *= per_block;
+        self.xattrs.block *= per_block;
+        for extent in self.extents.iter_mut() {
+            extent.block *= per_block;
+        }
+    }
+
+    /// Convert the block numbers of the extents from sectors back to blocks of `per_block` sectors
+    pub fn sectors_to_blocks(&mut self, per_block: u64) {
+        self.name_extent.block /= per_block;
+        self.xattrs.block /= per_block;
+        for extent in self.extents.iter_mut() {
+            extent.block /= per_block;
         }
     }
 
+    /// Check that every data extent of the node ends within a disk of `disk_sectors` sectors.
+    /// A corrupt node could otherwise send reads past the end of the disk. A disk of unknown
+    /// size, given as 0, passes
+    pub fn extents_valid(&self, disk_sectors: u64) -> bool {
+        disk_sectors == 0 || self.extents.iter().all(|extent| {
+            extent.empty() || extent.block.checked_add(extent.sectors()).map_or(false, |end| end <= disk_sectors)
+        })
+    }
+
     pub fn data(&self) -> NodeData {
-        let mut name: [u8; 256] = [0; 256];
+        let mut name: [u8; 236] = [0; 236];
+        let long = self.name.len() > NAME_INLINE && ! self.name_extent.empty();
+        let inline = if long {
+            NAME_LONG_INLINE
+        } else {
+            name.len()
+        };
+
         let mut i = 0;
         for b in self.name.as_bytes().iter() {
-            if i < name.len() {
+            if i < inline {
                 name[i] = *b;
             } else {
                 break;
             }
             i += 1;
         }
-        NodeData {
+
+        if long {
+            name[NAME_LONG_INLINE] = NAME_LONG;
+            unsafe { ptr::write(name[NAME_LONG_INLINE + 1..].as_mut_ptr() as *mut Extent, self.name_extent.to_le()) };
+        }
+
+        let mut xattrs = self.xattrs;
+        if self.compressed {
+            xattrs.length |= NODE_COMPRESSED;
+        }
+
+        let mut data = NodeData {
             name: name,
+            xattrs: xattrs,
+            checksum: 0,
             extents: self.extents,
-        }
+        };
+        data.checksum = data.checksum();
+        data
     }
 }
 
@@ -59,7 +208,43 @@ impl Clone for Node {
         Node {
             block: self.block,
             name: self.name.clone(),
+            name_extent: self.name_extent,
+            xattrs: self.xattrs,
             extents: self.extents,
+            compressed: self.compressed,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::mem;
+
+    use disk::ide::Extent;
+
+    use super::{Node, NodeData};
+
+    #[test]
+    fn from_sector() {
+        assert_eq!(mem::size_of::<NodeData>(), 512);
+        assert!(NodeData::from_sector(&[0; 511]).is_none());
+
+        let mut sector = [0; 513];
+        sector[0] = b'a';
+        let data = NodeData::from_sector(&sector[..]).unwrap();
+        assert_eq!(data.name[0], b'a');
+        assert!(data.valid());
+    }
+
+    #[test]
+    fn extents_valid() {
+        let mut node = Node::new(3, &NodeData::from_sector(&[0; 512]).unwrap());
+        node.extents[0] = Extent { block: 4, length: 512 * 4 };
+        assert!(node.extents_valid(8));
+        assert!(! node.extents_valid(7));
+        assert!(node.extents_valid(0));
+
+        node.extents[0] = Extent { block: u64::max_value(), length: 512 };
+        assert!(! node.extents_valid(8));
+    }
+}
@@ -0,0 +1,99 @@
+use collections::vec::Vec;
+
+use core::slice;
+
+use arch::memory::Memory;
+
+use system::error::{Error, Result, ENOMEM};
+
+/// The number of buffers a file system keeps for reuse
+pub const POOL_BUFFERS: usize = 8;
+/// The size of the largest buffer a file system keeps for reuse. Larger buffers, as for reading
+/// a whole large file, are freed when they are given back
+pub const POOL_BUFFER_MAX: usize = 128 * 512;
+
+/// A scratch buffer for a disk transfer, taken from a `BufferPool`
+pub struct PoolBuffer {
+    memory: Memory<u8>,
+    size: usize,
+}
+
+impl PoolBuffer {
+    /// Get the first `len` bytes of the buffer, which must be no more than were asked for
+    pub fn get(&mut self, len: usize) -> &mut [u8] {
+        assert!(len <= self.size);
+        unsafe { slice::from_raw_parts_mut(self.memory.ptr, len) }
+    }
+
+    /// Get the address of the buffer
+    pub fn ptr(&self) -> *mut u8 {
+        self.memory.ptr
+    }
+}
+
+/// Scratch buffers that are kept after use, so that steady state I/O does not allocate. The
+/// contents of a buffer are whatever its last user left in it
+pub struct BufferPool {
+    free: Vec<PoolBuffer>,
+    max: usize,
+    allocations: usize,
+}
+
+impl BufferPool {
+    /// Create a pool that keeps at most `max` buffers
+    pub fn new(max: usize) -> BufferPool {
+        BufferPool {
+            free: Vec::new(),
+            max: max,
+            allocations: 0,
+        }
+    }
+
+    /// Take a buffer of at least `size` bytes. The smallest kept buffer that is large enough is
+    /// reused, otherwise a new one is allocated, so this never waits. Returns ENOMEM if the
+    /// allocation fails
+    pub fn take(&mut self, size: usize) -> Result<PoolBuffer> {
+        let mut best: Option<usize> = None;
+        for (i, buffer) in self.free.iter().enumerate() {
+            if buffer.size >= size && best.map_or(true, |best| buffer.size < self.free[best].size) {
+                best = Some(i);
+            }
+        }
+
+        if let Some(i) = best {
+            return Ok(self.free.swap_remove(i));
+        }
+
+        match Memory::<u8>::new(size) {
+            Some(memory) => {
+                self.allocations += 1;
+                Ok(PoolBuffer {
+                    memory: memory,
+                    size: size,
+                })
+            },
+            None => Err(Error::new(ENOMEM)),
+        }
+    }
+
+    /// Give a buffer back for reuse. Buffers larger than `POOL_BUFFER_MAX` are freed, so that one
+    /// large transfer does not stay allocated. If the pool is full, the smallest buffer is freed
+    pub fn give(&mut self, buffer: PoolBuffer) {
+        if buffer.size > POOL_BUFFER_MAX {
+            return;
+        }
+
+        if self.free.len() < self.max {
+            self.free.push(buffer);
+        } else if let Some(i) = (0..self.free.len()).min_by_key(|i| self.free[*i].size) {
+            if self.free[i].size < buffer.size {
+                self.free[i] = buffer;
+            }
+        }
+    }
+
+    /// Get the number of buffers allocated since the pool was created
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+}
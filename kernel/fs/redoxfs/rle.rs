@@ -0,0 +1,97 @@
+use collections::vec::Vec;
+
+use core::cmp;
+
+/// The length of the header before the runs, the decompressed length as a little endian word
+const RLE_HEADER: usize = 8;
+
+/// Compress data for a compressed node. After the header, each control byte below 128 is followed
+/// by that many bytes plus one, copied as they are, and each control byte from 128 is followed by
+/// one byte repeated that many times minus 126. Incompressible data grows by one byte in 128
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(RLE_HEADER + data.len() / 64);
+    for i in 0..RLE_HEADER {
+        out.push((data.len() as u64 >> (i * 8)) as u8);
+    }
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut run = 1;
+        while i + run < data.len() && run < 129 && data[i + run] == data[i] {
+            run += 1;
+        }
+
+        if run >= 2 {
+            out.push((run + 126) as u8);
+            out.push(data[i]);
+            i += run;
+        } else {
+            // Copy bytes until a run of three starts, where repeating is shorter
+            let start = i;
+            while i < data.len() && i - start < 128
+                  && ! (i + 2 < data.len() && data[i] == data[i + 1] && data[i] == data[i + 2]) {
+                i += 1;
+            }
+            out.push((i - start - 1) as u8);
+            out.extend_from_slice(&data[start..i]);
+        }
+    }
+
+    out
+}
+
+/// Decompress the data of a compressed node. Returns None if it is malformed, or does not
+/// decompress to the length in its header
+pub fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let len = match decompressed_len(data) {
+        Some(len) => len,
+        None => return None,
+    };
+
+    // The length comes from the disk, so it is not trusted to size the buffer
+    let mut out = Vec::with_capacity(cmp::min(len, data.len() as u64 * 129) as usize);
+    let mut i = RLE_HEADER;
+    while i < data.len() {
+        let control = data[i] as usize;
+        i += 1;
+
+        if control < 128 {
+            if i + control + 1 > data.len() {
+                return None;
+            }
+            out.extend_from_slice(&data[i..i + control + 1]);
+            i += control + 1;
+        } else {
+            if i >= data.len() {
+                return None;
+            }
+            for _ in 0..control - 126 {
+                out.push(data[i]);
+            }
+            i += 1;
+        }
+
+        if out.len() as u64 > len {
+            return None;
+        }
+    }
+
+    if out.len() as u64 == len {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+/// Get the decompressed length from the header of compressed data, None if it is too short
+pub fn decompressed_len(data: &[u8]) -> Option<u64> {
+    if data.len() < RLE_HEADER {
+        return None;
+    }
+
+    let mut len = 0;
+    for i in 0..RLE_HEADER {
+        len |= (data[i] as u64) << (i * 8);
+    }
+    Some(len)
+}
@@ -0,0 +1,24 @@
+use collections::vec::Vec;
+
+use disk::ide::Extent;
+
+use super::node::Node;
+
+/// The nodes of a file system at the time the snapshot was taken. Snapshots only live in memory
+/// and are lost on unmount
+pub struct Snapshot {
+    pub id: usize,
+    pub nodes: Vec<Node>,
+}
+
+impl Snapshot {
+    /// Check if any data extent of the snapshot shares blocks with an extent
+    pub fn pins(&self, extent: &Extent) -> bool {
+        self.nodes.iter().any(|node| node.extents.iter().any(|pinned| pinned.overlaps(extent)))
+    }
+
+    /// Get a node of the snapshot by name
+    pub fn node(&self, name: &str) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.name == name)
+    }
+}
@@ -0,0 +1,23 @@
+/// The mount count from which `fsck_recommended` suggests a check
+pub const FSCK_MOUNT_COUNT: u64 = 32;
+
+/// Administrative statistics of a file system. Images made before these were recorded report
+/// None for the statistics they lack
+pub struct FsStats {
+    /// When the file system was made, in seconds since the epoch
+    pub created: Option<u64>,
+    /// When the file system was last mounted, in seconds since the epoch
+    pub last_mounted: Option<u64>,
+    /// How many times the file system has been mounted
+    pub mount_count: Option<u64>,
+    /// Whether the file system was unmounted cleanly before the current mount
+    pub clean: Option<bool>,
+}
+
+impl FsStats {
+    /// Check if the file system has been mounted often without being unmounted cleanly, so that
+    /// a check is worth running
+    pub fn fsck_recommended(&self) -> bool {
+        self.clean != Some(true) && self.mount_count.map_or(false, |count| count >= FSCK_MOUNT_COUNT)
+    }
+}
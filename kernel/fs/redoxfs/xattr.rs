@@ -0,0 +1,74 @@
+use collections::string::{String, ToString};
+use collections::vec::Vec;
+
+use core::str;
+
+use system::error::{Error, Result, E2BIG, EINVAL};
+
+/// The maximum size of the encoded attributes of a node, which are stored in a single block
+pub const XATTR_SIZE: usize = 512;
+
+/// An extended attribute of a node
+#[derive(Clone)]
+pub struct Xattr {
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+/// Decode attributes, each stored as a key length byte, a little endian value length word, the
+/// key, and the value. Decoding stops at the first malformed entry
+pub fn decode(bytes: &[u8]) -> Vec<Xattr> {
+    let mut xattrs = Vec::new();
+
+    let mut i = 0;
+    while i + 3 <= bytes.len() {
+        let key_len = bytes[i] as usize;
+        let value_len = bytes[i + 1] as usize | (bytes[i + 2] as usize) << 8;
+        i += 3;
+
+        if key_len == 0 || i + key_len + value_len > bytes.len() {
+            break;
+        }
+
+        let key = match str::from_utf8(&bytes[i..i + key_len]) {
+            Ok(key) => key.to_string(),
+            Err(_) => break,
+        };
+        i += key_len;
+
+        let mut value = Vec::new();
+        value.extend_from_slice(&bytes[i..i + value_len]);
+        i += value_len;
+
+        xattrs.push(Xattr {
+            key: key,
+            value: value,
+        });
+    }
+
+    xattrs
+}
+
+/// Encode attributes, returns E2BIG if they do not fit in `XATTR_SIZE` bytes
+pub fn encode(xattrs: &[Xattr]) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+
+    for xattr in xattrs.iter() {
+        if xattr.key.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        if xattr.key.len() > 0xFF || xattr.value.len() > 0xFFFF
+           || bytes.len() + 3 + xattr.key.len() + xattr.value.len() > XATTR_SIZE {
+            return Err(Error::new(E2BIG));
+        }
+
+        bytes.push(xattr.key.len() as u8);
+        bytes.push(xattr.value.len() as u8);
+        bytes.push((xattr.value.len() >> 8) as u8);
+        bytes.extend_from_slice(xattr.key.as_bytes());
+        bytes.extend_from_slice(&xattr.value);
+    }
+
+    Ok(bytes)
+}
@@ -1,6 +1,6 @@
 use alloc::boxed::Box;
 
-use system::error::{Error, Result, EBADF};
+use system::error::{Error, Result, EBADF, ENOSYS};
 use system::syscall::Stat;
 
 /// Resource seek
@@ -51,8 +51,31 @@ pub trait Resource {
         Err(Error::new(EBADF))
     }
 
+    /// Sync the data and only the metadata needed to read it back, such as the length
+    fn sync_data(&mut self) -> Result<()> {
+        self.sync()
+    }
+
     /// Truncate to the given length
     fn truncate(&mut self, len: usize) -> Result<()> {
         Err(Error::new(EBADF))
     }
+
+    /// Load a range into memory ahead of a read. This is only a hint, so by default nothing is
+    /// done, and a range past the end is not an error
+    fn readahead(&mut self, offset: usize, len: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Get the status flags of the resource with `F_GETFL`, or change them with `F_SETFL`.
+    /// Returns ENOSYS if the resource has no flags that can be changed
+    fn fcntl(&mut self, cmd: usize, arg: usize) -> Result<usize> {
+        Err(Error::new(ENOSYS))
+    }
+
+    /// Allocate space for at least `len` bytes, extending the length with zeros unless
+    /// `keep_size` is set. Returns ENOSYS if preallocation is not supported
+    fn allocate(&mut self, len: usize, keep_size: bool) -> Result<()> {
+        Err(Error::new(ENOSYS))
+    }
 }
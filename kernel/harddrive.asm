@@ -1,6 +1,6 @@
 %include "asm/bootsector.asm"
 
-fs_header:
+%macro fs_header_data 0
 .signature:
     db "REDOXFS",0
 .version:
@@ -8,6 +8,9 @@ fs_header:
 .free_space:
     dq (fs_free_space - boot) / 512
     dq (fs_free_space.end - fs_free_space)
+.journal:
+    dq (fs_journal - boot) / 512
+    dq (fs_journal.end - fs_journal)
 .padding:
     align 256, db 0
 .extents:
@@ -16,6 +19,16 @@ fs_header:
 
     align 512, db 0
 .end:
+%endmacro
+
+fs_header:
+    fs_header_data
+
+; Backup copy of the header, used if the primary header is damaged
+fs_header_backup:
+    fs_header_data
+
+%unmacro fs_header_data 0
 
 %ifdef ARCH_i386
     %include "asm/startup-i386.asm"
@@ -69,6 +82,10 @@ fs_data.%1:
 %unmacro file 2+
 
     align 512, db 0
+fs_journal:
+    times 64 * 512 db 0  ;64 sectors of journal
+.end:
+
 fs_free_space:
     times 16 * 1024 * 1024 db 0  ;16 MB of free space
 .end:
@@ -1,27 +1,29 @@
 use common::slice::GetSlice;
 
+use alloc::arc::Arc;
 use alloc::boxed::Box;
 
-use arch::memory::Memory;
+use arch::context::Context;
 
-use collections::slice;
 use collections::string::{String, ToString};
 use collections::vec::Vec;
 
 use common::debug;
 
-use core::cmp;
+use core::{cmp, mem, ptr};
 
 use disk::Disk;
+use disk::cache::CachedDisk;
 use disk::ide::Extent;
+use disk::partition::{self, PartitionDisk};
 
-use fs::redoxfs::{FileSystem, Node, NodeData};
+use fs::redoxfs::{rle, FileSystem, Node};
 
 use fs::{KScheme, Resource, ResourceSeek, Url, VecResource};
 
-use syscall::{O_CREAT, O_TRUNC, MODE_DIR, MODE_FILE, Stat};
+use syscall::{do_sys_nanosleep, F_GETFL, F_SETFL, O_APPEND, O_CREAT, O_TMPFILE, O_TRUNC, DIRENT_DIR, DIRENT_FILE, MODE_DIR, MODE_FILE, Stat, TimeSpec};
 
-use system::error::{Error, Result, ENOENT, EIO};
+use system::error::{Error, Result, EINVAL, ENOENT, ENOSPC, ENOSYS, ENOTDIR, EIO, ERANGE};
 
 /// A file resource
 pub struct FileResource {
@@ -30,16 +32,194 @@ pub struct FileResource {
     pub vec: Vec<u8>,
     pub seek: usize,
     pub dirty: bool,
+    /// Shared by the handles of a temporary file, the last one to be dropped frees its blocks
+    pub tmp: Option<Arc<()>>,
+    /// The flags the file was opened with. Only `O_APPEND` can be changed afterwards
+    pub flags: usize,
+}
+
+impl FileResource {
+    /// Write the data and node in a transaction, leaving them in the disk cache
+    fn commit(&mut self) -> Result<()> {
+        if self.dirty {
+            let block = self.node.block;
+            let fs = unsafe { &mut (*self.scheme).fs };
+
+            fs.locks.lock(block);
+            let old = self.node.clone();
+            let mut result = fs.begin_txn();
+            if result.is_ok() {
+                result = self.write_node();
+                if result.is_ok() {
+                    result = fs.commit_txn();
+                } else {
+                    let _ = fs.abort_txn();
+                }
+            }
+            // Blocks may have been allocated even if the write failed
+            fs.update_open(&old, &self.node);
+            fs.locks.unlock(block);
+
+            result
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Write the data and node of this file to disk, the caller must hold the lock for the node.
+    /// Returns ENOSPC if there is no free space, or no empty extent, for the rest of the data, and
+    /// EIO only if a transfer fails.
+    ///
+    /// The data is flushed before the header and node are written, and the node is written last,
+    /// so after a crash the node never points at blocks whose data was not written, and its
+    /// blocks are never still free space in the header. At worst newly allocated blocks are
+    /// orphaned, which a checked mount reclaims
+    ///
+    /// A compressed file has its data compressed before it is written. If that does not make it
+    /// smaller, it is written as it is and the file is no longer compressed
+    fn write_node(&mut self) -> Result<()> {
+        if ! self.node.compressed {
+            return self.write_extents(false);
+        }
+
+        let compressed = rle::compress(&self.vec);
+        if compressed.len() < self.vec.len() {
+            let raw = mem::replace(&mut self.vec, compressed);
+            let result = self.write_extents(false);
+            self.vec = raw;
+            result
+        } else {
+            self.node.compressed = false;
+            self.write_extents(true)
+        }
+    }
+
+    /// Write `vec` to the extents of the node, then the node if `node_dirty` or the extents
+    /// changed, as described by `write_node`
+    // TODO: Check to make sure proper amount of bytes written. See Disk::write
+    fn write_extents(&mut self, mut node_dirty: bool) -> Result<()> {
+        let mut header_dirty = false;
+        let mut pos = 0;
+        let mut remaining = self.vec.len() as isize;
+        for ref mut extent in &mut self.node.extents {
+            if remaining > 0 && extent.empty() {
+                /*
+                debug::d("Reallocate file, extra: ");
+                debug::ds(remaining);
+                debug::dl();
+                */
+
+                unsafe {
+                    let sectors = ((remaining + 511) / 512) as u64;
+                    if let Ok(block) = (*self.scheme).fs.allocate(sectors) {
+                        extent.block = block;
+                        extent.length = remaining as u64;
+
+                        node_dirty = true;
+                        header_dirty = true;
+                    }
+                }
+            }
+
+            // Make sure it is a valid extent
+            if !extent.empty() {
+                // Do not overwrite blocks a snapshot still references
+                if try!(unsafe { (*self.scheme).fs.cow_extent(extent) }) {
+                    node_dirty = true;
+                    header_dirty = true;
+                }
+
+                let current_sectors = (extent.length as usize + 511) / 512;
+                let max_size = current_sectors * 512;
+
+                let size = cmp::min(remaining as usize, max_size);
+
+                if size as u64 != extent.length {
+                    extent.length = size as u64;
+                    node_dirty = true;
+                }
+
+                while self.vec.len() < pos + max_size {
+                    self.vec.push(0);
+                }
+
+                let count = try!(unsafe { (*self.scheme).fs.disk.write(extent.block, &self.vec[pos .. pos + max_size]) });
+                if count < max_size {
+                    return Err(Error::new(EIO));
+                }
+
+                self.vec.truncate(pos + size);
+
+                pos += size;
+                remaining -= size as isize;
+            }
+        }
+
+        if node_dirty || header_dirty {
+            // Barrier between the data and the metadata pointing at it
+            try!(unsafe { (*self.scheme).fs.disk.flush() });
+        }
+
+        if header_dirty {
+            unsafe {
+                try!((*self.scheme).fs.write_header());
+            }
+        }
+
+        if node_dirty {
+            //debug::d("Node dirty, rewrite\n");
+
+            if self.tmp.is_some() {
+                unsafe {
+                    (*self.scheme).fs.update_unnamed(&self.node);
+                }
+            } else if self.node.block > 0 {
+                unsafe {
+                    // Keep any name and attributes set since this file was opened, as by a rename
+                    let block = self.node.block;
+                    let mut named = false;
+                    if let Some(cached) = (*self.scheme).fs.nodes.iter().find(|node| node.block == block) {
+                        self.node.name = cached.name.clone();
+                        self.node.name_extent = cached.name_extent;
+                        self.node.xattrs = cached.xattrs;
+                        named = true;
+                    }
+
+                    // A file unlinked or replaced while open has no node any more, writing it
+                    // would bring the file back, so it is kept in memory like a temporary file
+                    if named {
+                        try!((*self.scheme).fs.write_node(&self.node));
+                        try!((*self.scheme).fs.disk.flush());
+                    }
+                }
+            } else {
+                debug::d("Need to place Node block\n");
+            }
+        }
+
+        self.dirty = false;
+
+        if remaining > 0 {
+            debug::d("No space for file, extra: ");
+            debug::ds(remaining);
+            debug::dl();
+            return Err(Error::new(ENOSPC));
+        }
+        Ok(())
+    }
 }
 
 impl Resource for FileResource {
     fn dup(&self) -> Result<Box<Resource>> {
+        unsafe { (*self.scheme).fs.open_node(&self.node) };
         Ok(box FileResource {
             scheme: self.scheme,
             node: self.node.clone(),
             vec: self.vec.clone(),
             seek: self.seek,
             dirty: self.dirty,
+            tmp: self.tmp.clone(),
+            flags: self.flags,
         })
     }
 
@@ -67,6 +247,15 @@ impl Resource for FileResource {
     }
 
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.flags & O_APPEND == O_APPEND {
+            self.seek = self.vec.len();
+        }
+
+        // Only write what the file system can hold, so that a full disk gives a short count
+        let capacity = unsafe { (*self.scheme).fs.capacity(&self.node) } as usize;
+        let end = cmp::min(self.seek + buf.len(), cmp::max(capacity, self.vec.len()));
+        let buf = &buf[..end.saturating_sub(self.seek)];
+
         let mut i = 0;
         while i < buf.len() && self.seek < self.vec.len() {
             self.vec[self.seek] = buf[i];
@@ -98,108 +287,62 @@ impl Resource for FileResource {
         Ok(self.seek)
     }
 
-    // TODO: Check to make sure proper amount of bytes written. See Disk::write
     fn sync(&mut self) -> Result<()> {
-        if self.dirty {
-            let mut node_dirty = false;
-            let mut pos = 0;
-            let mut remaining = self.vec.len() as isize;
-            for ref mut extent in &mut self.node.extents {
-                if remaining > 0 && extent.empty() {
-                    /*
-                    debug::d("Reallocate file, extra: ");
-                    debug::ds(remaining);
-                    debug::dl();
-                    */
-
-                    unsafe {
-                        let sectors = ((remaining + 511) / 512) as u64;
-                        if (*self.scheme).fs.header.free_space.length >= sectors * 512 {
-                            extent.block = (*self.scheme).fs.header.free_space.block;
-                            extent.length = remaining as u64;
-                            (*self.scheme).fs.header.free_space.block = (*self.scheme)
-                                                                            .fs
-                                                                            .header
-                                                                            .free_space
-                                                                            .block +
-                                                                        sectors;
-                            (*self.scheme).fs.header.free_space.length = (*self.scheme)
-                                                                             .fs
-                                                                             .header
-                                                                             .free_space
-                                                                             .length -
-                                                                         sectors * 512;
-
-                            node_dirty = true;
-                        }
-                    }
-                }
-
-                // Make sure it is a valid extent
-                if !extent.empty() {
-                    let current_sectors = (extent.length as usize + 511) / 512;
-                    let max_size = current_sectors * 512;
-
-                    let size = cmp::min(remaining as usize, max_size);
-
-                    if size as u64 != extent.length {
-                        extent.length = size as u64;
-                        node_dirty = true;
-                    }
-
-                    while self.vec.len() < pos + max_size {
-                        self.vec.push(0);
-                    }
-
-                    unsafe {
-                        let _ = (*self.scheme).fs.disk.write(extent.block, &self.vec[pos .. pos + max_size]);
-                    }
+        try!(self.commit());
+        // Data written without a metadata change may still be in the disk cache
+        unsafe { (*self.scheme).fs.disk.flush() }
+    }
 
-                    self.vec.truncate(pos + size);
+    fn readahead(&mut self, _offset: usize, _len: usize) -> Result<()> {
+        // The contents were read from the disk at open, so reads never go to the disk
+        Ok(())
+    }
 
-                    pos += size;
-                    remaining -= size as isize;
-                }
-            }
+    fn fcntl(&mut self, cmd: usize, arg: usize) -> Result<usize> {
+        match cmd {
+            F_GETFL => Ok(self.flags),
+            F_SETFL => {
+                self.flags = (self.flags & ! O_APPEND) | (arg & O_APPEND);
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL)),
+        }
+    }
 
-            if node_dirty {
-                //debug::d("Node dirty, rewrite\n");
+    fn allocate(&mut self, len: usize, keep_size: bool) -> Result<()> {
+        if len <= self.vec.len() {
+            return Ok(());
+        }
 
-                if self.node.block > 0 {
-                    unsafe {
-                        if let Some(mut node_data) = Memory::<NodeData>::new(1) {
-                            node_data.write(0, self.node.data());
+        // The size of a file is the sum of its extent lengths, so space past the end of the file
+        // cannot be kept allocated
+        if keep_size {
+            return Err(Error::new(ENOSYS));
+        }
 
-                            let mut buffer = slice::from_raw_parts(node_data.address() as *mut u8, 512);
-                            let _ = (*self.scheme).fs.disk.write(self.node.block, &mut buffer);
+        let block = self.node.block;
+        let fs = unsafe { &mut (*self.scheme).fs };
 
-                            //debug::d("Renode\n");
+        fs.locks.lock(block);
+        let old = self.node.clone();
+        let result = fs.reserve(&mut self.node, len as u64, false);
+        fs.update_open(&old, &self.node);
+        fs.locks.unlock(block);
+        try!(result);
 
-                            for mut node in (*self.scheme).fs.nodes.iter_mut() {
-                                if node.block == self.node.block {
-                                    *node = self.node.clone();
-                                }
-                            }
-                        }
-                    }
-                } else {
-                    debug::d("Need to place Node block\n");
-                }
-            }
-
-            self.dirty = false;
-
-            if remaining > 0 {
-                debug::d("Need to defragment file, extra: ");
-                debug::ds(remaining);
-                debug::dl();
-                return Err(Error::new(EIO));
-            }
+        while self.vec.len() < len {
+            self.vec.push(0);
         }
+        self.dirty = true;
         Ok(())
     }
 
     fn truncate(&mut self, len: usize) -> Result<()> {
+        // Growing past what the file system can hold would only fail when the data is written
+        if len > self.vec.len() && len as u64 > unsafe { (*self.scheme).fs.capacity(&self.node) } {
+            return Err(Error::new(ENOSPC));
+        }
+
         while len > self.vec.len() {
             self.vec.push(0);
         }
@@ -212,29 +355,114 @@ impl Resource for FileResource {
 
 impl Drop for FileResource {
     fn drop(&mut self) {
-        let _ = self.sync();
+        match self.tmp.take() {
+            // Nothing can open a temporary file again, so its data does not need to be written
+            Some(tmp) => {
+                unsafe { (*self.scheme).fs.close_node(&self.node) };
+                if Arc::strong_count(&tmp) == 1 {
+                    let _ = unsafe { (*self.scheme).fs.remove_unnamed(self.node.block) };
+                }
+            },
+            None => {
+                let _ = self.commit();
+                unsafe { (*self.scheme).fs.close_node(&self.node) };
+            }
+        }
     }
 }
 
+/// Number of dirty sectors cached for the file system disk
+const CACHE_SECTORS: usize = 1024;
+
+/// Time between checks for cached sectors that are due to be written back
+const FLUSH_TICK: TimeSpec = TimeSpec {
+    tv_sec: 1,
+    tv_nsec: 0,
+};
+
 /// A file scheme (pci + fs)
 pub struct FileScheme {
     fs: FileSystem,
 }
 
 impl FileScheme {
-    /// Create a new file scheme from an array of Disks
+    /// Create a new file scheme from the first of an array of Disks, or of their partitions,
+    /// that holds a file system
     pub fn new(mut disks: Vec<Box<Disk>>) -> Option<Box<Self>> {
         while ! disks.is_empty() {
-            let disk = disks.remove(0);
-            let name = disk.name();
-            match FileSystem::from_disk(disk) {
-                Ok(fs) => return Some(box FileScheme { fs: fs }),
-                Err(err) => debugln!("{}: {}", name, err)
+            let mut disk = disks.remove(0);
+
+            // A disk without a file system of its own may have one on a partition
+            if ! FileSystem::probe(&mut disk) {
+                if let Ok(partitions) = partition::read_partitions(&mut disk) {
+                    if ! partitions.is_empty() {
+                        for partition in PartitionDisk::split(disk, &partitions) {
+                            if let Some(scheme) = FileScheme::mount(box partition) {
+                                return Some(scheme);
+                            }
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(scheme) = FileScheme::mount(disk) {
+                return Some(scheme);
             }
         }
 
         None
     }
+
+    /// Open a resource for an existing node, reading its data
+    fn open_resource(&mut self, node: Node, flags: usize) -> Result<Box<Resource>> {
+        let vec = try!(self.fs.read_node_all(&node));
+
+        self.fs.open_node(&node);
+        let mut resource = box FileResource {
+            scheme: self,
+            node: node,
+            vec: vec,
+            seek: 0,
+            dirty: false,
+            tmp: None,
+            flags: flags,
+        };
+
+        if flags & O_TRUNC == O_TRUNC {
+            try!(resource.truncate(0));
+        }
+
+        Ok(resource)
+    }
+
+    /// Create a new file scheme from a Disk, returns None if it holds no file system
+    fn mount(disk: Box<Disk>) -> Option<Box<Self>> {
+        let name = disk.name();
+        match FileSystem::from_disk(box CachedDisk::new(disk, CACHE_SECTORS)) {
+            Ok(fs) => {
+                let mut scheme = box FileScheme { fs: fs };
+
+                let this = &mut *scheme as *mut FileScheme;
+                Context::spawn("kfsflush".to_string(), box move || {
+                    loop {
+                        let _ = do_sys_nanosleep(&FLUSH_TICK, ptr::null_mut());
+                        // The cached disk locks the inner disk, so this waits for any transfer
+                        // of the file system context to finish
+                        if let Err(err) = unsafe { (*this).fs.disk.on_tick() } {
+                            debugln!("{}: Failed to write back cache: {}", name, err);
+                        }
+                    }
+                });
+
+                Some(scheme)
+            },
+            Err(err) => {
+                debugln!("{}: {}", name, err);
+                None
+            }
+        }
+    }
 }
 
 impl KScheme for FileScheme {
@@ -250,6 +478,25 @@ impl KScheme for FileScheme {
     fn open(&mut self, url: Url, flags: usize) -> Result<Box<Resource>> {
         let path = url.reference().trim_matches('/');
 
+        // A temporary file is created in a directory, but is not listed in it
+        if flags & O_TMPFILE == O_TMPFILE {
+            if ! path.is_empty() && self.fs.list(path).is_empty() {
+                return Err(Error::new(if self.fs.node(path).is_some() { ENOTDIR } else { ENOENT }));
+            }
+
+            let node = try!(self.fs.create_unnamed());
+            self.fs.open_node(&node);
+            return Ok(box FileResource {
+                scheme: self,
+                node: node,
+                vec: Vec::new(),
+                seek: 0,
+                dirty: false,
+                tmp: Some(Arc::new(())),
+                flags: flags,
+            });
+        }
+
         let children = self.fs.list(path);
         if ! children.is_empty() {
             let mut list = String::new();
@@ -270,11 +517,13 @@ impl KScheme for FileScheme {
                         if found {
                             line.clear();
                         } else {
-                            line = dirname.clone();
+                            // Each entry starts with its type, directories also keep the trailing
+                            // slash that readers of the old format look for
+                            line = (DIRENT_DIR as char).to_string() + &dirname;
                             dirs.push(dirname);
                         }
                     }
-                    None => line = file.clone(),
+                    None => line = (DIRENT_FILE as char).to_string() + file,
                 }
                 if !line.is_empty() {
                     if !list.is_empty() {
@@ -291,62 +540,52 @@ impl KScheme for FileScheme {
                 Err(Error::new(ENOENT))
             }
         } else {
-            match self.fs.node(path) {
-                Some(node) => {
-                    let mut vec: Vec<u8> = Vec::new();
-                    for extent in &node.extents {
-                        if extent.block > 0 && extent.length > 0 {
-                            let current_sectors = (extent.length as usize + 511) / 512;
-                            let max_size = current_sectors * 512;
-
-                            let size = cmp::min(extent.length as usize, max_size);
-
-                            let pos = vec.len();
-
-                            while vec.len() < pos + max_size {
-                                vec.push(0);
-                            }
-
-                            let _ = self.fs.disk.read(extent.block, &mut vec[pos..pos + max_size]);
-
-                            vec.truncate(pos + size);
-                        }
-                    }
-
-                    let mut resource = box FileResource {
-                        scheme: self,
-                        node: node,
-                        vec: vec,
-                        seek: 0,
-                        dirty: false,
-                    };
-
-                    if flags & O_TRUNC == O_TRUNC {
-                        try!(resource.truncate(0));
-                    }
-
-                    Ok(resource)
-                }
-                None => {
-                    if flags & O_CREAT == O_CREAT {
+            match self.fs.resolve(path) {
+                Ok(node) => self.open_resource(node, flags),
+                Err(err) => {
+                    if err.errno == ENOENT && flags & O_CREAT == O_CREAT {
                         // TODO: Create file
                         let mut node = Node {
                             block: 0,
                             name: path.to_string(),
+                            name_extent: Extent {
+                                block: 0,
+                                length: 0,
+                            },
+                            xattrs: Extent {
+                                block: 0,
+                                length: 0,
+                            },
                             extents: [Extent {
                                 block: 0,
                                 length: 0,
                             }; 16],
+                            compressed: false,
                         };
 
-                        if self.fs.header.free_space.length >= 512 {
-                            node.block = self.fs.header.free_space.block;
-                            self.fs.header.free_space.block = self.fs.header.free_space.block + 1;
-                            self.fs.header.free_space.length = self.fs.header.free_space.length -
-                                                               512;
+                        let block = match self.fs.find_free(1) {
+                            Some(block) => block,
+                            None => return Err(Error::new(ENOSPC)),
+                        };
+
+                        try!(self.fs.begin_txn());
+
+                        node.block = block;
+                        self.fs.take_free(block, 1);
+
+                        let mut result = self.fs.set_name(&mut node, path);
+                        if result.is_ok() {
+                            result = self.fs.write_header();
+                        }
+                        if let Err(err) = result {
+                            let _ = self.fs.abort_txn();
+                            return Err(err);
                         }
 
-                        self.fs.nodes.push(node.clone());
+                        try!(self.fs.commit_txn());
+
+                        self.fs.add_node(node.clone());
+                        self.fs.open_node(&node);
 
                         Ok(box FileResource {
                             scheme: self,
@@ -354,9 +593,11 @@ impl KScheme for FileScheme {
                             vec: Vec::new(),
                             seek: 0,
                             dirty: false,
+                            tmp: None,
+                            flags: flags,
                         })
                     } else {
-                        Err(Error::new(ENOENT))
+                        Err(err)
                     }
                 }
             }
@@ -386,11 +627,13 @@ impl KScheme for FileScheme {
                         if found {
                             line.clear();
                         } else {
-                            line = dirname.clone();
+                            // Each entry starts with its type, directories also keep the trailing
+                            // slash that readers of the old format look for
+                            line = (DIRENT_DIR as char).to_string() + &dirname;
                             dirs.push(dirname);
                         }
                     }
-                    None => line = file.clone(),
+                    None => line = (DIRENT_FILE as char).to_string() + file,
                 }
                 if !line.is_empty() {
                     if !list.is_empty() {
@@ -404,6 +647,7 @@ impl KScheme for FileScheme {
             if list.len() > 0 {
                 stat.st_mode = MODE_DIR;
                 stat.st_size = list.len() as u64;
+                stat.st_blocks = 0;
 
                 Ok(())
             } else {
@@ -413,11 +657,12 @@ impl KScheme for FileScheme {
             match self.fs.node(path) {
                 Some(node) => {
                     stat.st_mode = MODE_FILE;
-                    stat.st_size = 0;
+                    stat.st_size = try!(self.fs.node_size(&node));
+                    stat.st_blocks = 0;
 
                     for extent in &node.extents {
                         if extent.block > 0 && extent.length > 0 {
-                            stat.st_size += extent.length;
+                            stat.st_blocks += (extent.length + 511) / 512;
                         }
                     }
 
@@ -428,6 +673,17 @@ impl KScheme for FileScheme {
         }
     }
 
+    fn open_node(&mut self, block: u64, flags: usize) -> Result<Box<Resource>> {
+        match self.fs.open_node(block) {
+            Some(node) => self.open_resource(node, flags),
+            None => Err(Error::new(ENOENT)),
+        }
+    }
+
+    fn rename(&mut self, from: Url, to: Url) -> Result<()> {
+        self.fs.rename_node(from.reference(), to.reference())
+    }
+
     fn unlink(&mut self, url: Url) -> Result<()> {
         let mut ret = Err(Error::new(ENOENT));
 
@@ -436,13 +692,18 @@ impl KScheme for FileScheme {
         let mut i = 0;
         while i < self.fs.nodes.len() {
             let mut remove = false;
+            let mut block = 0;
 
             if let Some(node) = self.fs.nodes.get(i) {
                 remove = node.name == path;
+                block = node.block;
             }
 
             if remove {
-                self.fs.nodes.remove(i);
+                // Wait for any writer of this node to finish before removing it
+                self.fs.locks.lock(block);
+                self.fs.remove_node(block);
+                self.fs.locks.unlock(block);
                 ret = Ok(());
             } else {
                 i += 1;
@@ -451,4 +712,584 @@ impl KScheme for FileScheme {
 
         ret
     }
+
+    fn getxattr(&mut self, url: Url, key: &str, buf: &mut [u8]) -> Result<usize> {
+        let path = url.reference().trim_matches('/');
+
+        let node = try!(self.fs.resolve(path));
+        let value = try!(self.fs.get_xattr(&node, key));
+        if value.len() > buf.len() {
+            return Err(Error::new(ERANGE));
+        }
+
+        for (b, v) in buf.iter_mut().zip(value.iter()) {
+            *b = *v;
+        }
+
+        Ok(value.len())
+    }
+
+    fn setxattr(&mut self, url: Url, key: &str, value: &[u8]) -> Result<()> {
+        let path = url.reference().trim_matches('/');
+
+        let mut node = try!(self.fs.resolve(path));
+        let block = node.block;
+
+        self.fs.locks.lock(block);
+        let result = self.fs.set_xattr(&mut node, key, value);
+        self.fs.locks.unlock(block);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use collections::string::{String, ToString};
+    use collections::vec::Vec;
+
+    use core::mem;
+    use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    use disk::Disk;
+    use disk::ide::Extent;
+
+    use fs::{KScheme, Resource, ResourceSeek, Url};
+    use fs::redoxfs::{Node, NodeData};
+    use fs::redoxfs::tests::{fs, node, MemDisk};
+
+    use syscall::{F_GETFL, F_SETFL, O_APPEND, O_CREAT, O_TMPFILE, Stat};
+    use system::error::{Error, Result, EIO, ENOENT, ENOSPC, ENOSYS};
+
+    use super::{FileResource, FileScheme};
+
+    static READS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// A disk that counts the reads passed on to another disk
+    struct CountingDisk {
+        inner: Box<Disk>,
+    }
+
+    impl Disk for CountingDisk {
+        fn name(&self) -> String {
+            "Counting".to_string()
+        }
+
+        fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+            READS.fetch_add(1, Ordering::SeqCst);
+            self.inner.read(block, buffer)
+        }
+
+        fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+            self.inner.write(block, buffer)
+        }
+    }
+
+    /// A memory disk with a write cache that loses power after a number of writes. Writes only
+    /// reach the media when flushed, except that the cache may write back the newest one first,
+    /// so the last write before the crash is kept and the other unflushed writes are lost
+    struct CrashDisk {
+        data: Vec<u8>,
+        pending: Vec<(u64, Vec<u8>)>,
+        writes: usize,
+    }
+
+    impl CrashDisk {
+        fn apply(data: &mut [u8], block: u64, buffer: &[u8]) {
+            let start = block as usize * 512;
+            for (d, b) in data[start..].iter_mut().zip(buffer.iter()) {
+                *d = *b;
+            }
+        }
+    }
+
+    impl Disk for CrashDisk {
+        fn name(&self) -> String {
+            "Crash".to_string()
+        }
+
+        fn read(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+            let mut data = self.data.clone();
+            for &(pending, ref pending_buffer) in self.pending.iter() {
+                CrashDisk::apply(&mut data, pending, pending_buffer);
+            }
+
+            let start = block as usize * 512;
+            for (b, d) in buffer.iter_mut().zip(data[start..].iter()) {
+                *b = *d;
+            }
+            Ok(buffer.len())
+        }
+
+        fn write(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+            if self.writes == 0 {
+                return Err(Error::new(EIO));
+            }
+
+            self.writes -= 1;
+            if self.writes == 0 {
+                CrashDisk::apply(&mut self.data, block, buffer);
+                self.pending.clear();
+            } else {
+                self.pending.push((block, buffer.to_vec()));
+            }
+            Ok(buffer.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            if self.writes == 0 {
+                return Err(Error::new(EIO));
+            }
+
+            for &(block, ref buffer) in self.pending.iter() {
+                CrashDisk::apply(&mut self.data, block, buffer);
+            }
+            self.pending.clear();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_short_when_full() {
+        let mut scheme = FileScheme { fs: fs(8) };
+        scheme.fs.header.free_space = Extent { block: 4, length: 512 * 2 };
+
+        let mut resource = FileResource {
+            scheme: &mut scheme,
+            node: node(),
+            vec: Vec::new(),
+            seek: 0,
+            dirty: false,
+            tmp: None,
+            flags: 0,
+        };
+
+        assert_eq!(resource.write(&[1; 768]).unwrap(), 768);
+        assert_eq!(resource.write(&[2; 768]).unwrap(), 256);
+        assert_eq!(resource.write(&[3; 768]).unwrap(), 0);
+
+        // Overwriting does not need more space
+        resource.seek = 0;
+        assert_eq!(resource.write(&[4; 1024]).unwrap(), 1024);
+    }
+
+    #[test]
+    fn full_disk_is_enospc() {
+        let mut scheme = FileScheme { fs: fs(8) };
+        scheme.fs.header.free_space = Extent { block: 4, length: 512 * 2 };
+
+        {
+            let mut resource = FileResource {
+                scheme: &mut scheme,
+                node: node(),
+                vec: Vec::new(),
+                seek: 0,
+                dirty: false,
+                tmp: None,
+                flags: 0,
+            };
+
+            assert_eq!(resource.truncate(4096).unwrap_err().errno, ENOSPC);
+            assert_eq!(resource.write(&[1; 1024]).unwrap(), 1024);
+            resource.sync().unwrap();
+
+            // Data that got past the capacity check still fails with the right error
+            resource.vec.extend_from_slice(&[2; 512]);
+            resource.dirty = true;
+            assert_eq!(resource.sync().unwrap_err().errno, ENOSPC);
+        }
+
+        match scheme.open(Url::from_str("file:/new").unwrap(), O_CREAT) {
+            Err(err) => assert_eq!(err.errno, ENOSPC),
+            Ok(_) => panic!("created a file on a full disk"),
+        }
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let mut data: Vec<u8> = b"compressed file\n".to_vec();
+        data.extend_from_slice(&[0; 8192]);
+        data.extend_from_slice(b"end\n");
+
+        let mut scheme = FileScheme { fs: fs(64) };
+        let mut compressed = node();
+        compressed.compressed = true;
+        scheme.fs.add_node(compressed.clone());
+
+        {
+            let mut resource = FileResource {
+                scheme: &mut scheme,
+                node: compressed,
+                vec: data.clone(),
+                seek: 0,
+                dirty: true,
+                tmp: None,
+                flags: 0,
+            };
+            resource.sync().unwrap();
+
+            // The data only needs one sector instead of 17
+            assert!(resource.node.compressed);
+            assert_eq!(resource.node.extents[0].sectors(), 1);
+            assert!(resource.node.extents[1].empty());
+            assert_eq!(resource.vec, data);
+        }
+
+        let mut sector = [0; 512];
+        scheme.fs.disk.read(3, &mut sector).unwrap();
+        let stored = Node::new(3, &NodeData::from_sector(&sector).unwrap());
+        assert!(stored.compressed);
+        assert!(stored.xattrs.empty());
+        assert_eq!(scheme.fs.read_node_all(&stored).unwrap(), data);
+
+        let mut stat = Stat::default();
+        scheme.stat(Url::from_str("file:/file").unwrap(), &mut stat).unwrap();
+        assert_eq!(stat.st_size, data.len() as u64);
+        assert_eq!(stat.st_blocks, 1);
+    }
+
+    #[test]
+    fn sparse_blocks() {
+        // Nodes have no holes, a run of zeros is stored compressed instead
+        let data = vec![0; 64 * 1024];
+
+        let mut scheme = FileScheme { fs: fs(64) };
+        let mut sparse = node();
+        sparse.compressed = true;
+        scheme.fs.add_node(sparse.clone());
+
+        {
+            let mut resource = FileResource {
+                scheme: &mut scheme,
+                node: sparse,
+                vec: data.clone(),
+                seek: 0,
+                dirty: true,
+                tmp: None,
+                flags: 0,
+            };
+            resource.sync().unwrap();
+        }
+
+        let mut stat = Stat::default();
+        scheme.stat(Url::from_str("file:/file").unwrap(), &mut stat).unwrap();
+        assert_eq!(stat.st_size, data.len() as u64);
+        assert!(stat.st_blocks * 512 < stat.st_size);
+    }
+
+    #[test]
+    fn incompressible_stored_raw() {
+        let data: Vec<u8> = (0..1024).map(|i| (i * 7 % 251) as u8).collect();
+
+        let mut scheme = FileScheme { fs: fs(16) };
+        let mut compressed = node();
+        compressed.compressed = true;
+
+        let mut resource = FileResource {
+            scheme: &mut scheme,
+            node: compressed,
+            vec: data.clone(),
+            seek: 0,
+            dirty: true,
+            tmp: None,
+            flags: 0,
+        };
+        resource.sync().unwrap();
+
+        assert!(! resource.node.compressed);
+        assert_eq!(resource.node.extents[0].length, 1024);
+        assert_eq!(unsafe { (*resource.scheme).fs.read_node_all(&resource.node) }.unwrap(), data);
+    }
+
+    #[test]
+    fn snapshot_keeps_old_data() {
+        let mut scheme = FileScheme { fs: fs(16) };
+        scheme.fs.add_node(node());
+
+        let (id, old_block) = {
+            let mut resource = FileResource {
+                scheme: &mut scheme,
+                node: node(),
+                vec: Vec::new(),
+                seek: 0,
+                dirty: false,
+                tmp: None,
+                flags: 0,
+            };
+
+            resource.write(b"old").unwrap();
+            resource.sync().unwrap();
+            let old_block = resource.node.extents[0].block;
+            let id = unsafe { (*resource.scheme).fs.snapshot() };
+
+            resource.seek = 0;
+            resource.write(b"new").unwrap();
+            resource.sync().unwrap();
+            assert!(resource.node.extents[0].block != old_block);
+
+            (id, old_block)
+        };
+
+        assert_eq!(scheme.fs.read_snapshot(id, "file").unwrap(), b"old".to_vec());
+        assert_eq!(scheme.fs.block_refs(old_block), 1);
+        assert!(scheme.fs.block_used(old_block));
+
+        scheme.fs.drop_snapshot(id).unwrap();
+        assert_eq!(scheme.fs.block_refs(old_block), 0);
+        assert!(!scheme.fs.block_used(old_block));
+        assert!(scheme.fs.drop_snapshot(id).is_err());
+    }
+
+    #[test]
+    fn rename_replace_keeps_open_file() {
+        let mut scheme = FileScheme { fs: fs(32) };
+        for &(path, data) in [("file:/a", b"new"), ("file:/b", b"old")].iter() {
+            let mut resource = scheme.open(Url::from_str(path).unwrap(), O_CREAT).unwrap();
+            resource.write(data).unwrap();
+            resource.sync().unwrap();
+        }
+
+        let mut reader = scheme.open(Url::from_str("file:/b").unwrap(), 0).unwrap();
+        scheme.rename(Url::from_str("file:/a").unwrap(), Url::from_str("file:/b").unwrap()).unwrap();
+
+        let mut buf = [0; 8];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"old");
+
+        let mut renamed = scheme.open(Url::from_str("file:/b").unwrap(), 0).unwrap();
+        assert_eq!(renamed.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"new");
+        assert!(scheme.open(Url::from_str("file:/a").unwrap(), 0).is_err());
+    }
+
+    #[test]
+    fn allocate_modes() {
+        let mut scheme = FileScheme { fs: fs(32) };
+        {
+            let mut resource = scheme.open(Url::from_str("file:/a").unwrap(), O_CREAT).unwrap();
+            resource.write(b"abc").unwrap();
+            resource.sync().unwrap();
+
+            // Space past the end of a file can not be kept
+            assert_eq!(resource.allocate(4096, true).unwrap_err().errno, ENOSYS);
+            assert_eq!(resource.seek(ResourceSeek::End(0)).unwrap(), 3);
+            resource.allocate(2, true).unwrap();
+
+            resource.allocate(4096, false).unwrap();
+            assert_eq!(resource.seek(ResourceSeek::End(0)).unwrap(), 4096);
+        }
+
+        let node = scheme.fs.node("a").unwrap();
+        assert!(! node.extents[1].empty());
+        let data = scheme.fs.read_node_all(&node).unwrap();
+        assert_eq!(data.len(), 4096);
+        assert_eq!(&data[..3], b"abc");
+        assert!(data[3..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn rename_while_writing() {
+        let mut scheme = FileScheme { fs: fs(64) };
+        let mut writer = scheme.open(Url::from_str("file:/a/x").unwrap(), O_CREAT).unwrap();
+        writer.write(b"first").unwrap();
+        writer.sync().unwrap();
+
+        scheme.rename(Url::from_str("file:/a").unwrap(), Url::from_str("file:/b").unwrap()).unwrap();
+        writer.write(&[1; 1024]).unwrap();
+        writer.sync().unwrap();
+        drop(writer);
+
+        assert!(scheme.fs.node("a/x").is_none());
+        assert!(scheme.fs.dir_empty("a"));
+        assert_eq!(scheme.fs.dirs.count("b"), 1);
+
+        // The node on disk keeps the new name
+        let node = scheme.fs.node("b/x").unwrap();
+        let mut sector = [0; 512];
+        scheme.fs.disk.read(node.block, &mut sector).unwrap();
+        let stored = Node::new(node.block, &NodeData::from_sector(&sector).unwrap());
+        assert_eq!(stored.name, "b/x");
+        assert_eq!(scheme.fs.read_node_all(&stored).unwrap().len(), 1029);
+    }
+
+    #[test]
+    fn rename_replace_open_writer() {
+        let mut scheme = FileScheme { fs: fs(32) };
+        for &(path, data) in [("file:/a", b"new"), ("file:/b", b"old")].iter() {
+            let mut resource = scheme.open(Url::from_str(path).unwrap(), O_CREAT).unwrap();
+            resource.write(data).unwrap();
+            resource.sync().unwrap();
+        }
+
+        let mut writer = scheme.open(Url::from_str("file:/b").unwrap(), 0).unwrap();
+        let old_block = scheme.fs.node("b").unwrap().block;
+        scheme.rename(Url::from_str("file:/a").unwrap(), Url::from_str("file:/b").unwrap()).unwrap();
+
+        // Growing the file changes its node, which is not written back
+        writer.seek(ResourceSeek::End(0)).unwrap();
+        writer.write(&[1; 1024]).unwrap();
+        writer.sync().unwrap();
+        drop(writer);
+
+        assert_eq!(scheme.fs.nodes.iter().filter(|node| node.name == "b").count(), 1);
+        let mut sector = [0; 512];
+        scheme.fs.disk.read(old_block, &mut sector).unwrap();
+        assert!(sector.iter().all(|b| *b == 0));
+
+        let mut renamed = scheme.open(Url::from_str("file:/b").unwrap(), 0).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(renamed.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"new");
+    }
+
+    #[test]
+    fn tmpfile_freed_on_last_close() {
+        let mut scheme = FileScheme { fs: fs(32) };
+        scheme.fs.add_node(node());
+        assert_eq!(scheme.open(Url::from_str("file:/missing").unwrap(), O_TMPFILE).err().unwrap().errno, ENOENT);
+
+        {
+            let mut tmp = scheme.open(Url::from_str("file:/").unwrap(), O_TMPFILE).unwrap();
+            tmp.write(&[1; 1024]).unwrap();
+            tmp.sync().unwrap();
+
+            let dup = tmp.dup().unwrap();
+            drop(tmp);
+            assert_eq!(scheme.fs.header.free_space.block, 7);
+            assert_eq!(scheme.fs.list("").len(), 1);
+            drop(dup);
+        }
+
+        assert!(scheme.fs.unnamed.is_empty());
+        assert_eq!(scheme.fs.header.free_space.block, 4);
+    }
+
+    #[test]
+    fn tmpfile_keeps_unlinked_blocks() {
+        let mut scheme = FileScheme { fs: fs(64) };
+
+        let mut a = scheme.open(Url::from_str("file:/a").unwrap(), O_CREAT).unwrap();
+        a.write(&[0xA; 1024]).unwrap();
+        a.sync().unwrap();
+        scheme.unlink(Url::from_str("file:/a").unwrap()).unwrap();
+
+        // Closing a temporary file reclaims the orphans before the free space, which would
+        // include the blocks of the unlinked file if they were not kept for its handle
+        {
+            let mut tmp = scheme.open(Url::from_str("file:/").unwrap(), O_TMPFILE).unwrap();
+            tmp.write(&[1; 1024]).unwrap();
+            tmp.sync().unwrap();
+        }
+
+        {
+            let mut b = scheme.open(Url::from_str("file:/b").unwrap(), O_CREAT).unwrap();
+            b.write(&[0xB; 1024]).unwrap();
+            b.sync().unwrap();
+        }
+
+        a.write(&[0xC; 1024]).unwrap();
+        a.sync().unwrap();
+        drop(a);
+
+        let mut b = scheme.open(Url::from_str("file:/b").unwrap(), 0).unwrap();
+        let mut buf = [0; 2048];
+        assert_eq!(b.read(&mut buf).unwrap(), 1024);
+        assert!(buf[..1024].iter().all(|b| *b == 0xB));
+    }
+
+    #[test]
+    fn crash_never_exposes_stale_data() {
+        for writes in 1..8 {
+            let mut scheme = FileScheme { fs: fs(16) };
+            scheme.fs.disk = box CrashDisk {
+                data: vec![0xFF; 16 * 512],
+                pending: Vec::new(),
+                writes: writes,
+            };
+            scheme.fs.add_node(node());
+
+            {
+                let mut resource = FileResource {
+                    scheme: &mut scheme,
+                    node: node(),
+                    vec: Vec::new(),
+                    seek: 0,
+                    dirty: false,
+                    tmp: None,
+                    flags: 0,
+                };
+                resource.write(&[7; 1024]).unwrap();
+                let _ = resource.sync();
+            }
+
+            // Whatever made it to the media, a node on it only points at written data
+            let mut sector = [0; 512];
+            scheme.fs.disk.read(3, &mut sector).unwrap();
+            if let Some(data) = NodeData::from_sector(&sector) {
+                if data.valid() {
+                    let node = Node::new(3, &data);
+                    for extent in node.extents.iter().filter(|extent| ! extent.empty()) {
+                        let mut buffer = vec![0; (extent.length as usize + 511) / 512 * 512];
+                        scheme.fs.disk.read(extent.block, &mut buffer).unwrap();
+                        assert!(buffer[..extent.length as usize].iter().all(|b| *b == 7));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn append_toggled() {
+        let mut scheme = FileScheme { fs: fs(16) };
+        let mut resource = FileResource {
+            scheme: &mut scheme,
+            node: node(),
+            vec: Vec::new(),
+            seek: 0,
+            dirty: false,
+            tmp: None,
+            flags: 0,
+        };
+        resource.write(b"abc").unwrap();
+
+        resource.fcntl(F_SETFL, O_APPEND).unwrap();
+        assert_eq!(resource.fcntl(F_GETFL, 0).unwrap() & O_APPEND, O_APPEND);
+        resource.seek = 0;
+        resource.write(b"de").unwrap();
+        assert_eq!(resource.vec, b"abcde".to_vec());
+
+        resource.fcntl(F_SETFL, 0).unwrap();
+        assert_eq!(resource.fcntl(F_GETFL, 0).unwrap() & O_APPEND, 0);
+        resource.seek = 0;
+        resource.write(b"x").unwrap();
+        assert_eq!(resource.vec, b"xbcde".to_vec());
+    }
+
+    #[test]
+    fn readahead_without_disk_reads() {
+        let mut scheme = FileScheme { fs: fs(32) };
+        {
+            let mut resource = scheme.open(Url::from_str("file:/a").unwrap(), O_CREAT).unwrap();
+            resource.write(&[7; 1000]).unwrap();
+            resource.sync().unwrap();
+        }
+
+        let inner = mem::replace(&mut scheme.fs.disk, box MemDisk { data: Vec::new() });
+        scheme.fs.disk = box CountingDisk { inner: inner };
+
+        // Open reads the whole file, so neither readahead nor the reads after it go to the disk
+        let mut resource = scheme.open(Url::from_str("file:/a").unwrap(), 0).unwrap();
+        let reads = READS.load(Ordering::SeqCst);
+        // A range past the end is clamped rather than refused
+        resource.readahead(512, 4096).unwrap();
+        assert_eq!(READS.load(Ordering::SeqCst), reads);
+
+        let mut buf = [0; 1024];
+        resource.seek(ResourceSeek::Start(512)).unwrap();
+        assert_eq!(resource.read(&mut buf).unwrap(), 488);
+        assert!(buf[..488].iter().all(|b| *b == 7));
+        assert_eq!(READS.load(Ordering::SeqCst), reads);
+    }
 }
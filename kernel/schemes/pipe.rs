@@ -7,17 +7,20 @@ use fs::Resource;
 
 use sync::WaitQueue;
 
-use system::error::{Error, Result, EPIPE};
+use system::error::{Error, Result, EAGAIN, EPIPE};
 
 /// Read side of a pipe
 pub struct PipeRead {
-    vec: Arc<WaitQueue<u8>>
+    vec: Arc<WaitQueue<u8>>,
+    /// Return EAGAIN instead of waiting when the pipe is empty
+    nonblock: bool,
 }
 
 impl PipeRead {
-    pub fn new() -> Self {
+    pub fn new(nonblock: bool) -> Self {
         PipeRead {
-            vec: Arc::new(WaitQueue::new())
+            vec: Arc::new(WaitQueue::new()),
+            nonblock: nonblock,
         }
     }
 }
@@ -26,6 +29,7 @@ impl Resource for PipeRead {
     fn dup(&self) -> Result<Box<Resource>> {
         Ok(box PipeRead {
             vec: self.vec.clone(),
+            nonblock: self.nonblock,
         })
     }
 
@@ -42,6 +46,8 @@ impl Resource for PipeRead {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         if Arc::weak_count(&self.vec) == 0 && self.vec.inner.lock().is_empty() {
             Ok(0)
+        } else if self.nonblock && ! buf.is_empty() && self.vec.inner.lock().is_empty() {
+            Err(Error::new(EAGAIN))
         } else {
             if !buf.is_empty() {
                 buf[0] = self.vec.receive();
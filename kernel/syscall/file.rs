@@ -1,16 +1,21 @@
-use arch::context::ContextFile;
+use alloc::boxed::Box;
 
-use core::slice;
+use arch::context::{Context, ContextFile};
 
-use fs::{ResourceSeek, Url};
+use collections::string::ToString;
+use collections::vec::Vec;
+
+use core::{slice, str};
+
+use fs::{Resource, ResourceSeek, Url};
 
 use schemes::pipe::{PipeRead, PipeWrite};
 
 use system::c_string_to_str;
 
-use syscall::{Stat, SEEK_CUR, SEEK_END, SEEK_SET};
+use syscall::{Stat, FALLOC_FL_KEEP_SIZE, MODE_DIR, O_NONBLOCK, SEEK_CUR, SEEK_END, SEEK_SET};
 
-use system::error::{Error, Result, EBADF, EFAULT, EINVAL};
+use system::error::{Error, Result, EBADF, EFAULT, EINVAL, ENOTDIR, ERANGE};
 
 pub fn do_sys_chdir(path: *const u8) -> Result<usize> {
     let contexts = ::env().contexts.lock();
@@ -65,6 +70,52 @@ pub fn do_sys_dup(fd: usize) -> Result<usize> {
     Ok(new_fd)
 }
 
+/// Duplicate a file onto a chosen descriptor, closing the file that was there
+pub fn do_sys_dup2(fd: usize, new_fd: usize) -> Result<usize> {
+    if new_fd as isize < 0 {
+        return Err(Error::new(EBADF));
+    }
+
+    let contexts = ::env().contexts.lock();
+    let current = try!(contexts.current());
+    let resource = try!(current.get_file(fd));
+    if new_fd == fd {
+        return Ok(new_fd);
+    }
+    let new_resource = try!(resource.dup());
+
+    //debugln!("{}: {}: dup2 {} as {}", current.pid, current.name, fd, new_fd);
+
+    unsafe {
+        replace_file(&mut *current.files.get(), new_fd, new_resource);
+    }
+    Ok(new_fd)
+}
+
+/// Put a resource at a descriptor, closing the file that was there
+fn replace_file(files: &mut Vec<ContextFile>, fd: usize, resource: Box<Resource>) {
+    files.retain(|file| file.fd != fd);
+    files.push(ContextFile {
+        fd: fd,
+        resource: resource,
+    });
+}
+
+pub fn do_sys_fallocate(fd: usize, mode: usize, len: usize) -> Result<usize> {
+    let mut contexts = ::env().contexts.lock();
+    let mut current = try!(contexts.current_mut());
+    let mut resource = try!(current.get_file_mut(fd));
+    resource.allocate(len, mode & FALLOC_FL_KEEP_SIZE == FALLOC_FL_KEEP_SIZE).and(Ok(0))
+}
+
+/// Get or change the status flags of a file
+pub fn do_sys_fcntl(fd: usize, cmd: usize, arg: usize) -> Result<usize> {
+    let mut contexts = ::env().contexts.lock();
+    let mut current = try!(contexts.current_mut());
+    let mut resource = try!(current.get_file_mut(fd));
+    resource.fcntl(cmd, arg)
+}
+
 pub fn do_sys_fpath(fd: usize, buf: *mut u8, count: usize) -> Result<usize> {
     let contexts = ::env().contexts.lock();
     let current = try!(contexts.current());
@@ -72,6 +123,19 @@ pub fn do_sys_fpath(fd: usize, buf: *mut u8, count: usize) -> Result<usize> {
     resource.path(unsafe { slice::from_raw_parts_mut(buf, count) })
 }
 
+/// Copy the working directory of the current context into `buf`, returning its length. ERANGE is
+/// returned if it does not fit
+pub fn do_sys_getcwd(buf: *mut u8, count: usize) -> Result<usize> {
+    let contexts = ::env().contexts.lock();
+    let current = try!(contexts.current());
+    let cwd = unsafe { &*current.cwd.get() };
+    if cwd.len() > count {
+        return Err(Error::new(ERANGE));
+    }
+    unsafe { slice::from_raw_parts_mut(buf, count) }[..cwd.len()].clone_from_slice(cwd.as_bytes());
+    Ok(cwd.len())
+}
+
 pub fn do_sys_fstat(fd: usize, stat: *mut Stat) -> Result<usize> {
     let contexts = ::env().contexts.lock();
     let current = try!(contexts.current());
@@ -83,6 +147,13 @@ pub fn do_sys_fstat(fd: usize, stat: *mut Stat) -> Result<usize> {
     }
 }
 
+pub fn do_sys_fdatasync(fd: usize) -> Result<usize> {
+    let mut contexts = ::env().contexts.lock();
+    let mut current = try!(contexts.current_mut());
+    let mut resource = try!(current.get_file_mut(fd));
+    resource.sync_data().and(Ok(0))
+}
+
 pub fn do_sys_fsync(fd: usize) -> Result<usize> {
     let mut contexts = ::env().contexts.lock();
     let mut current = try!(contexts.current_mut());
@@ -99,6 +170,14 @@ pub fn do_sys_ftruncate(fd: usize, len: usize) -> Result<usize> {
 
 //TODO: Link
 
+pub fn do_sys_getxattr(path: *const u8, key: *const u8, buf: *mut u8, len: usize) -> Result<usize> {
+    let contexts = ::env().contexts.lock();
+    let current = try!(contexts.current());
+    let path_string = current.canonicalize(c_string_to_str(path));
+    let url = try!(Url::from_str(&path_string));
+    ::env().getxattr(url, c_string_to_str(key), unsafe { slice::from_raw_parts_mut(buf, len) })
+}
+
 pub fn do_sys_lseek(fd: usize, offset: isize, whence: usize) -> Result<usize> {
     let mut contexts = ::env().contexts.lock();
     let mut current = try!(contexts.current_mut());
@@ -118,13 +197,15 @@ pub fn do_sys_mkdir(path: *const u8, flags: usize) -> Result<usize> {
     ::env().mkdir(try!(Url::from_str(&path_string)), flags).and(Ok(0))
 }
 
-pub fn do_sys_open(path_c: *const u8, flags: usize) -> Result<usize> {
-    let contexts = ::env().contexts.lock();
-    let current = try!(contexts.current());
-    let path = current.canonicalize(c_string_to_str(path_c));
-    //debugln!("{}: {}: open {}", current.pid, current.name, path);
-    let url = try!(Url::from_str(&path));
+/// Open a canonical path and add it to the files of a context
+fn open_path(current: &Context, path: &str, flags: usize) -> Result<usize> {
+    let url = try!(Url::from_str(path));
     let resource = try!(::env().open(url, flags));
+    Ok(add_file(current, resource))
+}
+
+/// Give an opened resource the next file descriptor of a context
+fn add_file(current: &Context, resource: Box<Resource>) -> usize {
     let fd = current.next_fd();
     unsafe {
         (*current.files.get()).push(ContextFile {
@@ -132,14 +213,56 @@ pub fn do_sys_open(path_c: *const u8, flags: usize) -> Result<usize> {
             resource: resource,
         });
     }
-    Ok(fd)
+    fd
+}
+
+pub fn do_sys_open(path_c: *const u8, flags: usize) -> Result<usize> {
+    let contexts = ::env().contexts.lock();
+    let current = try!(contexts.current());
+    let path = current.canonicalize(c_string_to_str(path_c));
+    //debugln!("{}: {}: open {}", current.pid, current.name, path);
+    open_path(current, &path, flags)
+}
+
+/// Open a file by its node number. The path names the file system, and can be any path on it
+pub fn do_sys_open_node(path_c: *const u8, node: usize, flags: usize) -> Result<usize> {
+    let contexts = ::env().contexts.lock();
+    let current = try!(contexts.current());
+    let path = current.canonicalize(c_string_to_str(path_c));
+    let resource = try!(::env().open_node(try!(Url::from_str(&path)), node as u64, flags));
+    Ok(add_file(current, resource))
+}
+
+pub fn do_sys_openat(fd: usize, path_c: *const u8, flags: usize) -> Result<usize> {
+    let contexts = ::env().contexts.lock();
+    let current = try!(contexts.current());
+    let relative = c_string_to_str(path_c);
+    let path = if relative.starts_with('/') || relative.find(':').is_some() {
+        // Absolute paths ignore the directory, like POSIX
+        current.canonicalize(relative)
+    } else {
+        let resource = try!(current.get_file(fd));
+
+        let mut buf = [0; 4096];
+        let count = try!(resource.path(&mut buf));
+        let dir = unsafe { str::from_utf8_unchecked(&buf[..count]) };
+
+        let mut stat = Stat::default();
+        try!(::env().stat(try!(Url::from_str(dir)), &mut stat));
+        if stat.st_mode & MODE_DIR != MODE_DIR {
+            return Err(Error::new(ENOTDIR));
+        }
+
+        dir.trim_right_matches('/').to_string() + "/" + relative
+    };
+    open_path(current, &path, flags)
 }
 
-pub fn do_sys_pipe2(fds: *mut usize, _flags: usize) -> Result<usize> {
+pub fn do_sys_pipe2(fds: *mut usize, flags: usize) -> Result<usize> {
     let contexts = ::env().contexts.lock();
     let current = try!(contexts.current());
     if fds as usize > 0 {
-        let read = box PipeRead::new();
+        let read = box PipeRead::new(flags & O_NONBLOCK == O_NONBLOCK);
         let write = box PipeWrite::new(&read);
 
         unsafe {
@@ -162,6 +285,27 @@ pub fn do_sys_pipe2(fds: *mut usize, _flags: usize) -> Result<usize> {
     }
 }
 
+/// Read at an offset without moving the file position. The contexts lock is held throughout, so
+/// no other access to the file can happen in between the seeks
+pub fn do_sys_pread(fd: usize, buf: *mut u8, count: usize, offset: usize) -> Result<usize> {
+    let mut contexts = ::env().contexts.lock();
+    let mut current = try!(contexts.current_mut());
+    let mut resource = try!(current.get_file_mut(fd));
+
+    let original = try!(resource.seek(ResourceSeek::Current(0)));
+    // Seeking past the end could extend the file
+    let len = try!(resource.seek(ResourceSeek::End(0)));
+    let result = if offset < len {
+        resource.seek(ResourceSeek::Start(offset))
+            .and_then(|_| resource.read(unsafe { slice::from_raw_parts_mut(buf, count) }))
+    } else {
+        Ok(0)
+    };
+    try!(resource.seek(ResourceSeek::Start(original)));
+
+    result
+}
+
 pub fn do_sys_read(fd: usize, buf: *mut u8, count: usize) -> Result<usize> {
     let mut contexts = ::env().contexts.lock();
     let mut current = try!(contexts.current_mut());
@@ -169,6 +313,15 @@ pub fn do_sys_read(fd: usize, buf: *mut u8, count: usize) -> Result<usize> {
     resource.read(unsafe { slice::from_raw_parts_mut(buf, count) })
 }
 
+/// Hint that a range of a file will be read soon. The range is clamped to the file, so this only
+/// fails if the file descriptor is invalid
+pub fn do_sys_readahead(fd: usize, offset: usize, len: usize) -> Result<usize> {
+    let mut contexts = ::env().contexts.lock();
+    let mut current = try!(contexts.current_mut());
+    let mut resource = try!(current.get_file_mut(fd));
+    resource.readahead(offset, len).and(Ok(0))
+}
+
 pub fn do_sys_rmdir(path: *const u8) -> Result<usize> {
     let contexts = ::env().contexts.lock();
     let current = try!(contexts.current());
@@ -176,6 +329,14 @@ pub fn do_sys_rmdir(path: *const u8) -> Result<usize> {
     ::env().rmdir(try!(Url::from_str(&path_string))).and(Ok(0))
 }
 
+pub fn do_sys_setxattr(path: *const u8, key: *const u8, value: *const u8, len: usize) -> Result<usize> {
+    let contexts = ::env().contexts.lock();
+    let current = try!(contexts.current());
+    let path_string = current.canonicalize(c_string_to_str(path));
+    let url = try!(Url::from_str(&path_string));
+    ::env().setxattr(url, c_string_to_str(key), unsafe { slice::from_raw_parts(value, len) }).and(Ok(0))
+}
+
 pub fn do_sys_stat(path: *const u8, stat: *mut Stat) -> Result<usize> {
     let contexts = ::env().contexts.lock();
     let current = try!(contexts.current());
@@ -188,6 +349,14 @@ pub fn do_sys_stat(path: *const u8, stat: *mut Stat) -> Result<usize> {
     }
 }
 
+pub fn do_sys_rename(from: *const u8, to: *const u8) -> Result<usize> {
+    let contexts = ::env().contexts.lock();
+    let current = try!(contexts.current());
+    let from_string = current.canonicalize(c_string_to_str(from));
+    let to_string = current.canonicalize(c_string_to_str(to));
+    ::env().rename(try!(Url::from_str(&from_string)), try!(Url::from_str(&to_string))).and(Ok(0))
+}
+
 pub fn do_sys_unlink(path: *const u8) -> Result<usize> {
     let contexts = ::env().contexts.lock();
     let current = try!(contexts.current());
@@ -201,3 +370,60 @@ pub fn do_sys_write(fd: usize, buf: *const u8, count: usize) -> Result<usize> {
     let mut resource = try!(current.get_file_mut(fd));
     resource.write(unsafe { slice::from_raw_parts(buf, count) })
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use collections::vec::Vec;
+
+    use arch::context::ContextFile;
+
+    use fs::Resource;
+
+    use system::error::Result;
+
+    use super::replace_file;
+
+    /// A resource reading from bytes in memory
+    struct Bytes {
+        data: &'static [u8],
+        seek: usize,
+    }
+
+    impl Resource for Bytes {
+        fn dup(&self) -> Result<Box<Resource>> {
+            Ok(box Bytes {
+                data: self.data,
+                seek: self.seek,
+            })
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let mut count = 0;
+            for (b, d) in buf.iter_mut().zip(self.data[self.seek..].iter()) {
+                *b = *d;
+                count += 1;
+            }
+            self.seek += count;
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn redirect() {
+        let mut files = vec![
+            ContextFile { fd: 1, resource: box Bytes { data: b"stdout", seek: 0 } },
+            ContextFile { fd: 3, resource: box Bytes { data: b"redirected", seek: 0 } },
+        ];
+
+        let resource = files[1].resource.dup().unwrap();
+        replace_file(&mut files, 1, resource);
+        assert_eq!(files.iter().filter(|file| file.fd == 1).count(), 1);
+
+        let file = files.iter_mut().find(|file| file.fd == 1).unwrap();
+        let mut buf = [0; 16];
+        let count = file.resource.read(&mut buf).unwrap();
+        assert_eq!(&buf[..count], b"redirected");
+    }
+}
@@ -34,22 +34,34 @@ pub fn syscall_handle(regs: &mut Regs) {
         SYS_CLOSE => do_sys_close(regs.bx),
         SYS_CLOCK_GETTIME => do_sys_clock_gettime(regs.bx, regs.cx as *mut TimeSpec),
         SYS_DUP => do_sys_dup(regs.bx),
+        SYS_DUP2 => do_sys_dup2(regs.bx, regs.cx),
         SYS_EXECVE => do_sys_execve(regs.bx as *const u8, regs.cx as *const *const u8),
         SYS_EXIT => do_sys_exit(regs.bx),
+        SYS_FALLOCATE => do_sys_fallocate(regs.bx, regs.cx, regs.dx),
+        SYS_FCNTL => do_sys_fcntl(regs.bx, regs.cx, regs.dx),
+        SYS_FDATASYNC => do_sys_fdatasync(regs.bx),
         SYS_FPATH => do_sys_fpath(regs.bx, regs.cx as *mut u8, regs.dx),
         SYS_FSTAT => do_sys_fstat(regs.bx, regs.cx as *mut Stat),
         SYS_FSYNC => do_sys_fsync(regs.bx),
         SYS_FTRUNCATE => do_sys_ftruncate(regs.bx, regs.cx),
+        SYS_GETCWD => do_sys_getcwd(regs.bx as *mut u8, regs.cx),
         SYS_GETPID => do_sys_getpid(),
+        SYS_GETXATTR => do_sys_getxattr(regs.bx as *const u8, regs.cx as *const u8, regs.dx as *mut u8, regs.si),
         SYS_IOPL => do_sys_iopl(regs),
         // TODO: link
         SYS_LSEEK => do_sys_lseek(regs.bx, regs.cx as isize, regs.dx),
         SYS_MKDIR => do_sys_mkdir(regs.bx as *const u8, regs.cx),
         SYS_NANOSLEEP => do_sys_nanosleep(regs.bx as *const TimeSpec, regs.cx as *mut TimeSpec),
         SYS_OPEN => do_sys_open(regs.bx as *const u8, regs.cx),
+        SYS_OPENAT => do_sys_openat(regs.bx, regs.cx as *const u8, regs.dx),
+        SYS_OPEN_NODE => do_sys_open_node(regs.bx as *const u8, regs.cx, regs.dx),
         SYS_PIPE2 => do_sys_pipe2(regs.bx as *mut usize, regs.cx),
+        SYS_PREAD => do_sys_pread(regs.bx, regs.cx as *mut u8, regs.dx, regs.si),
         SYS_READ => do_sys_read(regs.bx, regs.cx as *mut u8, regs.dx),
+        SYS_READAHEAD => do_sys_readahead(regs.bx, regs.cx, regs.dx),
+        SYS_RENAME => do_sys_rename(regs.bx as *const u8, regs.cx as *const u8),
         SYS_RMDIR => do_sys_rmdir(regs.bx as *const u8),
+        SYS_SETXATTR => do_sys_setxattr(regs.bx as *const u8, regs.cx as *const u8, regs.dx as *const u8, regs.si),
         SYS_STAT => do_sys_stat(regs.bx as *const u8, regs.cx as *mut Stat),
         SYS_UNLINK => do_sys_unlink(regs.bx as *const u8),
         SYS_WAITPID => do_sys_waitpid(regs.bx as isize, regs.cx as *mut usize, regs.dx),
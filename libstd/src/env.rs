@@ -8,11 +8,11 @@ use ffi::OsString;
 use fs::File;
 use path::{Path, PathBuf};
 use string::{String, ToString};
-use sys_common::AsInner;
+use sys_common::{read_growing, AsInner};
 use vec::Vec;
 
 use system::error::ENOENT;
-use system::syscall::sys_chdir;
+use system::syscall::{sys_chdir, sys_getcwd};
 
 use io::{Error, Result};
 
@@ -82,8 +82,8 @@ fn get_path_from(location : &str) -> Result<PathBuf> {
 
 /// Method to return the current directory
 pub fn current_dir() -> Result<PathBuf> {
-    // Return the current path
-    get_path_from("./")
+    let buf = try!(read_growing(|buf| sys_getcwd(buf).map_err(|x| Error::from_sys(x))));
+    Ok(PathBuf::from(unsafe { String::from_utf8_unchecked(buf) }))
 }
 
 /// Method to return the home directory
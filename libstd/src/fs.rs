@@ -1,17 +1,33 @@
+use boxed::Box;
+use core::cmp;
+use core::hash::{Hasher, SipHasher};
 use core::ops::Deref;
+use collections::HashMap;
 use core_collections::borrow::ToOwned;
-use io::{self, Read, Error, Result, Write, Seek, SeekFrom};
+use env;
+use isize;
+use io::{self, Read, Error, ErrorKind, IoSlice, IoSliceMut, Result, Write, Seek, SeekFrom, Take};
 use os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use mem;
 use path::{PathBuf, Path};
 use str;
 use string::String;
-use sys_common::AsInner;
+use sync::StaticMutex;
+use sys_common::{read_growing, retry, AsInner};
 use vec::Vec;
 
-use system::syscall::{sys_open, sys_dup, sys_close, sys_fpath, sys_ftruncate, sys_read,
-              sys_write, sys_lseek, sys_fsync, sys_mkdir, sys_rmdir, sys_stat, sys_unlink};
-use system::syscall::{O_RDWR, O_RDONLY, O_WRONLY, O_APPEND, O_CREAT, O_TRUNC, MODE_DIR, MODE_FILE, SEEK_SET, SEEK_CUR, SEEK_END, Stat};
+use system::error::{E2BIG, EBADF, EBUSY, EINVAL, EIO, ENAMETOOLONG, ENOSYS, ENOTDIR, EOPNOTSUPP, ERANGE, EXDEV};
+use system::syscall::{sys_open, sys_open_node, sys_openat, sys_dup, sys_dup2, sys_close, sys_fcntl, sys_fpath, sys_fstat, sys_ftruncate, sys_getpid, sys_pread, sys_read, sys_readahead,
+              sys_write, sys_lseek, sys_fsync, sys_fdatasync, sys_mkdir, sys_rename, sys_rmdir, sys_stat, sys_unlink,
+              sys_getxattr, sys_setxattr};
+use system::syscall::{F_GETFL, F_SETFL, O_RDWR, O_RDONLY, O_WRONLY, O_APPEND, O_CREAT, O_NONBLOCK, O_TMPFILE, O_TRUNC, MODE_DIR, MODE_FILE, SEEK_SET, SEEK_CUR, SEEK_END, Stat};
+use system::syscall::{DIRENT_DIR, DIRENT_FILE, DIRENT_SYMLINK};
+
+/// The largest file that `File::read_mmap` reads into memory
+pub const READ_MMAP_MAX: u64 = 64 * 1024 * 1024;
+
+/// The longest path, in bytes, that is passed to the kernel
+pub const PATH_MAX: usize = 4096;
 
 /// A Unix-style file
 pub struct File {
@@ -22,22 +38,57 @@ pub struct File {
 impl File {
     /// Open a new file using a path
     pub fn open<P: AsRef<Path>>(path: P) -> Result<File> {
-        let path_str = path.as_ref().as_os_str().as_inner();
-        let mut path_c = path_str.to_owned();
-        path_c.push_str("\0");
-        unsafe {
-            sys_open(path_c.as_ptr(), O_RDONLY, 0).map(|fd| File::from_raw_fd(fd) )
-        }.map_err(|x| Error::from_sys(x))
+        let path_c = try!(c_path(path.as_ref()));
+        retry(|| unsafe { sys_open(path_c.as_ptr(), O_RDONLY, 0) })
+            .map(|fd| unsafe { File::from_raw_fd(fd) })
+            .map_err(|x| Error::from_sys(x).with_path(path.as_ref()))
     }
 
     /// Create a new file using a path
     pub fn create<P: AsRef<Path>>(path: P) -> Result<File> {
-        let path_str = path.as_ref().as_os_str().as_inner();
-        let mut path_c = path_str.to_owned();
-        path_c.push_str("\0");
+        let path_c = try!(c_path(path.as_ref()));
+        retry(|| unsafe { sys_open(path_c.as_ptr(), O_CREAT | O_RDWR | O_TRUNC, 0) })
+            .map(|fd| unsafe { File::from_raw_fd(fd) })
+            .map_err(|x| Error::from_sys(x).with_path(path.as_ref()))
+    }
+
+    /// Read at most `limit` bytes from the file, as for a header at its start. Reads go straight
+    /// to the file, so after the `Take` is unwrapped with `into_inner` the file is positioned just
+    /// past what was read, and can be read further or seeked as usual
+    pub fn take(self, limit: u64) -> Take<File> {
+        Read::take(self, limit)
+    }
+
+    /// Create an unnamed temporary file in a directory. It is not listed in the directory, and
+    /// its space is freed when the last handle to it is closed, so nothing is left behind after a
+    /// crash. On kernels without `O_TMPFILE`, a named file is created and removed straight away
+    pub fn tmpfile_in<P: AsRef<Path>>(dir: P) -> Result<File> {
+        let path_c = try!(c_path(dir.as_ref()));
+        match retry(|| unsafe { sys_open(path_c.as_ptr(), O_TMPFILE | O_RDWR, 0) }) {
+            Ok(fd) => Ok(unsafe { File::from_raw_fd(fd) }),
+            Err(ref err) if err.errno == EINVAL || err.errno == ENOSYS || err.errno == EOPNOTSUPP => {
+                tmpfile_unlinked(dir.as_ref())
+            },
+            Err(err) => Err(Error::from_sys(err).with_path(dir.as_ref())),
+        }
+    }
+
+    /// Open a file by its node number, for tools like fsck that do not know its path. `fs` is any
+    /// path on the file system holding the node, such as `file:/`. Returns ENOENT if no node is
+    /// stored at that number
+    pub fn open_ino<P: AsRef<Path>>(fs: P, ino: u64) -> Result<File> {
+        let path_c = try!(c_path(fs.as_ref()));
+        retry(|| unsafe { sys_open_node(path_c.as_ptr(), ino as usize, O_RDWR) })
+            .map(|fd| unsafe { File::from_raw_fd(fd) })
+            .map_err(|x| Error::from_sys(x).with_path(fs.as_ref()))
+    }
+
+    /// Open a path relative to this directory. Absolute paths ignore the directory
+    pub fn open_at<P: AsRef<Path>>(&self, relative: P, options: &OpenOptions) -> Result<File> {
+        let path_c = try!(c_path(relative.as_ref()));
         unsafe {
-            sys_open(path_c.as_ptr(), O_CREAT | O_RDWR | O_TRUNC, 0).map(|fd| File::from_raw_fd(fd) )
-        }.map_err(|x| Error::from_sys(x))
+            sys_openat(self.fd, path_c.as_ptr(), options.flags()).map(|fd| File::from_raw_fd(fd))
+        }.map_err(|x| Error::from_sys(x).with_path(relative.as_ref()))
     }
 
     /// Duplicate the file
@@ -45,13 +96,20 @@ impl File {
         sys_dup(self.fd).map(|fd| unsafe { File::from_raw_fd(fd) }).map_err(|x| Error::from_sys(x))
     }
 
-    /// Get the canonical path of the file
-    pub fn path(&self) -> Result<PathBuf> {
-        let mut buf: [u8; 4096] = [0; 4096];
-        match sys_fpath(self.fd, &mut buf) {
-            Ok(count) => Ok(PathBuf::from(unsafe { String::from_utf8_unchecked(Vec::from(&buf[0..count])) })),
-            Err(err) => Err(Error::from_sys(err)),
+    /// Make `target_fd` refer to this file, closing the file it referred to before. Unlike `dup`,
+    /// which picks the lowest free descriptor, this is what redirections such as `2>&1` need
+    pub fn duplicate_to(&self, target_fd: RawFd) -> Result<()> {
+        if target_fd as isize < 0 {
+            return Err(Error::new_sys(EBADF));
         }
+
+        sys_dup2(self.fd, target_fd).and(Ok(())).map_err(|x| Error::from_sys(x))
+    }
+
+    /// Get the canonical path of the file. The path is not cut short however long it is
+    pub fn path(&self) -> Result<PathBuf> {
+        let buf = try!(read_growing(|buf| sys_fpath(self.fd, buf).map_err(|x| Error::from_sys(x))));
+        Ok(PathBuf::from(unsafe { String::from_utf8_unchecked(buf) }))
     }
 
     /// Flush the file data and metadata
@@ -59,15 +117,193 @@ impl File {
         sys_fsync(self.fd).and(Ok(())).map_err(|x| Error::from_sys(x))
     }
 
-    /// Flush the file data
+    /// Flush the file data, and only the metadata needed to read it back
     pub fn sync_data(&mut self) -> Result<()> {
-        sys_fsync(self.fd).and(Ok(())).map_err(|x| Error::from_sys(x))
+        sys_fdatasync(self.fd).and(Ok(())).map_err(|x| Error::from_sys(x))
+    }
+
+    /// Read into several buffers in turn, returning the total number of bytes read. The kernel has
+    /// no vectored read, so this reads each buffer separately and stops at the first short read
+    pub fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> Result<usize> {
+        read_vectored(self, bufs)
+    }
+
+    /// Write several buffers in turn, returning the total number of bytes written. The kernel has
+    /// no vectored write, so this writes each buffer separately and stops at the first short write
+    pub fn write_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize> {
+        write_vectored(self, bufs)
+    }
+
+    /// Get the length of the file by seeking to its end and back, for files whose metadata is
+    /// not available, such as those from `from_raw_fd`
+    pub fn stream_len(&mut self) -> Result<u64> {
+        stream_len(self)
+    }
+
+    /// Check if writes go to the end of the file, wherever the file is seeked to
+    pub fn is_append(&self) -> Result<bool> {
+        sys_fcntl(self.fd, F_GETFL, 0).map(|flags| flags & O_APPEND == O_APPEND).map_err(|x| Error::from_sys(x))
+    }
+
+    /// Make writes go to the end of the file, or stop them from doing so, without opening it
+    /// again. Returns ENOSYS if the flags of the file can not be changed
+    pub fn set_append(&self, append: bool) -> Result<()> {
+        let flags = try!(sys_fcntl(self.fd, F_GETFL, 0).map_err(|x| Error::from_sys(x)));
+        let flags = if append {
+            flags | O_APPEND
+        } else {
+            flags & ! O_APPEND
+        };
+        sys_fcntl(self.fd, F_SETFL, flags).and(Ok(())).map_err(|x| Error::from_sys(x))
+    }
+
+    /// Hint that `len` bytes from `offset` will be read soon, so that they can be loaded ahead of
+    /// time. The range is clamped to the end of the file. If the kernel has no readahead, the
+    /// range is read and the bytes are thrown away
+    pub fn readahead(&self, offset: u64, len: u64) -> Result<()> {
+        if offset > isize::MAX as u64 {
+            return Ok(());
+        }
+        let len = cmp::min(len, isize::MAX as u64 - offset);
+
+        match sys_readahead(self.fd, offset as usize, len as usize) {
+            Err(ref err) if err.errno == ENOSYS => readahead(|buf, offset| self.read_at(buf, offset), offset, len),
+            result => result.and(Ok(())).map_err(|x| Error::from_sys(x)),
+        }
+    }
+
+    /// Read the whole file into one buffer, for random access without seeking. This is a copy
+    /// made when it is called, not a mapping, so later writes to the file are not seen. Returns
+    /// E2BIG if the file is longer than `READ_MMAP_MAX` bytes
+    pub fn read_mmap(&self) -> Result<Box<[u8]>> {
+        let mut stat = Stat::default();
+        try!(sys_fstat(self.fd, &mut stat).map_err(|x| Error::from_sys(x)));
+        read_all_at(|buf, offset| self.read_at(buf, offset), stat.st_size, READ_MMAP_MAX)
+    }
+
+    /// Read without waiting, for an event loop polling many files. Returns None if the file was
+    /// opened non-blocking and has nothing to read yet. Files that never wait, like those on disk,
+    /// always give Some
+    pub fn read_nonblocking(&self, buf: &mut [u8]) -> Result<Option<usize>> {
+        would_block(retry(|| sys_read(self.fd, buf)).map_err(|x| Error::from_sys(x)))
     }
 
     /// Truncates the file
     pub fn set_len(&mut self, size: u64) -> Result<()> {
         sys_ftruncate(self.fd, size as usize).and(Ok(())).map_err(|x| Error::from_sys(x))
     }
+
+    /// Seek back to the start of the file
+    pub fn rewind(&mut self) -> Result<()> {
+        rewind_stream(self)
+    }
+
+    /// Iterate over the rest of the file in chunks of `size` bytes. Every chunk is full except
+    /// possibly the last one. Panics if `size` is 0
+    pub fn chunks(&mut self, size: usize) -> Chunks {
+        assert!(size != 0);
+        Chunks {
+            file: self,
+            size: size,
+        }
+    }
+
+    /// Read exactly enough bytes to fill `buf`, starting at `offset`, without moving the file
+    /// position. Returns `UnexpectedEof` if the file ends first
+    pub fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
+        while ! buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+                Ok(count) => {
+                    let tmp = buf;
+                    buf = &mut tmp[count..];
+                    offset += count as u64;
+                },
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read at an offset without moving the file position, emulated with seeks if the kernel has
+    /// no pread
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        if offset > isize::MAX as u64 {
+            return Err(Error::new_sys(EINVAL));
+        }
+
+        match sys_pread(self.fd, buf, offset as usize) {
+            Err(ref err) if err.errno == ENOSYS => {
+                let _guard = PREAD_LOCK.lock();
+
+                let original = try!(sys_lseek(self.fd, 0, SEEK_CUR).map_err(|x| Error::from_sys(x)));
+                try!(sys_lseek(self.fd, offset as isize, SEEK_SET).map_err(|x| Error::from_sys(x)));
+                let result = sys_read(self.fd, buf);
+                try!(sys_lseek(self.fd, original as isize, SEEK_SET).map_err(|x| Error::from_sys(x)));
+
+                result.map_err(|x| Error::from_sys(x))
+            },
+            result => result.map_err(|x| Error::from_sys(x)),
+        }
+    }
+}
+
+/// An iterator over the contents of a file in fixed size chunks, returned by `File::chunks`
+pub struct Chunks<'a> {
+    file: &'a mut File,
+    size: usize,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>>> {
+        let mut chunk = vec![0; self.size];
+        let mut len = 0;
+        while len < chunk.len() {
+            match self.file.read(&mut chunk[len..]) {
+                Ok(0) => break,
+                Ok(count) => len += count,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        if len == 0 {
+            None
+        } else {
+            chunk.truncate(len);
+            Some(Ok(chunk))
+        }
+    }
+}
+
+/// Held across the seeks and read that emulate pread on kernels without it
+static PREAD_LOCK: StaticMutex = StaticMutex::new();
+
+/// Held while taking a number for the name of a temporary file on kernels without `O_TMPFILE`
+static TMPFILE_LOCK: StaticMutex = StaticMutex::new();
+static mut TMPFILE_COUNT: usize = 0;
+
+/// Create a file with a name no other file in `dir` has, and remove it while keeping it open
+fn tmpfile_unlinked(dir: &Path) -> Result<File> {
+    let pid = sys_getpid().unwrap_or(0);
+    loop {
+        let count = unsafe {
+            let _guard = TMPFILE_LOCK.lock();
+            TMPFILE_COUNT += 1;
+            TMPFILE_COUNT
+        };
+
+        let path = dir.join(format!(".tmp-{}-{}", pid, count));
+        if metadata(&path).is_err() {
+            let file = try!(File::create(&path));
+            try!(remove_file(&path));
+            return Ok(file);
+        }
+    }
 }
 
 impl AsRawFd for File {
@@ -92,15 +328,133 @@ impl IntoRawFd for File {
     }
 }
 
+/// Copy a path with a null terminator for a syscall. Returns ENAMETOOLONG if it is longer than
+/// `PATH_MAX` bytes, without asking the kernel
+fn c_path(path: &Path) -> Result<String> {
+    let path_str = path.as_os_str().as_inner();
+    if path_str.len() > PATH_MAX {
+        return Err(Error::new_sys(ENAMETOOLONG).with_path(path));
+    }
+
+    let mut path_c = path_str.to_owned();
+    path_c.push_str("\0");
+    Ok(path_c)
+}
+
+/// Turn a `WouldBlock` error into None
+fn would_block<T>(result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Seek to the end to find the length, then back to the original position. The original position
+/// is restored even if seeking to the end fails, and the first error is returned
+fn stream_len<S: Seek>(stream: &mut S) -> Result<u64> {
+    let original = try!(stream.seek(SeekFrom::Current(0)));
+    let end = stream.seek(SeekFrom::End(0));
+    let restore = stream.seek(SeekFrom::Start(original));
+    let len = try!(end);
+    try!(restore);
+    Ok(len)
+}
+
+/// Read `len` bytes from the start into one buffer, failing with E2BIG if `len` is more than
+/// `max`. A file that has shrunk since its length was taken gives a shorter buffer
+fn read_all_at<F: FnMut(&mut [u8], u64) -> Result<usize>>(mut read_at: F, len: u64, max: u64) -> Result<Box<[u8]>> {
+    if len > max {
+        return Err(Error::new_sys(E2BIG));
+    }
+
+    let mut buf = vec![0; len as usize];
+    let mut pos = 0;
+    while pos < buf.len() {
+        match read_at(&mut buf[pos..], pos as u64) {
+            Ok(0) => break,
+            Ok(count) => pos += count,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    buf.truncate(pos);
+
+    Ok(buf.into_boxed_slice())
+}
+
+/// Emulate readahead by reading `len` bytes from `offset` in chunks and discarding them, stopping
+/// early at the end of the file
+fn readahead<F: FnMut(&mut [u8], u64) -> Result<usize>>(mut read_at: F, mut offset: u64, len: u64) -> Result<()> {
+    let mut buf = [0; 4096];
+    let end = offset + len;
+    while offset < end {
+        let count = cmp::min(buf.len() as u64, end - offset) as usize;
+        match read_at(&mut buf[..count], offset) {
+            Ok(0) => break,
+            Ok(count) => offset += count as u64,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Emulate a vectored read. An error is only returned if nothing was read, otherwise the count so
+/// far is returned, like a short read
+fn read_vectored<R: Read>(reader: &mut R, bufs: &mut [IoSliceMut]) -> Result<usize> {
+    let mut total = 0;
+    for buf in bufs.iter_mut() {
+        match reader.read(buf) {
+            Ok(count) => {
+                total += count;
+                if count < buf.len() {
+                    break;
+                }
+            },
+            Err(err) => if total == 0 {
+                return Err(err);
+            } else {
+                break;
+            },
+        }
+    }
+    Ok(total)
+}
+
+/// Emulate a vectored write. An error is only returned if nothing was written, otherwise the count
+/// so far is returned, like a short write
+fn write_vectored<W: Write>(writer: &mut W, bufs: &[IoSlice]) -> Result<usize> {
+    let mut total = 0;
+    for buf in bufs.iter() {
+        match writer.write(buf) {
+            Ok(count) => {
+                total += count;
+                if count < buf.len() {
+                    break;
+                }
+            },
+            Err(err) => if total == 0 {
+                return Err(err);
+            } else {
+                break;
+            },
+        }
+    }
+    Ok(total)
+}
+
 impl Read for File {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        sys_read(self.fd, buf).map_err(|x| Error::from_sys(x))
+        retry(|| sys_read(self.fd, buf)).map_err(|x| Error::from_sys(x))
     }
 }
 
 impl Write for File {
+    /// Write with a single syscall, which writes less than the whole buffer when the disk fills
+    /// up. `write_all` turns a write of nothing into a `WriteZero` error
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        sys_write(self.fd, buf).map_err(|x| Error::from_sys(x))
+        retry(|| sys_write(self.fd, buf)).map_err(|x| Error::from_sys(x))
     }
 
     // TODO buffered fs
@@ -111,6 +465,10 @@ impl Seek for File {
     /// Seek a given position
     fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
         let (whence, offset) = match pos {
+            // Offsets that do not fit in the syscall argument would otherwise be truncated
+            SeekFrom::Start(offset) if offset > isize::MAX as u64 => return Err(Error::new_sys(EINVAL)),
+            SeekFrom::Current(offset) | SeekFrom::End(offset)
+                if offset > isize::MAX as i64 || offset < isize::MIN as i64 => return Err(Error::new_sys(EINVAL)),
             SeekFrom::Start(offset) => (SEEK_SET, offset as isize),
             SeekFrom::Current(offset) => (SEEK_CUR, offset as isize),
             SeekFrom::End(offset) => (SEEK_END, offset as isize),
@@ -147,6 +505,9 @@ pub struct OpenOptions {
     append: bool,
     create: bool,
     truncate: bool,
+    create_parents: bool,
+    nonblocking: bool,
+    custom_flags: usize,
 }
 
 impl OpenOptions {
@@ -157,6 +518,9 @@ impl OpenOptions {
             append: false,
             create: false,
             truncate: false,
+            create_parents: false,
+            nonblocking: false,
+            custom_flags: 0,
         }
     }
 
@@ -185,8 +549,29 @@ impl OpenOptions {
         self
     }
 
-    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<File> {
-        let mut flags = 0;
+    /// Create the missing parent directories of the path before opening it. This only applies
+    /// when `create` is set
+    pub fn create_parents(&mut self, create_parents: bool) -> &mut OpenOptions {
+        self.create_parents = create_parents;
+        self
+    }
+
+    /// Make reads and writes that would wait return a `WouldBlock` error instead. This is
+    /// meaningful for devices and pipes, regular files never wait
+    pub fn nonblocking(&mut self, nonblocking: bool) -> &mut OpenOptions {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    /// Set additional flags to pass to open, such as `O_NONBLOCK`
+    pub fn custom_flags(&mut self, flags: usize) -> &mut OpenOptions {
+        self.custom_flags = flags;
+        self
+    }
+
+    /// Get the flags to pass to open
+    fn flags(&self) -> usize {
+        let mut flags = self.custom_flags;
 
         if self.read && self.write {
             flags |= O_RDWR;
@@ -208,15 +593,28 @@ impl OpenOptions {
             flags |= O_TRUNC;
         }
 
-        let path_str = path.as_ref().as_os_str().as_inner();
-        let mut path_c = path_str.to_owned();
-        path_c.push_str("\0");
-        unsafe {
-            sys_open(path_c.as_ptr(), flags, 0).map(|fd| File::from_raw_fd(fd))
-        }.map_err(|x| Error::from_sys(x))
+        if self.nonblocking {
+            flags |= O_NONBLOCK;
+        }
+
+        flags
+    }
+
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<File> {
+        if self.create && self.create_parents {
+            if let Some(parent) = path.as_ref().parent() {
+                try!(create_dir_all(parent));
+            }
+        }
+
+        let path_c = try!(c_path(path.as_ref()));
+        retry(|| unsafe { sys_open(path_c.as_ptr(), self.flags(), 0) })
+            .map(|fd| unsafe { File::from_raw_fd(fd) })
+            .map_err(|x| Error::from_sys(x).with_path(path.as_ref()))
     }
 }
 
+#[derive(Copy, Clone)]
 pub struct Metadata {
     stat: Stat
 }
@@ -240,6 +638,16 @@ impl Metadata {
     pub fn len(&self) -> u64 {
         self.stat.st_size
     }
+
+    /// The number of blocks allocated to the file, in units of `block_size`
+    pub fn blocks(&self) -> u64 {
+        self.stat.st_blocks
+    }
+
+    /// The size of the blocks reported by `blocks`
+    pub fn block_size(&self) -> u64 {
+        512
+    }
 }
 
 pub struct DirEntry {
@@ -269,37 +677,87 @@ pub struct ReadDir {
     file: File,
 }
 
+impl ReadDir {
+    /// Go back to the first entry, so that the directory can be listed again without opening it
+    /// again. The listing is the one made when the directory was opened, so changes made since
+    /// then are not included. Open the directory again to see them
+    pub fn rewind(&mut self) -> Result<()> {
+        rewind_stream(&mut self.file)
+    }
+}
+
+/// Seek a file or directory listing back to its start
+fn rewind_stream<S: Seek>(stream: &mut S) -> Result<()> {
+    stream.seek(SeekFrom::Start(0)).and(Ok(()))
+}
+
 impl Iterator for ReadDir {
     type Item = Result<DirEntry>;
     fn next(&mut self) -> Option<Result<DirEntry>> {
-        let mut path = String::new();
-        let mut buf: [u8; 1] = [0; 1];
-        loop {
-            match self.file.read(&mut buf) {
-                Ok(0) => break,
-                Ok(count) => {
-                    if buf[0] == 10 {
-                        break;
-                    } else {
-                        path.push_str(unsafe { str::from_utf8_unchecked(&buf[..count]) });
-                    }
+        next_dir_entry(&mut self.file)
+    }
+}
+
+/// Read the next entry of a directory listing
+fn next_dir_entry<R: Read>(reader: &mut R) -> Option<Result<DirEntry>> {
+    match read_dir_line(reader) {
+        Some(Ok(line)) => Some(Ok(parse_dir_entry(line))),
+        Some(Err(err)) => Some(Err(err)),
+        None => None,
+    }
+}
+
+/// Parse a line of a directory listing. Entries start with a type byte, but listings without one
+/// are still read, taking a trailing slash to mean a directory
+fn parse_dir_entry(mut line: String) -> DirEntry {
+    let kind = match line.as_bytes().first() {
+        Some(&b) if b == DIRENT_FILE || b == DIRENT_DIR || b == DIRENT_SYMLINK => Some(b),
+        _ => None,
+    };
+    if kind.is_some() {
+        line.remove(0);
+    }
+
+    let slash = line.ends_with('/');
+    if slash {
+        line.pop();
+    }
+
+    let dir = match kind {
+        Some(kind) => kind == DIRENT_DIR,
+        None => slash,
+    };
+    DirEntry {
+        path: line,
+        dir: dir,
+        file: kind.map_or(! dir, |kind| kind == DIRENT_FILE),
+    }
+}
+
+/// Read the next name from a directory listing. Returns None at the end of the listing, and an
+/// error if the listing could not be read, so that a partial listing is not mistaken for a
+/// complete one
+fn read_dir_line<R: Read>(reader: &mut R) -> Option<Result<String>> {
+    let mut path = String::new();
+    let mut buf: [u8; 1] = [0; 1];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(count) => {
+                if buf[0] == 10 {
+                    break;
+                } else {
+                    path.push_str(unsafe { str::from_utf8_unchecked(&buf[..count]) });
                 }
-                Err(_err) => break,
             }
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Some(Err(err)),
         }
-        if path.is_empty() {
-            None
-        } else {
-            let dir = path.ends_with('/');
-            if dir {
-                path.pop();
-            }
-            Some(Ok(DirEntry {
-                path: path,
-                dir: dir,
-                file: !dir,
-            }))
-        }
+    }
+    if path.is_empty() {
+        None
+    } else {
+        Some(Ok(path))
     }
 }
 
@@ -316,30 +774,239 @@ pub fn canonicalize<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
     }
 }
 
-pub fn metadata<P: AsRef<Path>>(path: P) -> Result<Metadata> {
-    let mut stat = Stat {
-        st_mode: 0,
-        st_size: 0
-    };
+/// Make a path absolute without touching the disk. A path without a scheme is joined onto the
+/// current directory, or onto the root of its scheme if it starts with '/'. `.` and `..` are
+/// resolved lexically, so symlinks are not followed and the path does not need to exist
+pub fn absolute<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
     let path_str = path.as_ref().as_os_str().as_inner();
-    let mut path_c = path_str.to_owned();
-    path_c.push_str("\0");
+    let joined = if has_scheme(path_str) {
+        normalize(path_str, "")
+    } else {
+        let cwd = try!(env::current_dir());
+        normalize(path_str, cwd.as_os_str().as_inner())
+    };
+    Ok(PathBuf::from(joined))
+}
+
+/// Whether a path names its scheme, like `file:/home`
+fn has_scheme(path: &str) -> bool {
+    match path.find(':') {
+        Some(colon) => path.find('/').map_or(true, |slash| colon < slash),
+        None => false,
+    }
+}
+
+/// Join `path` onto the working directory `cwd` unless it names a scheme, and resolve `.` and `..`
+/// lexically. `..` at the root of a scheme stays at the root
+fn normalize(path: &str, cwd: &str) -> String {
+    let (scheme, reference) = if has_scheme(path) {
+        let colon = path.find(':').unwrap();
+        (&path[..colon + 1], path[colon + 1..].to_owned())
+    } else {
+        let colon = cwd.find(':').map_or(0, |i| i + 1);
+        if path.starts_with('/') {
+            (&cwd[..colon], path.to_owned())
+        } else {
+            let mut reference = cwd[colon..].to_owned();
+            if !reference.ends_with('/') {
+                reference.push('/');
+            }
+            reference.push_str(path);
+            (&cwd[..colon], reference)
+        }
+    };
+
+    let mut parts: Vec<&str> = Vec::new();
+    let mut dir = false;
+    for part in reference.split('/') {
+        match part {
+            "" => (),
+            "." => dir = true,
+            ".." => {
+                parts.pop();
+                dir = true;
+            },
+            _ => {
+                parts.push(part);
+                dir = false;
+            }
+        }
+    }
+    dir = dir || reference.ends_with('/');
+
+    let mut normalized = scheme.to_owned();
+    normalized.push('/');
+    for part in parts.iter() {
+        normalized.push_str(part);
+        normalized.push('/');
+    }
+    if !dir && !parts.is_empty() {
+        normalized.pop();
+    }
+    normalized
+}
+
+/// The initial capacity used by `read` when the size of the file is unknown
+const READ_DEFAULT_CAPACITY: usize = 8192;
+
+/// Read until EOF, starting with `hint` bytes of space and doubling the space when it runs out
+fn read_to_end_hint<R: Read>(r: &mut R, buf: &mut Vec<u8>, hint: usize) -> Result<usize> {
+    let start_len = buf.len();
+    let mut len = start_len;
+
+    // One extra byte lets a file of exactly `hint` bytes hit EOF without growing
+    let initial = if hint > 0 {
+        hint + 1
+    } else {
+        READ_DEFAULT_CAPACITY
+    };
+    buf.resize(start_len + initial, 0);
+
+    let ret;
+    loop {
+        if len == buf.len() {
+            let grow = len - start_len;
+            buf.resize(len + grow, 0);
+        }
+
+        match r.read(&mut buf[len..]) {
+            Ok(0) => {
+                ret = Ok(len - start_len);
+                break;
+            }
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                ret = Err(e);
+                break;
+            }
+        }
+    }
+
+    buf.truncate(len);
+    ret
+}
+
+/// Read the entire contents of a file
+///
+/// The length from the metadata is only a hint, reading continues past it if the file is larger
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    let hint = metadata(path.as_ref()).map(|metadata| metadata.len() as usize).unwrap_or(0);
+
+    let mut file = try!(File::open(path));
+    let mut buf = Vec::new();
+    try!(read_to_end_hint(&mut file, &mut buf, hint));
+    Ok(buf)
+}
+
+/// Read the entire contents of a file, failing with E2BIG if it is longer than `max` bytes. The
+/// bound is checked against the bytes actually read rather than the length in the metadata, so a
+/// file that grows while it is read cannot exhaust memory
+pub fn read_bounded<P: AsRef<Path>>(path: P, max: usize) -> Result<Vec<u8>> {
+    let hint = metadata(path.as_ref()).map(|metadata| metadata.len() as usize).unwrap_or(0);
+
+    let mut file = try!(File::open(path.as_ref()));
+    read_to_end_bounded(&mut file, hint, max).map_err(|err| err.with_path(path.as_ref()))
+}
+
+/// Read until EOF, failing with E2BIG once more than `max` bytes have been read
+fn read_to_end_bounded<R: Read>(r: &mut R, hint: usize, max: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    // One byte past the bound tells a file of exactly `max` bytes from a longer one
+    let limit = (max as u64).saturating_add(1);
+    try!(read_to_end_hint(&mut r.by_ref().take(limit), &mut buf, cmp::min(hint, max)));
+    if buf.len() > max {
+        Err(Error::new_sys(E2BIG))
+    } else {
+        Ok(buf)
+    }
+}
+
+/// Get the metadata of a path with a single stat, without opening it. This works on files and
+/// directories that cannot be opened for reading, and is what `Path::is_dir` and friends use
+pub fn metadata<P: AsRef<Path>>(path: P) -> Result<Metadata> {
+    let mut stat = Stat::default();
+    let path_c = try!(c_path(path.as_ref()));
     unsafe {
-        try!(sys_stat(path_c.as_ptr(), &mut stat).map_err(|x| Error::from_sys(x)));
+        try!(sys_stat(path_c.as_ptr(), &mut stat).map_err(|x| Error::from_sys(x).with_path(path.as_ref())));
     }
     Ok(Metadata {
         stat: stat
     })
 }
 
+/// A cache of file metadata for callers that know the paths they query are stable. Nothing is
+/// invalidated automatically, changed paths must be passed to `invalidate`
+pub struct MetadataCache {
+    entries: HashMap<PathBuf, Metadata>,
+}
+
+impl MetadataCache {
+    pub fn new() -> MetadataCache {
+        MetadataCache {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Entries are keyed by the path with redundant separators and `.` components removed, so
+    /// equivalent spellings of a path share an entry without needing a syscall
+    fn key(path: &Path) -> PathBuf {
+        let mut key = PathBuf::new();
+        for component in path.components() {
+            key.push(component.as_os_str());
+        }
+        key
+    }
+
+    /// Get the metadata of a path, calling `metadata` only if it is not cached
+    pub fn get<P: AsRef<Path>>(&mut self, path: P) -> Result<Metadata> {
+        let key = MetadataCache::key(path.as_ref());
+        if let Some(cached) = self.entries.get(&key) {
+            return Ok(*cached);
+        }
+
+        let fresh = try!(metadata(&key));
+        self.entries.insert(key, fresh);
+        Ok(fresh)
+    }
+
+    /// Forget the cached metadata of a path
+    pub fn invalidate<P: AsRef<Path>>(&mut self, path: P) {
+        self.entries.remove(&MetadataCache::key(path.as_ref()));
+    }
+
+    /// Forget all cached metadata
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
 /// Create a new directory, using a path
 /// The default mode of the directory is 744
 pub fn create_dir<P: AsRef<Path>>(path: P) -> Result<()> {
-    let path_str = path.as_ref().as_os_str().as_inner();
-    let mut path_c = path_str.to_owned();
-    path_c.push_str("\0");
+    let path_c = try!(c_path(path.as_ref()));
     unsafe {
-        sys_mkdir(path_c.as_ptr(), 755).and(Ok(())).map_err(|x| Error::from_sys(x))
+        sys_mkdir(path_c.as_ptr(), 755).and(Ok(())).map_err(|x| Error::from_sys(x).with_path(path.as_ref()))
+    }
+}
+
+/// Create a directory and all of its missing parents. Directories that already exist are left
+/// alone
+pub fn create_dir_all<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    if path == Path::new("") || path.is_dir() {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        try!(create_dir_all(parent));
+    }
+
+    match create_dir(path) {
+        Ok(()) => Ok(()),
+        // Created by someone else in the meantime
+        Err(_) if path.is_dir() => Ok(()),
+        Err(err) => Err(err),
     }
 }
 
@@ -349,29 +1016,594 @@ pub fn copy<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<u64> {
     io::copy(&mut infile, &mut outfile)
 }
 
+/// A reader that hashes the bytes read through it
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: SipHasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner: inner,
+            hasher: SipHasher::new(),
+        }
+    }
+
+    fn hash(&self) -> u64 {
+        self.hasher.finish()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let count = try!(self.inner.read(buf));
+        self.hasher.write(&buf[..count]);
+        Ok(count)
+    }
+}
+
+/// Copy a file, then read the copy back and compare checksums, returning `InvalidData` if they
+/// differ. The source checksum is computed from the bytes copied, so the source is read once
+pub fn copy_verified<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<u64> {
+    let mut infile = HashingReader::new(try!(File::open(from)));
+    let written = {
+        let mut outfile = try!(File::create(to.as_ref()));
+        let written = try!(io::copy(&mut infile, &mut outfile));
+        try!(outfile.sync_all());
+        written
+    };
+
+    let mut copied = HashingReader::new(try!(File::open(to)));
+    let read = try!(io::copy(&mut copied, &mut io::sink()));
+
+    if read != written || copied.hash() != infile.hash() {
+        return Err(Error::new(ErrorKind::InvalidData, "copy does not match source"));
+    }
+
+    Ok(written)
+}
+
+/// The size of the chunks copied by `copy_with_progress`
+const COPY_CHUNK_SIZE: usize = 65536;
+
+/// Copy a file, calling `progress` with the total number of bytes copied after each chunk
+///
+/// `progress` is not called again once an error aborts the copy
+pub fn copy_with_progress<P: AsRef<Path>, Q: AsRef<Path>, F: FnMut(u64)>(from: P, to: Q, mut progress: F) -> Result<u64> {
+    let mut infile = try!(File::open(from));
+    let mut outfile = try!(File::create(to));
+
+    let mut buf = vec![0; COPY_CHUNK_SIZE];
+    let mut written = 0;
+    loop {
+        let len = match infile.read(&mut buf) {
+            Ok(0) => return Ok(written),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        try!(outfile.write_all(&buf[..len]));
+        written += len as u64;
+        progress(written);
+    }
+}
+
+/// Open a file, retrying when the open fails with EIO or EBUSY, which flaky removable media may
+/// return for a while. `backoff` is called with the number of failed attempts before each retry,
+/// and should wait. After `attempts` failures the last error is returned. Other errors, such as
+/// ENOENT, are returned at once
+pub fn open_retry<P: AsRef<Path>, F: FnMut(usize)>(path: P, attempts: usize, backoff: F) -> Result<File> {
+    retry_transient(attempts, || File::open(path.as_ref()), backoff)
+}
+
+/// Call `op` up to `attempts` times while it fails with a transient error
+fn retry_transient<T, O, F>(attempts: usize, mut op: O, mut backoff: F) -> Result<T>
+    where O: FnMut() -> Result<T>, F: FnMut(usize)
+{
+    let mut failed = 0;
+    loop {
+        match op() {
+            Err(err) => {
+                failed += 1;
+                let transient = match err.raw_os_error() {
+                    Some(EIO) | Some(EBUSY) => true,
+                    _ => false,
+                };
+                if ! transient || failed >= attempts {
+                    return Err(err);
+                }
+                backoff(failed);
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Rename a file or directory, replacing the destination file if it exists. Within a scheme that
+/// supports renaming, the destination is replaced atomically, so it always refers to either the
+/// old or the new file, and handles open on the old file keep reading its contents. A directory
+/// can only replace an empty directory, otherwise ENOTEMPTY is returned. Renames between schemes,
+/// or in schemes that do not support renaming, fall back to copying and removing, which is not
+/// atomic
 pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
-    try!(copy(Path::new(from.as_ref()), to));
-    remove_file(from)
+    let from_c = try!(c_path(from.as_ref()));
+    let to_c = try!(c_path(to.as_ref()));
+
+    match unsafe { sys_rename(from_c.as_ptr(), to_c.as_ptr()) } {
+        Ok(_) => Ok(()),
+        Err(ref err) if err.errno == ENOSYS || err.errno == EXDEV => {
+            try!(copy(Path::new(from.as_ref()), to));
+            remove_file(from)
+        },
+        Err(err) => Err(Error::from_sys(err).with_path(from.as_ref())),
+    }
 }
 
+/// Read the entries of a directory. A path that does not exist or is not a directory is an error
+/// here, rather than an empty listing, so that an empty directory can be told apart from a
+/// mistyped path
 pub fn read_dir<P: AsRef<Path>>(path: P) -> Result<ReadDir> {
+    try!(check_dir(path.as_ref(), metadata(path.as_ref())));
     File::open(path).map(|file| ReadDir { file: file })
 }
 
+/// Read the subdirectories of a directory. The type of each entry comes from the listing, so no
+/// entry is stat'ed
+pub fn read_subdirs<P: AsRef<Path>>(path: P) -> Result<Vec<DirEntry>> {
+    try!(check_dir(path.as_ref(), metadata(path.as_ref())));
+    let mut file = try!(File::open(path));
+    subdirs(&mut file)
+}
+
+/// Keep the directories of a directory listing, failing if the listing cannot be read
+fn subdirs<R: Read>(listing: &mut R) -> Result<Vec<DirEntry>> {
+    let mut dirs = Vec::new();
+    while let Some(line) = read_dir_line(listing) {
+        let entry = parse_dir_entry(try!(line));
+        if try!(entry.file_type()).is_dir() {
+            dirs.push(entry);
+        }
+    }
+    Ok(dirs)
+}
+
+/// Check that the metadata of a path is that of a directory, returning ENOTDIR if it is not
+fn check_dir(path: &Path, metadata: Result<Metadata>) -> Result<()> {
+    if try!(metadata).is_dir() {
+        Ok(())
+    } else {
+        Err(Error::new_sys(ENOTDIR).with_path(path))
+    }
+}
+
+/// Count the entries of a directory by scanning its listing, which is cheaper than collecting
+/// `read_dir`. An empty directory has 0 entries
+pub fn dir_entry_count<P: AsRef<Path>>(path: P) -> Result<usize> {
+    let mut file = try!(File::open(path));
+
+    let mut count = 0;
+    let mut in_name = false;
+    let mut buf = [0; 4096];
+    loop {
+        let len = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        for b in buf[..len].iter() {
+            if *b == b'\n' {
+                in_name = false;
+            } else if ! in_name {
+                in_name = true;
+                count += 1;
+            }
+        }
+    }
+
+    Ok(count)
+}
+
 pub fn remove_dir<P: AsRef<Path>>(path: P) -> Result<()> {
-    let path_str = path.as_ref().as_os_str().as_inner();
-    let mut path_c = path_str.to_owned();
-    path_c.push_str("\0");
+    let path_c = try!(c_path(path.as_ref()));
     unsafe {
         sys_rmdir(path_c.as_ptr()).and(Ok(()))
-    }.map_err(|x| Error::from_sys(x))
+    }.map_err(|x| Error::from_sys(x).with_path(path.as_ref()))
+}
+
+/// Get the value of an extended attribute of a file
+pub fn getxattr<P: AsRef<Path>>(path: P, key: &str) -> Result<Vec<u8>> {
+    let path_c = try!(c_path(path.as_ref()));
+    let mut key_c = key.to_owned();
+    key_c.push_str("\0");
+
+    let mut buf = vec![0; 256];
+    loop {
+        match unsafe { sys_getxattr(path_c.as_ptr(), key_c.as_ptr(), buf.as_mut_ptr(), buf.len()) } {
+            Ok(len) => {
+                buf.truncate(len);
+                return Ok(buf);
+            },
+            Err(ref err) if err.errno == ERANGE => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            },
+            Err(err) => return Err(Error::from_sys(err).with_path(path.as_ref())),
+        }
+    }
+}
+
+/// Set an extended attribute of a file, replacing any previous value
+pub fn setxattr<P: AsRef<Path>>(path: P, key: &str, value: &[u8]) -> Result<()> {
+    let path_c = try!(c_path(path.as_ref()));
+    let mut key_c = key.to_owned();
+    key_c.push_str("\0");
+    unsafe {
+        sys_setxattr(path_c.as_ptr(), key_c.as_ptr(), value.as_ptr(), value.len()).and(Ok(()))
+    }.map_err(|x| Error::from_sys(x).with_path(path.as_ref()))
 }
 
 pub fn remove_file<P: AsRef<Path>>(path: P) -> Result<()> {
-    let path_str = path.as_ref().as_os_str().as_inner();
-    let mut path_c = path_str.to_owned();
-    path_c.push_str("\0");
+    let path_c = try!(c_path(path.as_ref()));
     unsafe {
         sys_unlink(path_c.as_ptr()).and(Ok(()))
-    }.map_err(|x| Error::from_sys(x))
+    }.map_err(|x| Error::from_sys(x).with_path(path.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use prelude::v1::*;
+
+    use cmp;
+    use io::prelude::*;
+    use io::{self, ErrorKind, IoSlice, IoSliceMut, SeekFrom};
+
+    use path::Path;
+    use system::error::{E2BIG, EAGAIN, EBUSY, EIO, ENAMETOOLONG, ENOENT, ENOTDIR};
+    use system::syscall::{MODE_DIR, MODE_FILE, Stat};
+
+    use super::{c_path, check_dir, next_dir_entry, normalize, parse_dir_entry, read_all_at, read_dir_line, read_to_end_bounded, read_to_end_hint, read_vectored, readahead, retry_transient, rewind_stream, stream_len, subdirs, would_block, write_vectored, Metadata, PATH_MAX};
+
+    /// A directory listing that fails after a number of bytes, like a disk erroring mid-directory
+    struct FaultyListing {
+        data: &'static [u8],
+        fail_at: usize,
+    }
+
+    impl Read for FaultyListing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.fail_at == 0 {
+                return Err(io::Error::new(ErrorKind::Other, "disk error"));
+            }
+
+            let count = cmp::min(cmp::min(buf.len(), self.data.len()), self.fail_at);
+            for (b, d) in buf.iter_mut().zip(self.data[..count].iter()) {
+                *b = *d;
+            }
+            self.data = &self.data[count..];
+            self.fail_at -= count;
+            Ok(count)
+        }
+    }
+
+    /// A non-blocking pipe that has some bytes to read, then nothing until more are written
+    struct NonBlockingPipe {
+        data: Vec<u8>,
+    }
+
+    impl Read for NonBlockingPipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() {
+                return Err(io::Error::new_sys(EAGAIN));
+            }
+
+            let count = cmp::min(buf.len(), self.data.len());
+            buf[..count].clone_from_slice(&self.data[..count]);
+            self.data.drain(..count);
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn read_dir_complete() {
+        let mut listing = FaultyListing { data: b"a\nb/\n", fail_at: 100 };
+        assert_eq!(read_dir_line(&mut listing).unwrap().unwrap(), "a");
+        assert_eq!(read_dir_line(&mut listing).unwrap().unwrap(), "b/");
+        assert!(read_dir_line(&mut listing).is_none());
+    }
+
+    #[test]
+    fn read_dir_error() {
+        let mut listing = FaultyListing { data: b"a\nb/\nc\n", fail_at: 3 };
+        assert_eq!(read_dir_line(&mut listing).unwrap().unwrap(), "a");
+        assert_eq!(read_dir_line(&mut listing).unwrap().unwrap_err().kind(), ErrorKind::Other);
+    }
+
+    /// A writer that accepts a limited number of bytes
+    struct LimitedWriter {
+        data: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for LimitedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let count = cmp::min(buf.len(), self.limit - self.data.len());
+            self.data.extend_from_slice(&buf[..count]);
+            Ok(count)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_vectored_three() {
+        let mut writer = LimitedWriter { data: Vec::new(), limit: 100 };
+        let bufs = [IoSlice::new(b"head"), IoSlice::new(b"-"), IoSlice::new(b"body")];
+        assert_eq!(write_vectored(&mut writer, &bufs).unwrap(), 9);
+        assert_eq!(writer.data, b"head-body");
+    }
+
+    #[test]
+    fn write_vectored_short() {
+        let mut writer = LimitedWriter { data: Vec::new(), limit: 6 };
+        let bufs = [IoSlice::new(b"head"), IoSlice::new(b"-"), IoSlice::new(b"body")];
+        assert_eq!(write_vectored(&mut writer, &bufs).unwrap(), 6);
+        assert_eq!(writer.data, b"head-b");
+    }
+
+    #[test]
+    fn read_vectored_three() {
+        let mut listing = FaultyListing { data: b"abcdefg", fail_at: 100 };
+        let (mut a, mut b, mut c) = ([0; 2], [0; 3], [0; 4]);
+        {
+            let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b), IoSliceMut::new(&mut c)];
+            assert_eq!(read_vectored(&mut listing, &mut bufs).unwrap(), 7);
+        }
+        assert_eq!(&a, b"ab");
+        assert_eq!(&b, b"cde");
+        assert_eq!(&c[..2], b"fg");
+    }
+
+    #[test]
+    fn retry_transient_succeeds() {
+        let mut calls = 0;
+        let mut waits = Vec::new();
+        let result = retry_transient(5, || {
+            calls += 1;
+            if calls < 3 {
+                Err(io::Error::new_sys(if calls == 1 { EIO } else { EBUSY }))
+            } else {
+                Ok(calls)
+            }
+        }, |failed| waits.push(failed));
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(waits, vec![1, 2]);
+    }
+
+    #[test]
+    fn retry_transient_gives_up() {
+        let mut calls = 0;
+        let result: io::Result<()> = retry_transient(3, || {
+            calls += 1;
+            Err(io::Error::new_sys(EIO))
+        }, |_| ());
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(EIO));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn retry_transient_not_found() {
+        let mut calls = 0;
+        let result: io::Result<()> = retry_transient(3, || {
+            calls += 1;
+            Err(io::Error::new_sys(ENOENT))
+        }, |_| ());
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(ENOENT));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn parse_dir_entry_typed() {
+        let entry = parse_dir_entry("\u{2}bin/".to_string());
+        assert_eq!(entry.path, "bin");
+        assert!(entry.dir && ! entry.file);
+
+        let entry = parse_dir_entry("\u{1}file".to_string());
+        assert_eq!(entry.path, "file");
+        assert!(entry.file && ! entry.dir);
+
+        let entry = parse_dir_entry("\u{3}link".to_string());
+        assert_eq!(entry.path, "link");
+        assert!(! entry.file && ! entry.dir);
+    }
+
+    #[test]
+    fn parse_dir_entry_untyped() {
+        let entry = parse_dir_entry("bin/".to_string());
+        assert_eq!(entry.path, "bin");
+        assert!(entry.dir && ! entry.file);
+
+        let entry = parse_dir_entry("file".to_string());
+        assert_eq!(entry.path, "file");
+        assert!(entry.file && ! entry.dir);
+    }
+
+    #[test]
+    fn stream_len_restores_position() {
+        let mut cursor = io::Cursor::new(vec![0; 100]);
+        cursor.seek(SeekFrom::Start(40)).unwrap();
+        assert_eq!(stream_len(&mut cursor).unwrap(), 100);
+        assert_eq!(cursor.position(), 40);
+    }
+
+    fn metadata_with_mode(mode: u16) -> io::Result<Metadata> {
+        let mut stat = Stat::default();
+        stat.st_mode = mode;
+        Ok(Metadata { stat: stat })
+    }
+
+    #[test]
+    fn check_dir_directory() {
+        assert!(check_dir(Path::new("dir"), metadata_with_mode(MODE_DIR)).is_ok());
+    }
+
+    #[test]
+    fn check_dir_file() {
+        let err = check_dir(Path::new("file"), metadata_with_mode(MODE_FILE)).unwrap_err();
+        assert_eq!(err.path(), Some(Path::new("file")));
+        assert_eq!(err.into_sys().errno, ENOTDIR);
+    }
+
+    #[test]
+    fn check_dir_missing() {
+        let missing = Err(io::Error::new_sys(ENOENT).with_path("missing"));
+        let err = check_dir(Path::new("missing"), missing).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(err.into_sys().errno, ENOENT);
+    }
+
+    #[test]
+    fn normalize_relative() {
+        assert_eq!(normalize("a/b", "file:/home/"), "file:/home/a/b");
+        assert_eq!(normalize("./a/../b/", "file:/home/"), "file:/home/b/");
+        assert_eq!(normalize("..", "file:/home/user/"), "file:/home/");
+        assert_eq!(normalize("../../..", "file:/home/"), "file:/");
+        assert_eq!(normalize("/etc/./passwd", "file:/home/"), "file:/etc/passwd");
+    }
+
+    #[test]
+    fn normalize_scheme() {
+        assert_eq!(normalize("tcp:/host/../other", ""), "tcp:/other");
+        assert_eq!(normalize("file:", ""), "file:/");
+        assert_eq!(normalize("a:b/c", "file:/home/"), "a:/b/c");
+    }
+
+    #[test]
+    fn read_past_hint() {
+        let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+
+        // The file grew after its metadata was read
+        let mut buf = Vec::new();
+        assert_eq!(read_to_end_hint(&mut io::Cursor::new(&data[..]), &mut buf, 100).unwrap(), 10000);
+        assert_eq!(buf, data);
+
+        // Directories report a length of 0
+        let mut buf = Vec::new();
+        assert_eq!(read_to_end_hint(&mut io::Cursor::new(&data[..]), &mut buf, 0).unwrap(), 10000);
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn read_bounded() {
+        let mut cursor = io::Cursor::new(vec![1; 100]);
+        assert_eq!(read_to_end_bounded(&mut cursor, 100, 100).unwrap(), vec![1; 100]);
+
+        // The length in the metadata can be stale, as when a file grows during the read
+        let mut cursor = io::Cursor::new(vec![1; 101]);
+        assert_eq!(read_to_end_bounded(&mut cursor, 10, 100).unwrap_err().raw_os_error(), Some(E2BIG));
+    }
+
+    #[test]
+    fn subdirs_of_listing() {
+        let mut listing = io::Cursor::new(b"\x02bin/\n\x01file\nold/\nplain\n\x02usr/".to_vec());
+        let dirs = subdirs(&mut listing).unwrap();
+        assert_eq!(dirs.iter().map(|dir| dir.path.as_str()).collect::<Vec<_>>(), ["bin", "old", "usr"]);
+
+        let mut listing = io::Cursor::new(b"\x01a\n\x01b".to_vec());
+        assert!(subdirs(&mut listing).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rewind_dir() {
+        let mut listing = io::Cursor::new(b"\x02bin/\n\x01file\nplain\n".to_vec());
+        let mut first = Vec::new();
+        while let Some(entry) = next_dir_entry(&mut listing) {
+            first.push(entry.unwrap());
+        }
+        assert_eq!(first.len(), 3);
+
+        rewind_stream(&mut listing).unwrap();
+        let mut second = Vec::new();
+        while let Some(entry) = next_dir_entry(&mut listing) {
+            second.push(entry.unwrap());
+        }
+
+        assert_eq!(first.iter().map(|entry| (entry.path.as_str(), entry.dir, entry.file)).collect::<Vec<_>>(),
+                   second.iter().map(|entry| (entry.path.as_str(), entry.dir, entry.file)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn rewind_reread() {
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut file = io::Cursor::new(&data[..]);
+
+        let mut first = Vec::new();
+        file.read_to_end(&mut first).unwrap();
+        rewind_stream(&mut file).unwrap();
+        let mut second = Vec::new();
+        file.read_to_end(&mut second).unwrap();
+
+        assert_eq!(first, data);
+        assert_eq!(second, first);
+    }
+
+    #[test]
+    fn readahead_clamped() {
+        let data = [1; 5000];
+        let mut reads = Vec::new();
+        readahead(|buf, offset| {
+            reads.push((offset, buf.len()));
+            let mut cursor = io::Cursor::new(&data[..]);
+            try!(cursor.seek(SeekFrom::Start(offset)));
+            cursor.read(buf)
+        }, 1000, 10000).unwrap();
+        assert_eq!(reads, vec![(1000, 4096), (5000, 4096)]);
+
+        reads.clear();
+        readahead(|buf, offset| {
+            reads.push((offset, buf.len()));
+            Ok(0)
+        }, 10000, 100).unwrap();
+        assert_eq!(reads, vec![(10000, 100)]);
+    }
+
+    #[test]
+    fn read_all_at_bounded() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut read_at = |buf: &mut [u8], offset: u64| {
+            let mut cursor = io::Cursor::new(&data[..]);
+            try!(cursor.seek(SeekFrom::Start(offset)));
+            cursor.read(&mut buf[..cmp::min(buf.len(), 30)])
+        };
+
+        assert_eq!(&*read_all_at(&mut read_at, 100, 100).unwrap(), &data[..]);
+        assert_eq!(read_all_at(&mut read_at, 101, 100).unwrap_err().raw_os_error(), Some(E2BIG));
+        // The file shrank after its length was taken
+        assert_eq!(read_all_at(&mut read_at, 120, 200).unwrap().len(), 100);
+    }
+
+    #[test]
+    fn path_max() {
+        let path: String = (0..PATH_MAX).map(|_| 'a').collect();
+        assert_eq!(c_path(Path::new(path.as_str())).unwrap().len(), PATH_MAX + 1);
+
+        let path = path + "a";
+        assert_eq!(c_path(Path::new(path.as_str())).unwrap_err().raw_os_error(), Some(ENAMETOOLONG));
+    }
+
+    #[test]
+    fn read_would_block() {
+        let mut pipe = NonBlockingPipe { data: b"abc".to_vec() };
+        let mut buf = [0; 2];
+        assert_eq!(would_block(pipe.read(&mut buf)).unwrap(), Some(2));
+        assert_eq!(would_block(pipe.read(&mut buf)).unwrap(), Some(1));
+        assert_eq!(would_block(pipe.read(&mut buf)).unwrap(), None);
+
+        let err = io::Error::new(ErrorKind::Other, "disk error");
+        assert!(would_block::<usize>(Err(err)).is_err());
+    }
 }
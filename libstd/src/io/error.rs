@@ -9,15 +9,16 @@
 // except according to those terms.
 
 use boxed::Box;
-use convert::Into;
+use convert::{AsRef, Into};
 use error::{Error as StdError, self};
 use fmt;
 use marker::{Send, Sync};
-use option::Option::{self, None};
+use option::Option::{self, None, Some};
+use path::{Path, PathBuf};
 use result;
 
 use system::error::Error as SysError;
-use system::error::{ENOENT, EACCES, EEXIST, EINVAL, STR_ERROR};
+use system::error::{ENOENT, EACCES, EAGAIN, EEXIST, EINTR, EINVAL, STR_ERROR};
 
 /// A specialized [`Result`](../result/enum.Result.html) type for I/O
 /// operations.
@@ -80,10 +81,51 @@ impl Error {
         }
     }
 
+    /// Attach the path that the failed operation was applied to. If the error already has a
+    /// path, the original, more specific path is kept
+    pub fn with_path<P: AsRef<Path>>(self, path: P) -> Error {
+        if self.path().is_some() {
+            return self;
+        }
+
+        Error {
+            repr: Repr::Path(box PathError {
+                error: self,
+                path: path.as_ref().to_path_buf(),
+            }),
+        }
+    }
+
+    /// Get the path that the failed operation was applied to, if it is known
+    pub fn path(&self) -> Option<&Path> {
+        match self.repr {
+            Repr::Path(ref p) => Some(&p.path),
+            _ => None,
+        }
+    }
+
+    /// Remove the path from this error, leaving the plain error
+    pub fn without_path(self) -> Error {
+        match self {
+            Error { repr: Repr::Path(p) } => p.error,
+            error => error,
+        }
+    }
+
+    /// Get the error number of this error, if it came from a syscall
+    pub fn raw_os_error(&self) -> Option<isize> {
+        match self.repr {
+            Repr::Os(n) => Some(n),
+            Repr::Path(ref p) => p.error.raw_os_error(),
+            _ => None,
+        }
+    }
+
     /// Into a system error.
     pub fn into_sys(self) -> SysError {
         match self.repr {
             Repr::Os(n) => SysError::new(n),
+            Repr::Path(p) => p.error.into_sys(),
             _ => SysError::new(!0),
         }
     }
@@ -103,9 +145,12 @@ impl Error {
                 EACCES => ErrorKind::PermissionDenied,
                 EEXIST => ErrorKind::AlreadyExists,
                 EINVAL => ErrorKind::InvalidInput,
+                EAGAIN => ErrorKind::WouldBlock,
+                EINTR => ErrorKind::Interrupted,
                 _ => ErrorKind::Other
             },
             &Repr::Custom(ref c) => c.kind,
+            &Repr::Path(ref p) => p.error.kind(),
         }
     }
 }
@@ -113,6 +158,7 @@ impl Error {
 enum Repr {
     Os(isize),
     Custom(Box<Custom>),
+    Path(Box<PathError>),
 }
 
 #[derive(Debug)]
@@ -121,6 +167,12 @@ struct Custom {
     error: Box<error::Error+Send+Sync>,
 }
 
+#[derive(Debug)]
+struct PathError {
+    error: Error,
+    path: PathBuf,
+}
+
 /// A list specifying general categories of I/O error.
 ///
 /// This list is intended to grow over time and it is not recommended to
@@ -205,6 +257,7 @@ impl fmt::Display for Error {
                 write!(fmt, "{} (os error {})", detail, code)
             }
             Repr::Custom(ref c) => c.error.fmt(fmt),
+            Repr::Path(ref p) => write!(fmt, "{}: {}", p.path.display(), p.error),
         }
     }
 }
@@ -221,6 +274,7 @@ impl error::Error for Error {
                 }
             },
             Repr::Custom(ref c) => c.error.description(),
+            Repr::Path(ref p) => p.error.description(),
         }
     }
 
@@ -228,6 +282,7 @@ impl error::Error for Error {
         match self.repr {
             Repr::Os(..) => None,
             Repr::Custom(ref c) => c.error.cause(),
+            Repr::Path(ref p) => p.error.cause(),
         }
     }
 }
@@ -244,7 +299,9 @@ mod test {
     use error;
     use error::Error as error_Error;
     use fmt;
+    use path::Path;
     use sys::os::error_string;
+    use system::error::{EAGAIN, ENOENT};
 
     #[test]
     fn test_debug_error() {
@@ -281,4 +338,20 @@ mod test {
         let extracted = err.into_inner().unwrap();
         extracted.downcast::<TestError>().unwrap();
     }
+
+    #[test]
+    fn test_with_path() {
+        let err = Error::new_sys(ENOENT).with_path("a/b").with_path("a");
+        assert_eq!(err.path(), Some(Path::new("a/b")));
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        let err = err.without_path();
+        assert_eq!(err.path(), None);
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_would_block() {
+        assert_eq!(Error::new_sys(EAGAIN).kind(), ErrorKind::WouldBlock);
+    }
 }
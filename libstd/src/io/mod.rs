@@ -247,6 +247,7 @@ pub use self::error::{Result, Error, ErrorKind};
 pub use self::util::{copy, sink, Sink, empty, Empty, repeat, Repeat};
 
 use prelude::v1::{Vec, String};
+use core::ops::{Deref, DerefMut};
 use fmt;
 use cmp;
 use str;
@@ -1417,6 +1418,44 @@ pub trait BufRead: Read {
     }
 }
 
+/// A buffer to write from in a vectored write, such as `File::write_vectored`
+pub struct IoSlice<'a>(&'a [u8]);
+
+impl<'a> IoSlice<'a> {
+    pub fn new(buf: &'a [u8]) -> IoSlice<'a> {
+        IoSlice(buf)
+    }
+}
+
+impl<'a> Deref for IoSlice<'a> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+/// A buffer to read into in a vectored read, such as `File::read_vectored`
+pub struct IoSliceMut<'a>(&'a mut [u8]);
+
+impl<'a> IoSliceMut<'a> {
+    pub fn new(buf: &'a mut [u8]) -> IoSliceMut<'a> {
+        IoSliceMut(buf)
+    }
+}
+
+impl<'a> Deref for IoSliceMut<'a> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl<'a> DerefMut for IoSliceMut<'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.0
+    }
+}
+
 /// A `Write` adaptor which will write data to multiple locations.
 ///
 /// This struct is generally created by calling [`broadcast()`][broadcast] on a
@@ -1486,6 +1525,26 @@ impl<T> Take<T> {
     /// This instance may reach EOF after reading fewer bytes than indicated by
     /// this method if the underlying `Read` instance reaches EOF.
         pub fn limit(&self) -> u64 { self.limit }
+
+    /// Gets a reference to the underlying reader.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying reader.
+    ///
+    /// Reading from it directly does not count against the limit.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consumes the `Take`, returning the underlying reader.
+    ///
+    /// A reader without buffering of its own, like `File`, is left positioned just past the
+    /// bytes read through the `Take`, so reading can carry on from there.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
 }
 
 impl<T: Read> Read for Take<T> {
@@ -1869,6 +1928,24 @@ mod tests {
         assert_eq!(0, R.take(0).read(&mut buf).unwrap());
     }
 
+    #[test]
+    fn take_into_inner() {
+        let mut data = b"REDOXFS\0\0\0\0\0\0\0\0\x01".to_vec();
+        data.extend_from_slice(b"rest of the file");
+
+        let mut magic = Vec::new();
+        let mut take = Cursor::new(data).take(16);
+        take.read_to_end(&mut magic).unwrap();
+        assert_eq!(&magic[..8], b"REDOXFS\0");
+        assert_eq!(magic.len(), 16);
+
+        let mut inner = take.into_inner();
+        assert_eq!(inner.seek(io::SeekFrom::Current(0)).unwrap(), 16);
+        let mut rest = String::new();
+        inner.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "rest of the file");
+    }
+
     #[bench]
     fn bench_read_to_end(b: &mut test::Bencher) {
         b.iter(|| {
@@ -0,0 +1,27 @@
+use fs::File;
+use io::{Error, Result};
+
+use os::unix::io::AsRawFd;
+
+use system::syscall::{sys_fallocate, FALLOC_FL_KEEP_SIZE};
+
+pub trait FileExt {
+    /// Make sure at least `len` bytes of space are allocated for the file, without changing its
+    /// length. Returns `ENOSYS` if the file system cannot preallocate space. On redoxfs the size
+    /// of a file is the space of its extents, so this always fails with `ENOSYS` when `len` is
+    /// past the end of the file, and `allocate_extend` has to be used instead
+    fn allocate(&self, len: u64) -> Result<()>;
+
+    /// Allocate space for `len` bytes, extending the file with zeros if it is shorter
+    fn allocate_extend(&self, len: u64) -> Result<()>;
+}
+
+impl FileExt for File {
+    fn allocate(&self, len: u64) -> Result<()> {
+        sys_fallocate(self.as_raw_fd(), FALLOC_FL_KEEP_SIZE, len as usize).and(Ok(())).map_err(|x| Error::from_sys(x))
+    }
+
+    fn allocate_extend(&self, len: u64) -> Result<()> {
+        sys_fallocate(self.as_raw_fd(), 0, len as usize).and(Ok(())).map_err(|x| Error::from_sys(x))
+    }
+}
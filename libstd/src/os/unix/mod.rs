@@ -1 +1,3 @@
+pub mod fs;
 pub mod io;
+pub mod raw;
@@ -0,0 +1,4 @@
+//! Syscalls that return EINTR to the caller instead of retrying like `File` does, for code that
+//! needs to handle interruption itself
+
+pub use system::syscall::{sys_open, sys_read, sys_write};
@@ -1,3 +1,11 @@
+use core::cmp;
+
+use fs::PATH_MAX;
+use io::Result;
+use vec::Vec;
+
+use system::error::{Result as SysResult, EINTR};
+
 /// A trait for viewing representations from std types
 pub trait AsInner<Inner: ?Sized> {
     fn as_inner(&self) -> &Inner;
@@ -12,3 +20,106 @@ pub trait IntoInner<Inner> {
 pub trait FromInner<Inner> {
     fn from_inner(inner: Inner) -> Self;
 }
+
+/// Call a syscall until it is not interrupted by a signal, so that callers of blocking
+/// operations do not see spurious EINTR errors
+pub fn retry<F: FnMut() -> SysResult<usize>>(mut f: F) -> SysResult<usize> {
+    loop {
+        match f() {
+            Err(ref err) if err.errno == EINTR => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Call `read` with a buffer of `PATH_MAX` bytes, and again with a larger buffer until the result
+/// fits. A result that fills the buffer may have been cut short, so the buffer is doubled, and a
+/// result longer than the buffer is the length needed, so the buffer is made that long. Only an
+/// error from `read` is returned as an error
+pub fn read_growing<F: FnMut(&mut [u8]) -> Result<usize>>(mut read: F) -> Result<Vec<u8>> {
+    let mut buf = vec![0; PATH_MAX];
+    loop {
+        let count = try!(read(&mut buf));
+        if count < buf.len() {
+            buf.truncate(count);
+            return Ok(buf);
+        }
+
+        let len = cmp::max(count + 1, buf.len() * 2);
+        buf.resize(len, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cmp;
+
+    use fs::PATH_MAX;
+    use vec::Vec;
+
+    use system::error::{Error as SysError, EINTR, EIO};
+
+    use super::{read_growing, retry};
+
+    #[test]
+    fn retry_interrupted() {
+        let mut calls = 0;
+        let result = retry(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(SysError::new(EINTR))
+            } else {
+                Ok(7)
+            }
+        });
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn retry_other_error() {
+        let mut calls = 0;
+        let result = retry(|| {
+            calls += 1;
+            Err(SysError::new(EIO))
+        });
+        assert_eq!(result.unwrap_err().errno, EIO);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn read_growing_long_path() {
+        let path: Vec<u8> = (0..PATH_MAX + 100).map(|i| b'a' + (i % 26) as u8).collect();
+        let mut calls = 0;
+        let buf = read_growing(|buf| {
+            calls += 1;
+            let count = cmp::min(buf.len(), path.len());
+            buf[..count].clone_from_slice(&path[..count]);
+            Ok(count)
+        }).unwrap();
+        assert_eq!(buf, path);
+        assert_eq!(calls, 2);
+
+        let buf = read_growing(|buf| {
+            buf[..3].clone_from_slice(b"a:/");
+            Ok(3)
+        }).unwrap();
+        assert_eq!(buf, b"a:/");
+    }
+
+    #[test]
+    fn read_growing_reported_length() {
+        // Like a syscall that reports the length it needs instead of cutting the result short
+        let path: Vec<u8> = (0..PATH_MAX * 5).map(|i| b'/' + (i % 64) as u8).collect();
+        let mut lens = Vec::new();
+        let buf = read_growing(|buf| {
+            lens.push(buf.len());
+            if buf.len() >= path.len() {
+                buf[..path.len()].clone_from_slice(&path);
+            }
+            Ok(path.len())
+        }).unwrap();
+        assert_eq!(buf, path);
+        assert_eq!(lens, vec![PATH_MAX, PATH_MAX * 5 + 1]);
+    }
+}